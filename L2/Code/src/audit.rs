@@ -0,0 +1,423 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::{anyhow, bail, Result};
+use http::header::USER_AGENT;
+use http::HeaderMap;
+use log::{error, info, trace};
+use once_cell::sync::Lazy;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::consts::{AUDIT_LOG_MAX_FILES, AUDIT_LOG_MAX_SIZE};
+
+/// Outcome of an audited action
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+/// Entry of the audit log, one JSON object per line
+/// The log adds the hash of the previous entry (`prev`) and the hash of the entry itself (`hash`)
+#[derive(Serialize, Debug)]
+pub struct Event<'a> {
+    pub timestamp: u64, // Unix timestamp
+    pub event: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<&'a str>, // User performing the action, if not the subject
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<&'a str>, // User the action applies to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<&'a str>, // Data specific to the event, such as the new email of a user
+    pub outcome: Outcome,
+}
+
+impl<'a> Event<'a> {
+    pub fn new(event: &'a str, outcome: Outcome) -> Self {
+        Self {
+            timestamp: jsonwebtoken::get_current_timestamp(),
+            event,
+            actor: None,
+            subject: None,
+            ip: None,
+            user_agent: None,
+            detail: None,
+            outcome,
+        }
+    }
+
+    pub fn actor(self, actor: &'a str) -> Self {
+        Self { actor: Some(actor), ..self }
+    }
+
+    pub fn subject(self, subject: &'a str) -> Self {
+        Self { subject: Some(subject), ..self }
+    }
+
+    pub fn detail(self, detail: &'a str) -> Self {
+        Self { detail: Some(detail), ..self }
+    }
+
+    /// IP and user agent of the request
+    pub fn client(self, addr: &SocketAddr, headers: &'a HeaderMap) -> Self {
+        let user_agent = headers.get(USER_AGENT).and_then(|h| h.to_str().ok());
+        Self { ip: Some(addr.ip()), user_agent, ..self }
+    }
+}
+
+// Hash preceding the first entry of the chain
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+fn sha256(data: &str) -> String {
+    digest::digest(&digest::SHA256, data.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Add a field at the end of a serialized JSON object
+fn with_field(object: &str, name: &str, value: &str) -> String {
+    format!("{},\"{name}\":\"{value}\"}}", &object[..object.len() - 1])
+}
+
+/// Append-only log of JSON lines, each entry is chained to the previous one by its hash
+/// Once the file is too big, it is renamed with a timestamp suffix and a new file is started, the chain
+/// continues in the new file. Only the most recent rotated files are kept
+pub struct AuditLog {
+    path: PathBuf,
+    file: Option<File>,
+    size: u64,
+    last_hash: String,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl AuditLog {
+    /// Open the log, the chain continues from the last entry written
+    pub fn open<P: Into<PathBuf>>(path: P, max_size: u64, max_files: usize) -> Result<Self> {
+        let path = path.into();
+
+        let last_hash = files(&path)?
+            .iter()
+            .rev()
+            .find_map(|f| last_line(f).transpose())
+            .transpose()?
+            .map(|line| parse_line(&line).map(|(_, hash, _)| hash))
+            .transpose()?
+            .unwrap_or(GENESIS.to_string());
+        let size = fs::metadata(&path).map_or(0, |m| m.len());
+
+        Ok(Self { path, file: None, size, last_hash, max_size, max_files })
+    }
+
+    pub fn append(&mut self, event: &Event) -> Result<()> {
+        let body = with_field(&serde_json::to_string(event)?, "prev", &self.last_hash);
+        let hash = sha256(&body);
+        let line = with_field(&body, "hash", &hash);
+
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_size {
+            self.rotate()?;
+        }
+
+        if self.file.is_none() {
+            self.file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        }
+        let file = self.file.as_mut().ok_or(anyhow!("Audit log not opened"))?;
+        file.write_all(format!("{line}\n").as_bytes())?;
+        file.sync_data()?;
+
+        self.size += line.len() as u64 + 1;
+        self.last_hash = hash;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        info!("Rotate audit log");
+        self.file = None;
+
+        // Named after the rotation time, in milliseconds, always after the previous rotated file :
+        // the names order the files even when several rotations happen within a millisecond
+        let now = time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000;
+        let last = files(&self.path)?.iter()
+            .filter_map(|f| f.extension()?.to_str()?.parse::<i128>().ok())
+            .max();
+        let stamp = last.map_or(now, |last| now.max(last + 1));
+        fs::rename(&self.path, rotated_name(&self.path, &format!("{stamp:015}")))?;
+        self.size = 0;
+
+        // Forget the oldest files, the chain can still be checked from the first entry kept
+        let rotated = files(&self.path)?;
+        for old in rotated.iter().take(rotated.len().saturating_sub(self.max_files)) {
+            info!("Remove old audit log {}", old.display());
+            fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+}
+
+fn rotated_name(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{suffix}"));
+    PathBuf::from(name)
+}
+
+/// Files of the log, oldest first : the rotated files then the current one
+fn files(path: &Path) -> Result<Vec<PathBuf>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let prefix = format!("{}.", path.file_name().ok_or(anyhow!("Invalid audit log path"))?.to_string_lossy());
+
+    let mut files: Vec<PathBuf> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().strip_prefix(&prefix).is_some_and(|s| s.bytes().all(|b| b.is_ascii_digit())))
+            .map(|e| dir.join(e.file_name()))
+            .collect(),
+        Err(_) => vec![],
+    };
+    files.sort();
+
+    if path.exists() {
+        files.push(path.to_path_buf());
+    }
+    Ok(files)
+}
+
+fn last_line(path: &Path) -> Result<Option<String>> {
+    let mut last = None;
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            last = Some(line);
+        }
+    }
+    Ok(last)
+}
+
+/// Split a line into the hash of the previous entry, its own hash, and the hashed body
+fn parse_line(line: &str) -> Result<(String, String, String)> {
+    let (body, hash) = line.rsplit_once(",\"hash\":\"").ok_or(anyhow!("Missing hash"))?;
+    let hash = hash.strip_suffix("\"}").ok_or(anyhow!("Malformed hash"))?;
+    let body = format!("{body}}}");
+
+    let prev = serde_json::from_str::<Value>(&body)?
+        .get("prev")
+        .and_then(Value::as_str)
+        .ok_or(anyhow!("Missing previous hash"))?
+        .to_string();
+    Ok((prev, hash.to_string(), body))
+}
+
+/// Result of a check of the hash chain
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Verification {
+    pub valid: bool,
+    pub entries: usize, // Entries checked, until the first invalid one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Check that no entry of the log has been modified, removed or inserted
+/// The first entry kept is trusted, older files may have been removed by the rotation
+pub fn verify_chain(path: &Path) -> Result<Verification> {
+    let mut expected: Option<String> = None;
+    let mut entries = 0;
+
+    for file in files(path)? {
+        for (number, line) in BufReader::new(File::open(&file)?).lines().enumerate() {
+            let line = line?;
+            let location = format!("{} line {}", file.display(), number + 1);
+
+            let checked = parse_line(&line).and_then(|(prev, hash, body)| {
+                if expected.as_ref().is_some_and(|e| *e != prev) {
+                    bail!("chain broken");
+                }
+                if sha256(&body) != hash {
+                    bail!("entry modified");
+                }
+                Ok(hash)
+            });
+
+            match checked {
+                Ok(hash) => expected = Some(hash),
+                Err(e) => return Ok(Verification { valid: false, entries, error: Some(format!("{location} : {e}")) }),
+            }
+            entries += 1;
+        }
+    }
+
+    Ok(Verification { valid: true, entries, error: None })
+}
+
+/// Criteria of a search in the audit log, all optional
+#[derive(Deserialize, Default, Debug)]
+pub struct Query {
+    pub event: Option<String>, // "login" also matches "login.password", "login.totp"...
+    pub actor: Option<String>,
+    pub subject: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub outcome: Option<Outcome>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    fn matches(&self, entry: &Value) -> bool {
+        let field = |name: &str| entry.get(name).and_then(Value::as_str);
+        let timestamp = entry.get("timestamp").and_then(Value::as_u64).unwrap_or(0);
+
+        self.event.as_ref().is_none_or(|e| field("event").is_some_and(|f| f == e || f.starts_with(&format!("{e}."))))
+            && self.actor.as_ref().is_none_or(|a| field("actor") == Some(a))
+            && self.subject.as_ref().is_none_or(|s| field("subject") == Some(s))
+            && self.ip.is_none_or(|ip| field("ip") == Some(&ip.to_string()))
+            && self.outcome.is_none_or(|o| entry.get("outcome").and_then(|v| serde_json::from_value::<Outcome>(v.clone()).ok()) == Some(o))
+            && self.since.is_none_or(|s| timestamp >= s)
+            && self.until.is_none_or(|u| timestamp <= u)
+    }
+}
+
+/// Entries matching the query, newest first
+pub fn search(path: &Path, query: &Query, limit: usize) -> Result<Vec<Value>> {
+    let mut found = vec![];
+
+    for file in files(path)?.iter().rev() {
+        let lines: Vec<String> = BufReader::new(File::open(file)?).lines().collect::<std::io::Result<_>>()?;
+        for line in lines.iter().rev() {
+            let Ok(entry) = serde_json::from_str::<Value>(line) else {
+                continue;
+            };
+            if query.matches(&entry) {
+                found.push(entry);
+                if found.len() == limit {
+                    return Ok(found);
+                }
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+static LOG: Lazy<Mutex<Option<AuditLog>>> = Lazy::new(|| Mutex::new(None));
+
+/// Open the audit log, must be called once at startup
+pub fn init(path: &str) -> Result<()> {
+    info!("Open audit log {path}");
+    let log = AuditLog::open(path, AUDIT_LOG_MAX_SIZE, AUDIT_LOG_MAX_FILES)?;
+    *LOG.lock().or(Err(anyhow!("Audit log poisoned")))? = Some(log);
+    Ok(())
+}
+
+/// Path of the audit log opened at startup
+pub fn path() -> Result<PathBuf> {
+    let log = LOG.lock().or(Err(anyhow!("Audit log poisoned")))?;
+    Ok(log.as_ref().ok_or(anyhow!("Audit log not opened"))?.path.clone())
+}
+
+/// Append an event to the audit log
+/// A failure is logged but doesn't stop the action, it already happened
+pub fn record(event: Event) {
+    trace!("Audit {}", event.event);
+
+    let written = LOG.lock()
+        .or(Err(anyhow!("Audit log poisoned")))
+        .and_then(|mut log| log.as_mut().ok_or(anyhow!("Audit log not opened"))?.append(&event));
+    if let Err(e) = written {
+        error!("Failed to write the audit log : {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn temp_log() -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        (dir.join("audit.log"), dir)
+    }
+
+    fn event(subject: &str) -> Event<'_> {
+        Event::new("login.password", Outcome::Success).subject(subject)
+    }
+
+    #[rstest]
+    pub fn event_json_test() {
+        let event = Event { timestamp: 42, ..Event::new("admin.user.disable", Outcome::Success) }
+            .actor("admin@test.com")
+            .subject("unit@test.com");
+
+        // Missing fields are left out of the line
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"timestamp":42,"event":"admin.user.disable","actor":"admin@test.com","subject":"unit@test.com","outcome":"success"}"#,
+        );
+    }
+
+    #[rstest]
+    pub fn chain_test() {
+        let (path, dir) = temp_log();
+        let mut log = AuditLog::open(&path, 1 << 20, 2).unwrap();
+        log.append(&event("a@test.com")).unwrap();
+        log.append(&event("b@test.com")).unwrap();
+
+        // The chain continues after a restart
+        let mut log = AuditLog::open(&path, 1 << 20, 2).unwrap();
+        log.append(&event("c@test.com")).unwrap();
+        assert_eq!(verify_chain(&path).unwrap(), Verification { valid: true, entries: 3, error: None });
+
+        // Modified entry
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, content.replace("b@test.com", "x@test.com")).unwrap();
+        let verification = verify_chain(&path).unwrap();
+        assert!(!verification.valid);
+        assert_eq!(verification.entries, 1);
+
+        // Removed entry
+        let lines: Vec<&str> = content.lines().collect();
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert!(!verify_chain(&path).unwrap().valid);
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[rstest]
+    pub fn rotation_test() {
+        let (path, dir) = temp_log();
+
+        // Each entry is about 240 bytes : two entries per file
+        let mut log = AuditLog::open(&path, 500, 2).unwrap();
+        for i in 0..9 {
+            log.append(&event(&format!("{i}@test.com"))).unwrap();
+        }
+
+        // Current file and two rotated files, the oldest entries are gone but the chain is still valid
+        assert_eq!(files(&path).unwrap().len(), 3);
+        assert_eq!(verify_chain(&path).unwrap().entries, 5);
+        assert!(verify_chain(&path).unwrap().valid);
+
+        // Search goes through the rotated files, newest first
+        let query = Query { event: Some("login".into()), ..Default::default() };
+        let found = search(&path, &query, 3).unwrap();
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[0]["subject"], "8@test.com");
+        assert!(search(&path, &Query { event: Some("log".into()), ..Default::default() }, 10).unwrap().is_empty());
+        assert_eq!(search(&path, &Query { subject: Some("4@test.com".into()), ..Default::default() }, 10).unwrap().len(), 1);
+
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -1,6 +1,11 @@
+mod csrf;
 mod handlers_access;
+mod handlers_admin;
+mod handlers_oauth;
 mod handlers_refresh;
 pub mod handlers_unauth;
 mod middlewares;
 mod models;
-pub mod router;
\ No newline at end of file
+pub mod router;
+pub mod session;
+pub mod tls;
\ No newline at end of file
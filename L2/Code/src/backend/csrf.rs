@@ -0,0 +1,274 @@
+use axum::body::{to_bytes, Body};
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http::header::{CONTENT_TYPE, HOST, ORIGIN, REFERER};
+use http::{HeaderMap, HeaderValue, Method, StatusCode};
+use log::{info, trace};
+use tower_sessions::Session;
+use url::Url;
+use uuid::Uuid;
+use crate::config;
+use crate::consts::{CSRF_HEADER, CSRF_MAX_BODY_SIZE, CSRF_TOKEN_DURATION};
+use crate::metrics;
+
+/// Generate an anti-CSRF token and save it in the session, replacing the previous one
+pub fn issue(session: &Session) -> Result<String, StatusCode> {
+    let token = Uuid::new_v4().to_string();
+    let expiration = time::OffsetDateTime::now_utc() + time::Duration::seconds(CSRF_TOKEN_DURATION);
+
+    session.insert("csrf", token.clone()).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    session.insert("csrf_expiration", expiration.unix_timestamp()).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok(token)
+}
+
+/// Check the anti-CSRF token given by the user against the one saved in its session
+fn check(session: &Session, csrf: Option<&str>) -> Result<(), (StatusCode, &'static str)> {
+    let csrf = csrf.ok_or((StatusCode::BAD_REQUEST, "Anti-CSRF token missing"))?;
+
+    // Check that the anti-CSRF token isn't expired
+    let token_expiration = session.get::<i64>("csrf_expiration")
+        .or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Session error")))?
+        .ok_or((StatusCode::BAD_REQUEST, "Anti-CSRF token missing"))?;
+    if token_expiration < time::OffsetDateTime::now_utc().unix_timestamp() {
+        info!("Anti-CSRF token expired");
+        return Err((StatusCode::BAD_REQUEST, "Anti-CSRF token expired"));
+    }
+
+    // Compare the anti-CSRF token saved with the given one
+    let token : String = session.get::<String>("csrf")
+        .or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Session error")))?
+        .ok_or((StatusCode::BAD_REQUEST, "Anti-CSRF token missing"))?;
+    if ring::constant_time::verify_slices_are_equal(token.as_bytes(), csrf.as_bytes()).is_err() {
+        info!("Anti-CSRF tokens don't match");
+        return Err((StatusCode::BAD_REQUEST, "Anti-CSRF tokens don't match"));
+    }
+
+    Ok(())
+}
+
+/// Middleware protecting the routes authenticated by a cookie, which the browser sends even from another site
+/// The unsafe methods must give the token saved in the session, in the X-CSRF-Token header or in the `csrf`
+/// field of a JSON or form body. Once used, the token is replaced and the new one is sent in X-CSRF-Token.
+pub async fn protect(session: Session, request: Request, next: Next) -> Response {
+    if matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(request).await;
+    }
+
+    let endpoint = request.extensions().get::<MatchedPath>()
+        .map_or(request.uri().path().to_string(), |p| p.as_str().to_string());
+
+    // The body is only read when the header is missing, then handed over to the handler
+    let (parts, body) = request.into_parts();
+    let (csrf, body) = match parts.headers.get(CSRF_HEADER).and_then(|h| h.to_str().ok()) {
+        Some(csrf) => (Some(csrf.to_string()), body),
+        None => match to_bytes(body, CSRF_MAX_BODY_SIZE).await {
+            Ok(bytes) => (token_from_body(&parts.headers, &bytes), Body::from(bytes)),
+            Err(_) => return (StatusCode::PAYLOAD_TOO_LARGE, "Body too large").into_response(),
+        },
+    };
+
+    if let Err(rejection) = check(&session, csrf.as_deref()) {
+        metrics::CSRF_REJECTIONS.with_label_values(&[&endpoint]).inc();
+        return rejection.into_response();
+    }
+
+    trace!("Rotate anti-CSRF token");
+    let token = match issue(&session) {
+        Ok(token) => token,
+        Err(status) => return status.into_response(),
+    };
+
+    let mut response = next.run(Request::from_parts(parts, body)).await;
+    if let Ok(token) = HeaderValue::from_str(&token) {
+        response.headers_mut().insert(CSRF_HEADER, token);
+    }
+    response
+}
+
+/// Middleware protecting the routes used before having a session, like the login or the registration
+/// Browsers give the Origin of the page sending an unsafe request, it must be this server. Requests without
+/// Origin nor Referer don't come from a web page, they are let through.
+pub async fn same_origin(request: Request, next: Next) -> Response {
+    if matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(request).await;
+    }
+
+    let headers = request.headers();
+    let Some(origin) = headers.get(ORIGIN).or(headers.get(REFERER)) else {
+        return next.run(request).await;
+    };
+
+    let host = headers.get(HOST).and_then(|h| h.to_str().ok());
+    let public_url = config::get().server.public_url.as_ref();
+    if !origin.to_str().is_ok_and(|origin| is_same_origin(origin, public_url, host)) {
+        info!("Cross-origin request refused");
+        let endpoint = request.extensions().get::<MatchedPath>()
+            .map_or(request.uri().path().to_string(), |p| p.as_str().to_string());
+        metrics::CSRF_REJECTIONS.with_label_values(&[&endpoint]).inc();
+        return (StatusCode::FORBIDDEN, "Cross-origin request").into_response();
+    }
+
+    next.run(request).await
+}
+
+/// Compare the page sending a request with the public URL if configured, else with the host the client asked for
+fn is_same_origin(origin: &str, public_url: Option<&Url>, host: Option<&str>) -> bool {
+    let Ok(origin) = Url::parse(origin) else {
+        return false; // "null" for sandboxed pages and local files
+    };
+
+    match (public_url, origin.host_str()) {
+        (Some(url), _) => origin.origin() == url.origin(),
+        (None, Some(origin_host)) => {
+            let authority = match origin.port() {
+                Some(port) => format!("{origin_host}:{port}"),
+                None => origin_host.to_string(),
+            };
+            host.is_some_and(|host| host.eq_ignore_ascii_case(&authority))
+        },
+        (None, None) => false,
+    }
+}
+
+/// `csrf` field of a JSON or urlencoded form body
+fn token_from_body(headers: &HeaderMap, body: &[u8]) -> Option<String> {
+    let content_type = headers.get(CONTENT_TYPE).and_then(|h| h.to_str().ok()).unwrap_or_default();
+
+    if content_type.starts_with("application/json") {
+        serde_json::from_slice::<serde_json::Value>(body).ok()?
+            .get("csrf")?
+            .as_str()
+            .map(str::to_string)
+    } else if content_type.starts_with("application/x-www-form-urlencoded") {
+        url::form_urlencoded::parse(body)
+            .find(|(key, _)| key == "csrf")
+            .map(|(_, value)| value.into_owned())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::error_handling::HandleErrorLayer;
+    use axum::middleware::from_fn;
+    use axum::routing::{get, post};
+    use axum::{BoxError, Router};
+    use http::header::{COOKIE, SET_COOKIE};
+    use rstest::rstest;
+    use serde_json::json;
+    use tower::ServiceExt;
+    use tower_sessions::{MemoryStore, SessionManagerLayer};
+
+    fn app() -> Router {
+        Router::new()
+            .route("/protected", post(|| async { "done" }))
+            .route_layer(from_fn(protect))
+            .route("/token", get(|session: Session| async move { issue(&session).unwrap() }))
+            .layer(tower::ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(|_: BoxError| async { StatusCode::BAD_REQUEST }))
+                .layer(SessionManagerLayer::new(MemoryStore::default()).with_secure(false)))
+    }
+
+    // Session cookie and anti-CSRF token of a new session
+    async fn start(app: &Router) -> (String, String) {
+        let response = app.clone().oneshot(Request::get("/token").body(Body::empty()).unwrap()).await.unwrap();
+        let cookie = response.headers()[SET_COOKIE].to_str().unwrap().split(';').next().unwrap().to_string();
+        let token = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (cookie, String::from_utf8(token.to_vec()).unwrap())
+    }
+
+    async fn send(app: &Router, cookie: &str, header: Option<&str>, content_type: &str, body: String) -> Response {
+        let mut request = Request::post("/protected").header(COOKIE, cookie).header(CONTENT_TYPE, content_type);
+        if let Some(token) = header {
+            request = request.header(CSRF_HEADER, token);
+        }
+        app.clone().oneshot(request.body(Body::from(body)).unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    pub async fn missing_token_test() {
+        let app = app();
+        let (cookie, _) = start(&app).await;
+        let response = send(&app, &cookie, None, "application/json", "{}".into()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    pub async fn wrong_token_test() {
+        let app = app();
+        let (cookie, token) = start(&app).await;
+        let response = send(&app, &cookie, Some("wrong"), "application/json", "{}".into()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // The token of another session doesn't match either
+        let (other, _) = start(&app).await;
+        let response = send(&app, &other, Some(&token), "application/json", "{}".into()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    pub async fn header_token_test() {
+        let app = app();
+        let (cookie, token) = start(&app).await;
+        let response = send(&app, &cookie, Some(&token), "application/json", "{}".into()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    pub async fn body_token_test() {
+        let app = app();
+        let (cookie, token) = start(&app).await;
+        let response = send(&app, &cookie, None, "application/json", json!({"csrf": token}).to_string()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The handler still gets the whole body
+        let token = response.headers()[CSRF_HEADER].to_str().unwrap().to_string();
+        let response = send(&app, &cookie, None, "application/x-www-form-urlencoded", format!("a=1&csrf={token}")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), "done");
+
+        // Only JSON and forms are read
+        let (cookie, token) = start(&app).await;
+        let response = send(&app, &cookie, None, "text/plain", format!("csrf={token}")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    pub async fn rotation_test() {
+        let app = app();
+        let (cookie, token) = start(&app).await;
+        let response = send(&app, &cookie, Some(&token), "application/json", "{}".into()).await;
+        let rotated = response.headers()[CSRF_HEADER].to_str().unwrap().to_string();
+        assert_ne!(rotated, token);
+
+        // The used token can't be replayed, the one sent back works once
+        let response = send(&app, &cookie, Some(&token), "application/json", "{}".into()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = send(&app, &cookie, Some(&rotated), "application/json", "{}".into()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[rstest(
+    origin,
+    public_url,
+    host,
+    expected,
+    case("http://localhost:8090", None, Some("localhost:8090"), true),
+    case("http://localhost:8090/login?next=/", None, Some("localhost:8090"), true),
+    case("https://auth.test.com", None, Some("auth.test.com"), true),
+    case("http://localhost:8091", None, Some("localhost:8090"), false),
+    case("https://evil.test.com", None, Some("auth.test.com"), false),
+    case("https://auth.test.com", None, None, false),
+    case("null", None, Some("localhost:8090"), false),
+    case("https://auth.test.com", Some("https://auth.test.com/"), Some("internal:8090"), true),
+    case("http://auth.test.com", Some("https://auth.test.com/"), Some("auth.test.com"), false),
+    case("https://evil.test.com", Some("https://auth.test.com/"), Some("evil.test.com"), false),
+    )]
+    pub fn same_origin_test(origin: &str, public_url: Option<&str>, host: Option<&str>, expected: bool) {
+        let public_url = public_url.map(|url| Url::parse(url).unwrap());
+        assert_eq!(is_same_origin(origin, public_url.as_ref(), host), expected);
+    }
+}
@@ -1,62 +1,313 @@
+use std::net::SocketAddr;
+use axum::extract::{ConnectInfo, Path};
 use axum::Json;
-use http::StatusCode;
-use log::info;
+use axum_extra::extract::cookie::Cookie;
+use axum_extra::extract::CookieJar;
+use http::{HeaderMap, StatusCode};
+use log::{info, trace, warn};
+use serde_json::json;
 use tower_sessions::Session;
+use uuid::Uuid;
+use crate::audit;
+use crate::backend::csrf;
+use crate::audit::{Event, Outcome};
 use crate::backend::middlewares::AccessUser;
-use crate::backend::models::ChangePassword;
-use crate::database;
+use crate::backend::models::{ChangeEmail, ChangePassword, Csrf, DeviceSession, PasskeyRegister, PasskeyRemove, RecoveryCodes, TotpConfirm, TotpSetup};
+use crate::consts::{MAX_PASSKEYS, MAX_PASSKEY_NAME_LENGTH};
+use crate::{config, database};
+use crate::email::{get_change_email_url, get_login_url, send_template};
+use crate::email::templates::locale;
 use crate::utils::crypto::{hash_password, verify_password};
-use crate::utils::input_val::is_password_valid;
+use crate::utils::input_val::{is_email_valid, is_password_valid};
+use crate::utils::{totp, webauthn};
+use webauthn_rs::prelude::{CreationChallengeResponse, Passkey, PasskeyRegistration};
 
-pub async fn change_password (
+/// Anti-CSRF token for the clients that don't load the home page, it replaces the previous one
+pub async fn csrf_token(
     session: Session,
+    _user: AccessUser,
+) -> axum::response::Result<Json<Csrf>> {
+    trace!("Issue anti-CSRF token");
+
+    Ok(Json(Csrf { csrf: csrf::issue(&session)? }))
+}
+
+pub async fn change_password (
     user: AccessUser,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(parameters): Json<ChangePassword>
 ) -> axum::response::Result<StatusCode> {
     info!("Changing user's password");
 
-    // Check that the anti-CSRF token isn't expired
-    let token_expiration = session.get::<i64>("csrf_expiration").or(Err(StatusCode::INTERNAL_SERVER_ERROR))?.ok_or(StatusCode::BAD_REQUEST)?;
-    if token_expiration < time::OffsetDateTime::now_utc().unix_timestamp() {
-        info!("Anti-CSRF token expired");
-        Err((StatusCode::BAD_REQUEST, "Anti-CSRF token expired"))?;
-    }
+    let changed = update_password(&user, &parameters);
+    let outcome = if changed.is_ok() { Outcome::Success } else { Outcome::Failure };
+    audit::record(Event::new("password.change", outcome).subject(&user.email).client(&addr, &headers));
+    changed?;
 
-    // Compare the anti-CSRF token saved with the given one
-    let token : String = session.get::<String>("csrf")
-        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
-        .ok_or(StatusCode::BAD_REQUEST)?;
-    if token != parameters.csrf {
-        info!("Anti-CSRF tokens don't match");
-        Err((StatusCode::BAD_REQUEST, "Anti-CSRF tokens don't match"))?;
-    }
+    // Security notification, the password is changed even if it can't be sent
+    send_template(&user.email, "password_changed", locale(&headers), &json!({"email": user.email, "link": get_login_url()}))
+        .unwrap_or_else(|_| warn!("Failed to send the password change notification"));
+
+    Ok(StatusCode::OK)
+}
 
+fn update_password(user: &AccessUser, parameters: &ChangePassword) -> Result<(), (StatusCode, &'static str)> {
     // TODO : Check the parameters then update the DB with the new password
 
     // Check if passwords match and the new password is not the same as the old one.
     if parameters.password != parameters.password2 || parameters.password == parameters.old_password {
-        Err(StatusCode::BAD_REQUEST)?;
+        return Err((StatusCode::BAD_REQUEST, "Invalid password"));
     }
 
     // Check if the new password meets validity criteria.
     if !is_password_valid(&parameters.password) {
-        return Err(StatusCode::BAD_REQUEST.into());
+        return Err((StatusCode::BAD_REQUEST, "Invalid password"));
     }
 
     let user_db = match database::user::get(&user.email) {
         Some(user) => user,
-        None => return Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+        None => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")),
     };
 
     // Verify if the old password provided matches the stored password hash.
     if !verify_password(&parameters.old_password, &user_db.hash) {
-        Err(StatusCode::BAD_REQUEST)?;
+        return Err((StatusCode::BAD_REQUEST, "Wrong password"));
     }
 
-    let user_hash : String = hash_password(&parameters.password).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let user_hash : String = hash_password(&parameters.password).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Hash error")))?;
 
     // Hash the new password before updating it in the database.
-    database::user::change_password(&user.email, &user_hash).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    database::user::change_password(&user.email, &user_hash).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))?;
+
+    // Sessions opened with the old password must not survive
+    database::revocation::revoke_all(&user.email).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))?;
+
+    Ok(())
+}
+
+/// Ask to change the email of the user, after checking its password again
+/// A confirmation link is sent to the new address, the email only changes once it is opened
+pub async fn change_email(
+    user: AccessUser,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(parameters): Json<ChangeEmail>
+) -> axum::response::Result<StatusCode> {
+    info!("Request email change");
+
+    let requested = request_email_change(&user, &parameters, locale(&headers));
+    let outcome = if requested.is_ok() { Outcome::Success } else { Outcome::Failure };
+    audit::record(Event::new("email.change_request", outcome).subject(&user.email).client(&addr, &headers));
+    requested?;
+
+    Ok(StatusCode::OK)
+}
+
+fn request_email_change(user: &AccessUser, parameters: &ChangeEmail, locale: &str) -> Result<(), (StatusCode, &'static str)> {
+    // Normalized the same way as at registration
+    let new_email = parameters.email.trim().to_ascii_lowercase();
+    if !is_email_valid(&new_email) || new_email == user.email {
+        return Err((StatusCode::BAD_REQUEST, "Invalid email"));
+    }
+
+    let user_db = database::user::get(&user.email).ok_or((StatusCode::INTERNAL_SERVER_ERROR, "Database error"))?;
+    if !verify_password(&parameters.password, &user_db.hash) {
+        return Err((StatusCode::BAD_REQUEST, "Wrong password"));
+    }
+
+    // Checked again when the change is confirmed, someone may register it meanwhile
+    if database::user::exists(&new_email).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))? {
+        return Err((StatusCode::BAD_REQUEST, "Email already used"));
+    }
+
+    let token = Uuid::new_v4().to_string();
+    let duration = core::time::Duration::from_secs(config::get().tokens.verify_link_duration);
+    database::token::add_change_email(&user.email, &new_email, &token, duration)
+        .or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))?;
+
+    send_template(&new_email, "email_change", locale, &json!({"email": user.email, "link": get_change_email_url(&token)}))
+        .or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to send the confirmation")))?;
+
+    // The current address is warned, the owner may not be the one asking
+    let data = json!({"email": user.email, "new_email": new_email, "link": get_login_url()});
+    send_template(&user.email, "email_change_requested", locale, &data)
+        .unwrap_or_else(|_| warn!("Failed to send the email change notification"));
+    Ok(())
+}
+
+/// Logout everywhere : revoke every JWT issued to the user until now
+pub async fn logout_all(
+    user: AccessUser,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    jar: CookieJar,
+) -> axum::response::Result<(CookieJar, StatusCode)> {
+    info!("Logout user everywhere");
+
+    database::revocation::revoke_all(&user.email).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    audit::record(Event::new("logout.all", Outcome::Success).subject(&user.email).client(&addr, &headers));
+
+    Ok((jar.remove(Cookie::from("access")), StatusCode::OK))
+}
+
+/// Start the TOTP enrolment of the user
+/// Returns the secret and the otpauth:// URI to scan with an authenticator app
+pub async fn totp_enroll(
+    user: AccessUser,
+) -> axum::response::Result<Json<TotpSetup>> {
+    info!("Enroll TOTP");
+
+    let secret = totp::generate_secret();
+    let url = totp::get_url(&secret, &user.email).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    if !database::user::enroll_totp(&user.email, &secret).or(Err(StatusCode::INTERNAL_SERVER_ERROR))? {
+        Err((StatusCode::BAD_REQUEST, "Two-factor authentication already enabled"))?;
+    }
+
+    Ok(Json(TotpSetup { secret, url }))
+}
+
+/// Confirm the TOTP enrolment with a first valid code
+/// Returns the recovery codes, they are only shown once
+pub async fn totp_confirm(
+    user: AccessUser,
+    headers: HeaderMap,
+    Json(parameters): Json<TotpConfirm>
+) -> axum::response::Result<Json<RecoveryCodes>> {
+    info!("Confirm TOTP");
+
+    let pending = database::user::get(&user.email)
+        .and_then(|u| u.totp)
+        .filter(|t| !t.confirmed)
+        .ok_or((StatusCode::BAD_REQUEST, "No pending two-factor enrolment"))?;
+
+    let step = totp::verify_current(&pending.secret, &parameters.code, pending.last_step)
+        .ok_or((StatusCode::BAD_REQUEST, "Invalid code"))?;
+
+    // Only the hashes of the recovery codes are saved
+    let codes = totp::generate_recovery_codes();
+    let hashes = codes.iter()
+        .map(|c| hash_password(c))
+        .collect::<Result<Vec<String>, _>>()
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    if !database::user::confirm_totp(&user.email, step, hashes).or(Err(StatusCode::INTERNAL_SERVER_ERROR))? {
+        Err(StatusCode::BAD_REQUEST)?;
+    }
+
+    send_template(&user.email, "totp_enabled", locale(&headers), &json!({"email": user.email, "link": get_login_url()}))
+        .unwrap_or_else(|_| warn!("Failed to send the TOTP notification"));
+
+    Ok(Json(RecoveryCodes { codes }))
+}
+
+/// Start the registration of a passkey, the challenge is signed by the authenticator
+/// Returns the options for navigator.credentials.create()
+pub async fn passkey_register_start(
+    session: Session,
+    user: AccessUser,
+) -> axum::response::Result<Json<CreationChallengeResponse>> {
+    info!("Start passkey registration");
+
+    let user_id = database::user::passkey_user_id(&user.email)
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    let existing: Vec<Passkey> = database::user::get(&user.email)
+        .and_then(|u| u.passkeys)
+        .map(|p| p.credentials.into_iter().map(|c| c.passkey).collect())
+        .unwrap_or_default();
+    if existing.len() >= MAX_PASSKEYS {
+        Err((StatusCode::BAD_REQUEST, "Too many passkeys"))?;
+    }
+
+    let (challenge, state) = webauthn::start_registration(user_id, &user.email, &existing)
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    session.insert("passkey_registration", state).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok(Json(challenge))
+}
+
+/// Check the new passkey against the challenge and save it
+pub async fn passkey_register_finish(
+    session: Session,
+    user: AccessUser,
+    headers: HeaderMap,
+    Json(parameters): Json<PasskeyRegister>
+) -> axum::response::Result<StatusCode> {
+    info!("Finish passkey registration");
+
+    // The challenge can only be used once
+    let state = session.remove::<PasskeyRegistration>("passkey_registration")
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or((StatusCode::BAD_REQUEST, "No pending passkey registration"))?;
+
+    let name = parameters.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_PASSKEY_NAME_LENGTH {
+        Err((StatusCode::BAD_REQUEST, "Invalid passkey name"))?;
+    }
+
+    let passkey = webauthn::finish_registration(&parameters.credential, &state)
+        .or(Err((StatusCode::BAD_REQUEST, "Invalid passkey")))?;
+
+    if !database::user::add_passkey(&user.email, name, &passkey).or(Err(StatusCode::INTERNAL_SERVER_ERROR))? {
+        Err((StatusCode::BAD_REQUEST, "Passkey already registered or too many passkeys"))?;
+    }
+
+    send_template(&user.email, "passkey_added", locale(&headers), &json!({"email": user.email, "name": name, "link": get_login_url()}))
+        .unwrap_or_else(|_| warn!("Failed to send the passkey notification"));
+
+    Ok(StatusCode::OK)
+}
+
+pub async fn passkey_remove(
+    user: AccessUser,
+    Json(parameters): Json<PasskeyRemove>
+) -> axum::response::Result<StatusCode> {
+    info!("Remove passkey");
+
+    if !database::user::remove_passkey(&user.email, &parameters.id).or(Err(StatusCode::INTERNAL_SERVER_ERROR))? {
+        Err((StatusCode::NOT_FOUND, "Passkey not found"))?;
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// Devices holding a session of the user, the most recently used first
+pub async fn sessions(user: AccessUser) -> axum::response::Result<Json<Vec<DeviceSession>>> {
+    info!("List sessions of user");
+
+    Ok(Json(device_sessions(&user).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?))
+}
+
+pub(crate) fn device_sessions(user: &AccessUser) -> anyhow::Result<Vec<DeviceSession>> {
+    Ok(database::family::list(&user.email)?
+        .into_iter()
+        .map(|(id, family)| DeviceSession {
+            current: id == user.family,
+            id,
+            label: family.device.label,
+            user_agent: family.device.user_agent,
+            ip: family.device.ip,
+            created: family.device.created,
+            last_used: family.device.last_used,
+        })
+        .collect())
+}
+
+/// Log a device out : its refresh token family is revoked, with the access JWTs it issued
+pub async fn revoke_session(
+    user: AccessUser,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> axum::response::Result<StatusCode> {
+    info!("Revoke session of user");
+
+    if !database::family::revoke_of(&user.email, &id).or(Err(StatusCode::INTERNAL_SERVER_ERROR))? {
+        Err((StatusCode::NOT_FOUND, "Session not found"))?;
+    }
+    audit::record(Event::new("session.revoke", Outcome::Success).subject(&user.email).client(&addr, &headers));
 
     Ok(StatusCode::OK)
 }
@@ -0,0 +1,283 @@
+use std::net::SocketAddr;
+use axum::extract::{ConnectInfo, Path, Query};
+use axum::response::{Html, IntoResponse, Response};
+use axum::Json;
+use http::{HeaderMap, StatusCode};
+use log::{info, warn};
+use serde_json::{json, Value};
+use crate::audit;
+use crate::audit::{Event, Outcome, Verification};
+use crate::backend::handlers_unauth::{send_reset_link, send_verification_link};
+use crate::backend::middlewares::{Admin, RequireRole};
+use crate::backend::models::{EmailPreview, RoleChange, UserPage, UserQuery, UserSummary};
+use crate::consts::{ADMIN_MAX_PAGE_SIZE, ADMIN_PAGE_SIZE, AUDIT_QUERY_LIMIT, AUDIT_QUERY_MAX_LIMIT, EMAIL_LOCALES};
+use crate::database;
+use crate::database::email::Email;
+use crate::database::user::User;
+use crate::email::get_login_url;
+use crate::email::templates;
+use crate::utils::crypto::hash_password;
+use crate::utils::oauth;
+
+type AdminError = (StatusCode, &'static str);
+
+fn summary(email: String, user: User) -> UserSummary {
+    UserSummary {
+        email,
+        verified: user.verified,
+        disabled: user.disabled,
+        role: user.role,
+        totp: user.totp.is_some_and(|t| t.confirmed),
+        passkeys: user.passkeys.map_or(0, |p| p.credentials.len()),
+    }
+}
+
+/// User targeted by an admin action
+fn target(email: &str) -> Result<User, AdminError> {
+    database::user::get(email).ok_or((StatusCode::NOT_FOUND, "Unknown user"))
+}
+
+/// An admin can't lock itself out
+fn not_self(admin: &RequireRole<Admin>, email: &str) -> Result<(), AdminError> {
+    match admin.email == email {
+        true => Err((StatusCode::BAD_REQUEST, "Not allowed on your own account")),
+        false => Ok(()),
+    }
+}
+
+/// Run an admin action on a user and record it in the audit log, whatever its outcome
+fn audited(
+    event: &str,
+    admin: &RequireRole<Admin>,
+    addr: &SocketAddr,
+    headers: &HeaderMap,
+    email: &str,
+    action: impl FnOnce() -> Result<(), AdminError>,
+) -> Result<StatusCode, AdminError> {
+    let result = action();
+
+    let outcome = if result.is_ok() { Outcome::Success } else { Outcome::Failure };
+    audit::record(Event::new(event, outcome).actor(&admin.email).subject(email).client(addr, headers));
+
+    result.map(|_| StatusCode::OK)
+}
+
+/// List the users, optionally searched by email
+pub async fn list_users(Query(query): Query<UserQuery>) -> Result<Json<UserPage>, AdminError> {
+    info!("List users");
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(ADMIN_PAGE_SIZE).clamp(1, ADMIN_MAX_PAGE_SIZE);
+    let search = query.search.unwrap_or_default();
+
+    let (total, users) = database::user::list(search.trim(), (page - 1) * per_page, per_page)
+        .or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))?;
+
+    Ok(Json(UserPage {
+        total,
+        page,
+        per_page,
+        users: users.into_iter().map(|(email, user)| summary(email, user)).collect(),
+    }))
+}
+
+pub async fn get_user(Path(email): Path<String>) -> Result<Json<UserSummary>, AdminError> {
+    info!("Get user");
+
+    let user = target(&email)?;
+    Ok(Json(summary(email, user)))
+}
+
+/// Disable a user : its sessions are revoked and it can't log in anymore
+pub async fn disable_user(
+    admin: RequireRole<Admin>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(email): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    info!("Disable user");
+
+    audited("admin.user.disable", &admin, &addr, &headers, &email, || {
+        not_self(&admin, &email)?;
+        target(&email)?;
+        database::user::set_disabled(&email, true).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))?;
+        database::revocation::revoke_all(&email).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))?;
+        Ok(())
+    })
+}
+
+pub async fn enable_user(
+    admin: RequireRole<Admin>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(email): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    info!("Enable user");
+
+    audited("admin.user.enable", &admin, &addr, &headers, &email, || {
+        target(&email)?;
+        database::user::set_disabled(&email, false).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))?;
+        Ok(())
+    })
+}
+
+/// Flag a user as unverified, it can log in again once it follows a new verification link
+pub async fn unverify_user(
+    admin: RequireRole<Admin>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(email): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    info!("Unverify user");
+
+    audited("admin.user.unverify", &admin, &addr, &headers, &email, || {
+        not_self(&admin, &email)?;
+        target(&email)?;
+        database::user::unverify(&email).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))?;
+        database::revocation::revoke_all(&email).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))?;
+        Ok(())
+    })
+}
+
+/// Delete a user and revoke its sessions, the email can then be registered again
+pub async fn delete_user(
+    admin: RequireRole<Admin>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(email): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    info!("Delete user");
+
+    audited("admin.user.delete", &admin, &addr, &headers, &email, || {
+        not_self(&admin, &email)?;
+        if !database::user::delete(&email).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))? {
+            return Err((StatusCode::NOT_FOUND, "Unknown user"));
+        }
+        database::revocation::revoke_all(&email).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))?;
+        Ok(())
+    })
+}
+
+/// Send a new verification link to an unverified user
+pub async fn resend_verification(
+    admin: RequireRole<Admin>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(email): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    info!("Resend verification link");
+
+    audited("admin.user.resend_verification", &admin, &addr, &headers, &email, || {
+        if target(&email)?.verified {
+            return Err((StatusCode::BAD_REQUEST, "User already verified"));
+        }
+        send_verification_link(&email, EMAIL_LOCALES[0]).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to send the email")))
+    })
+}
+
+/// Force a password reset : the password is replaced by a random one, the sessions are revoked,
+/// and a reset link is sent to the user
+pub async fn force_password_reset(
+    admin: RequireRole<Admin>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(email): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    info!("Force password reset");
+
+    audited("admin.user.force_reset", &admin, &addr, &headers, &email, || {
+        target(&email)?;
+        let hash = hash_password(&oauth::generate_secret()).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to hash password")))?;
+        database::user::change_password(&email, &hash).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))?;
+        database::revocation::revoke_all(&email).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))?;
+
+        // The password is already reset, the user can still ask for another link
+        send_reset_link(&email, EMAIL_LOCALES[0]).unwrap_or_else(|_| warn!("Failed to send the password reset email"));
+        Ok(())
+    })
+}
+
+/// Log a user out of every device
+pub async fn revoke_sessions(
+    admin: RequireRole<Admin>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(email): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    info!("Revoke sessions of user");
+
+    audited("admin.user.revoke_sessions", &admin, &addr, &headers, &email, || {
+        target(&email)?;
+        database::revocation::revoke_all(&email).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))
+    })
+}
+
+/// Change the role of a user, effective from its next access JWT
+pub async fn set_role(
+    admin: RequireRole<Admin>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(email): Path<String>,
+    Json(change): Json<RoleChange>,
+) -> Result<StatusCode, AdminError> {
+    info!("Change role of user");
+
+    audited("admin.user.set_role", &admin, &addr, &headers, &email, || {
+        not_self(&admin, &email)?;
+        target(&email)?;
+        database::user::set_role(&email, change.role).or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))?;
+        Ok(())
+    })
+}
+
+/// Search the audit log, newest entries first
+pub async fn audit_log(Query(query): Query<audit::Query>) -> Result<Json<Vec<Value>>, AdminError> {
+    info!("Search audit log");
+
+    let limit = query.limit.unwrap_or(AUDIT_QUERY_LIMIT).clamp(1, AUDIT_QUERY_MAX_LIMIT);
+    let path = audit::path().or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Audit log not opened")))?;
+    let entries = audit::search(&path, &query, limit)
+        .or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to read the audit log")))?;
+    Ok(Json(entries))
+}
+
+/// Check that the audit log hasn't been tampered with
+pub async fn audit_verify() -> Result<Json<Verification>, AdminError> {
+    info!("Verify audit log");
+
+    let path = audit::path().or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Audit log not opened")))?;
+    let verification = audit::verify_chain(&path)
+        .or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to read the audit log")))?;
+    if !verification.valid {
+        warn!("Audit log tampered");
+    }
+    Ok(Json(verification))
+}
+
+/// List the emails sent to an address, whatever their delivery status
+pub async fn email(Path(email): Path<String>) -> axum::response::Result<Json<Vec<Email>>> {
+    info!("List emails of recipient");
+
+    match database::email::get(&email) {
+        Ok(emails) => Ok(Json(emails)),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+    }
+}
+
+/// Render an email template with sample data
+pub async fn email_preview(
+    Path(name): Path<String>,
+    Query(preview): Query<EmailPreview>,
+) -> axum::response::Result<Response> {
+    let locale = preview.locale.as_deref().unwrap_or(EMAIL_LOCALES[0]);
+    let data = json!({"email": "user@example.com", "new_email": "new@example.com", "link": get_login_url()});
+
+    let email = templates::render(&name, locale, &data).or(Err(StatusCode::NOT_FOUND))?;
+
+    Ok(match preview.part.as_deref() {
+        None | Some("html") => Html(email.html).into_response(),
+        Some("txt") => email.text.into_response(),
+        Some("subject") => email.subject.into_response(),
+        Some(_) => StatusCode::BAD_REQUEST.into_response(),
+    })
+}
@@ -0,0 +1,301 @@
+use axum::extract::{OriginalUri, Query};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::{Form, Json};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use http::header::{AUTHORIZATION, CACHE_CONTROL, PRAGMA};
+use http::{HeaderMap, StatusCode};
+use log::{debug, info, trace, warn};
+use serde_json::{json, Value};
+use tower_sessions::Session;
+use url::Url;
+use crate::backend::csrf;
+use crate::backend::middlewares::{AccessUser, OAuthUser};
+use crate::backend::models::{AuthorizeRequest, ClientCredentials, Consent, NewClient, TokenRequest, TokenResponse};
+use crate::consts::{OAUTH_SCOPES, OAUTH_TOKEN_DURATION};
+use crate::database::authorization::Code;
+use crate::database::client::Client;
+use crate::utils::crypto::{hash_password, verify_password};
+use crate::utils::{jwt, oauth};
+use crate::{database, HBS};
+
+/// Check the client and the redirect URI of an authorization request
+/// These errors are shown to the user, never sent to a redirect URI that can't be trusted
+fn check_client(request: &AuthorizeRequest) -> Result<Client, (StatusCode, &'static str)> {
+    let client = database::client::get(&request.client_id)
+        .or(Err((StatusCode::INTERNAL_SERVER_ERROR, "Database error")))?
+        .ok_or((StatusCode::BAD_REQUEST, "Unknown client"))?;
+
+    if !client.redirect_uris.contains(&request.redirect_uri) {
+        debug!("Redirect URI not registered for the client");
+        return Err((StatusCode::BAD_REQUEST, "Invalid redirect URI"));
+    }
+
+    Ok(client)
+}
+
+/// Check the rest of an authorization request, returns the scopes requested
+/// The error is an OAuth error code, sent back to the client
+fn check_request(request: &AuthorizeRequest) -> Result<Vec<String>, &'static str> {
+    if request.response_type != "code" {
+        return Err("unsupported_response_type");
+    }
+
+    // PKCE is required for every client, even the confidential ones (OAuth 2.1)
+    if request.code_challenge.is_none() || request.code_challenge_method.as_deref() != Some("S256") {
+        return Err("invalid_request");
+    }
+
+    oauth::parse_scopes(&request.scope).ok_or("invalid_scope")
+}
+
+/// Send the user back to the client, with the state given by the client
+fn redirect_to_client(request: &AuthorizeRequest, params: &[(&str, &str)]) -> Response {
+    let mut url = match Url::parse(&request.redirect_uri) {
+        Ok(url) => url,
+        Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    {
+        let mut query = url.query_pairs_mut();
+        query.extend_pairs(params);
+        if let Some(state) = &request.state {
+            query.append_pair("state", state);
+        }
+    }
+
+    Redirect::to(url.as_str()).into_response()
+}
+
+/// Create an authorization code and send it to the client
+fn issue_code(email: &str, request: &AuthorizeRequest, scopes: Vec<String>) -> Result<Response, StatusCode> {
+    let code = oauth::generate_code();
+    database::authorization::add_code(&code, Code {
+        email: email.to_string(),
+        client_id: request.client_id.clone(),
+        redirect_uri: request.redirect_uri.clone(),
+        scopes,
+        nonce: request.nonce.clone(),
+        code_challenge: request.code_challenge.clone().unwrap_or_default(),
+        expiration: 0, // Set by the DB
+    }).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    trace!("Authorization code issued");
+    Ok(redirect_to_client(request, &[("code", &code)]))
+}
+
+/// Authorization endpoint : the user logs in and allows the client to access its account
+/// The consent page is skipped if the user already granted the scopes to the client
+pub async fn authorize(
+    session: Session,
+    user: Option<AccessUser>,
+    OriginalUri(uri): OriginalUri,
+    Query(request): Query<AuthorizeRequest>,
+) -> axum::response::Result<Response> {
+    info!("OAuth authorization request");
+
+    let client = check_client(&request)?;
+    let scopes = match check_request(&request) {
+        Ok(scopes) => scopes,
+        Err(error) => return Ok(redirect_to_client(&request, &[("error", error)])),
+    };
+
+    // Log in first, the login page comes back here afterwards
+    let Some(user) = user else {
+        trace!("User not logged in, redirect to the login page");
+        let next: String = url::form_urlencoded::byte_serialize(uri.to_string().as_bytes()).collect();
+        return Ok(Redirect::to(&format!("/login?next={next}")).into_response());
+    };
+
+    if database::authorization::has_consent(&user.email, &request.client_id, &scopes)
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))? {
+        trace!("Scopes already granted");
+        return Ok(issue_code(&user.email, &request, scopes)?);
+    }
+
+    let csrf = csrf::issue(&session)?;
+    let page = HBS.render("consent", &json!({
+        "email": user.email,
+        "client": client.name,
+        "scopes": scopes,
+        "request": request,
+        "token": csrf,
+    })).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok(Html(page).into_response())
+}
+
+/// Answer of the consent page
+pub async fn consent(
+    user: AccessUser,
+    Form(consent): Form<Consent>,
+) -> axum::response::Result<Response> {
+    info!("OAuth consent");
+
+    let request = consent.request;
+    check_client(&request)?;
+    let scopes = match check_request(&request) {
+        Ok(scopes) => scopes,
+        Err(error) => return Ok(redirect_to_client(&request, &[("error", error)])),
+    };
+
+    if consent.approve.is_none() {
+        info!("Access denied by the user");
+        return Ok(redirect_to_client(&request, &[("error", "access_denied")]));
+    }
+
+    database::authorization::consent(&user.email, &request.client_id, &scopes)
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok(issue_code(&user.email, &request, scopes)?)
+}
+
+/// Error of the token endpoint (RFC 6749 5.2)
+fn token_error(status: StatusCode, error: &str) -> Response {
+    (status, [(CACHE_CONTROL, "no-store")], Json(json!({"error": error}))).into_response()
+}
+
+/// Client credentials, from the Authorization header (client_secret_basic) or the body (client_secret_post)
+fn client_credentials(headers: &HeaderMap, request: &TokenRequest) -> Option<(String, Option<String>)> {
+    let basic = headers.get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|b| STANDARD.decode(b).ok())
+        .and_then(|b| String::from_utf8(b).ok());
+
+    match basic {
+        Some(credentials) => {
+            let (id, secret) = credentials.split_once(':')?;
+            Some((id.to_string(), Some(secret.to_string())))
+        },
+        None => Some((request.client_id.clone()?, request.client_secret.clone())),
+    }
+}
+
+/// Token endpoint : the client exchanges an authorization code for an access token and an ID token
+pub async fn token(headers: HeaderMap, Form(request): Form<TokenRequest>) -> Response {
+    info!("OAuth token request");
+
+    // Authenticate the client, public clients only have an id
+    let Some((client_id, secret)) = client_credentials(&headers, &request) else {
+        return token_error(StatusCode::UNAUTHORIZED, "invalid_client");
+    };
+    let client = match database::client::get(&client_id) {
+        Ok(Some(client)) => client,
+        Ok(None) => return token_error(StatusCode::UNAUTHORIZED, "invalid_client"),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    if let Some(hash) = &client.secret_hash {
+        if !secret.is_some_and(|s| verify_password(&s, hash)) {
+            warn!("Invalid client secret");
+            return token_error(StatusCode::UNAUTHORIZED, "invalid_client");
+        }
+    }
+
+    if request.grant_type != "authorization_code" {
+        return token_error(StatusCode::BAD_REQUEST, "unsupported_grant_type");
+    }
+
+    let code = match database::authorization::take_code(&request.code) {
+        Ok(Some(code)) => code,
+        Ok(None) => return token_error(StatusCode::BAD_REQUEST, "invalid_grant"),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    // The code is bound to the client, the redirect URI and the PKCE challenge of the authorization request
+    if code.client_id != client_id
+        || code.redirect_uri != request.redirect_uri
+        || !oauth::verify_pkce(&request.code_verifier, &code.code_challenge) {
+        warn!("Authorization code used with wrong parameters");
+        return token_error(StatusCode::BAD_REQUEST, "invalid_grant");
+    }
+
+    // The account may have been removed or disabled since the authorization
+    let user = match database::user::get(&code.email).filter(|u| u.can_login()) {
+        Some(user) => user,
+        None => return token_error(StatusCode::BAD_REQUEST, "invalid_grant"),
+    };
+
+    let scope = code.scopes.join(" ");
+    let with_email = code.scopes.iter().any(|s| s == "email");
+    let tokens = jwt::create_oauth_access(&code.email, &client_id, &scope)
+        .and_then(|access| Ok((access, jwt::create_id_token(&user.id, &code.email, &client_id, code.nonce.as_deref(), with_email)?)));
+    let (access_token, id_token) = match tokens {
+        Ok(tokens) => tokens,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    trace!("OAuth tokens issued");
+    let response = TokenResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in: OAUTH_TOKEN_DURATION,
+        id_token,
+        scope,
+    };
+    ([(CACHE_CONTROL, "no-store"), (PRAGMA, "no-cache")], Json(response)).into_response()
+}
+
+/// Claims about the user, restricted to the scopes granted to the client
+pub async fn userinfo(user: OAuthUser) -> Result<Json<Value>, StatusCode> {
+    info!("OAuth userinfo");
+
+    // Same subject as the ID token, the user may have been removed since the token was issued
+    let id = database::user::get(&user.email).ok_or(StatusCode::UNAUTHORIZED)?.id;
+    let mut claims = json!({"sub": id});
+    if user.scopes.iter().any(|s| s == "email") {
+        claims["email"] = json!(user.email);
+        claims["email_verified"] = json!(true);
+    }
+
+    Ok(Json(claims))
+}
+
+/// OpenID Connect discovery document
+pub async fn discovery() -> Json<Value> {
+    let issuer = oauth::issuer();
+
+    Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{issuer}/authorize"),
+        "token_endpoint": format!("{issuer}/token"),
+        "userinfo_endpoint": format!("{issuer}/userinfo"),
+        "jwks_uri": format!("{issuer}/.well-known/jwks.json"),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["EdDSA"],
+        "scopes_supported": OAUTH_SCOPES,
+        "claims_supported": ["sub", "email", "email_verified"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "code_challenge_methods_supported": ["S256"],
+    }))
+}
+
+/// Register an OAuth client, its secret is only returned here
+pub async fn register_client(Json(parameters): Json<NewClient>) -> axum::response::Result<Json<ClientCredentials>> {
+    info!("Register OAuth client");
+
+    let name = parameters.name.trim();
+    if name.is_empty() {
+        Err((StatusCode::BAD_REQUEST, "Invalid client name"))?;
+    }
+    if parameters.redirect_uris.is_empty() || !parameters.redirect_uris.iter().all(|u| oauth::is_redirect_uri_valid(u)) {
+        Err((StatusCode::BAD_REQUEST, "Invalid redirect URI"))?;
+    }
+
+    let secret = parameters.confidential.then(oauth::generate_secret);
+    let secret_hash = secret.as_deref()
+        .map(hash_password)
+        .transpose()
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let client_id = database::client::create(Client {
+        name: name.to_string(),
+        secret_hash,
+        redirect_uris: parameters.redirect_uris,
+        created: jsonwebtoken::get_current_timestamp(),
+    }).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok(Json(ClientCredentials { client_id, client_secret: secret }))
+}
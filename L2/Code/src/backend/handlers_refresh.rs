@@ -1,31 +1,61 @@
+use std::net::SocketAddr;
+use axum::extract::ConnectInfo;
+use axum::Json;
 use axum_extra::extract::cookie::{Cookie, SameSite};
 use axum_extra::extract::CookieJar;
-use http::StatusCode;
+use http::{HeaderMap, StatusCode};
 use log::info;
 use crate::backend::middlewares::RefreshUser;
-use crate::consts::ACCESS_TOKEN_DURATION;
+use crate::backend::models::Token;
+use crate::config;
+use crate::{audit, database};
+use crate::audit::{Event, Outcome};
 use crate::utils::jwt;
+use crate::utils::proxy::Scheme;
 
-pub async fn get_access(user: RefreshUser, jar: CookieJar) -> axum::response::Result<CookieJar> {
+pub async fn get_access(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    scheme: Scheme,
+    user: RefreshUser,
+    jar: CookieJar
+) -> axum::response::Result<(CookieJar, Json<Token>)> {
     info!("Get access JWT from refresh JWT");
     // User's refresh token is already checked through the extractor RefreshUser
     // You can trust the email given in the parameter "user"
 
-    // TODO : Create access JWT for email in user
-    let jwt : String = match jwt::create(&user.email, jwt::Role::Access) {
+    // The roles are read at each refresh, a change of role applies once the current access JWT expires
+    // A disabled account gets no new access JWT, and its refresh JWT is left as is
+    let roles = database::user::get(&user.email)
+        .filter(|u| u.can_login())
+        .ok_or(StatusCode::UNAUTHORIZED)?
+        .role
+        .granted();
+
+    // Both JWTs carry the generation the family is rotated to
+    let generation = user.generation + 1;
+    let refresh = jwt::create(&user.email, jwt::Role::Refresh, &user.family, generation, &[])
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let jwt : String = match jwt::create(&user.email, jwt::Role::Access, &user.family, generation, &roles) {
         Ok(jwt) => jwt,
         Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR).into()),
     };
 
+    // Rotate the refresh JWT once the new ones are ready, the one given by the user can't be used anymore
+    database::family::rotate(&user.family, user.generation, &addr.ip().to_string())
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .filter(|rotated| *rotated == generation)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
 
     // Add JWT to jar
     let cookie = Cookie::build(("access", jwt))
                               .path("/") // CHECK CA FAIT QUOI
-                              .max_age(time::Duration::seconds(ACCESS_TOKEN_DURATION as i64))
+                              .max_age(time::Duration::seconds(config::get().tokens.access_duration as i64))
                               .same_site(SameSite::Strict)
-                              .http_only(true);
-                              // .secure(true) add when HTTPS is enabled
+                              .http_only(true)
+                              .secure(config::get().secure_cookies() || scheme == Scheme::Https);
     let jar = jar.add(cookie);
 
-    Ok(jar)
+    audit::record(Event::new("token.refresh", Outcome::Success).subject(&user.email).client(&addr, &headers));
+    Ok((jar, Json(Token { token: refresh })))
 }
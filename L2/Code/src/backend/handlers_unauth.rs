@@ -1,25 +1,44 @@
 use axum::Json;
-use crate::backend::models::{NewUser, UserLogin, Token};
+use crate::backend::models::{ForgotPassword, OidcCallback, OidcLogin, PasskeyLogin, PasskeyLoginStart, LoginTotp, NewUser, ResetPassword, UserLogin, Token, TotpRequired};
 use axum::http::StatusCode;
-use axum::response::{Html, IntoResponse, Redirect};
-use log::{debug, info, trace};
-use serde_json::json;
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use log::{debug, info, trace, warn};
+use serde_json::{json, Value};
 use time::{Duration, OffsetDateTime};
 use tower_sessions::Session;
 use uuid::Uuid;
-use crate::{database, HBS};
+use crate::{audit, database, metrics, HBS};
+use crate::audit::{Event, Outcome};
+use crate::backend::csrf;
+use crate::backend::handlers_access::device_sessions;
 use crate::backend::middlewares::AccessUser;
-use axum::extract::Path;
+use std::net::SocketAddr;
+use axum::extract::{ConnectInfo, Path, Query};
+use http::HeaderMap;
+use http::header::{RETRY_AFTER, USER_AGENT};
 use axum_extra::extract::cookie::Cookie;
 use axum_extra::extract::CookieJar;
-use crate::database::email::Email;
-use crate::consts::{VERIFY_LINK_DURATION};
-use crate::email::{get_verification_url, send_mail};
-use crate::utils::{jwt};
+use jsonwebtoken::jwk::JwkSet;
+use crate::database::throttle::{email_key, ip_key, reset_request_key};
+use crate::database::token::Purpose;
+use crate::database::user::Totp;
+use crate::config;
+use crate::consts::{TOTP_LOGIN_DURATION, TOTP_MAX_ATTEMPTS};
+use crate::email::{get_login_url, get_reset_url, get_verification_url, send_template};
+use crate::email::templates::locale;
+use crate::utils::{jwt, oauth, oidc, totp, webauthn};
+use crate::utils::oidc::{PendingLogin, Provider};
+use webauthn_rs::prelude::{Passkey, PasskeyAuthentication, RequestChallengeResponse};
+use crate::utils::rate_limit::{EMAIL_POLICY, IP_POLICY, RESET_EMAIL_POLICY, RESET_IP_POLICY};
 use crate::utils::crypto::{default_hash, hash_password, verify_password};
+use anyhow::anyhow;
 use crate::utils::input_val::{is_email_valid, is_password_valid};
 
-pub async fn register(Json(user): Json<NewUser>) -> axum::response::Result<StatusCode> {
+pub async fn register(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(user): Json<NewUser>
+) -> axum::response::Result<StatusCode> {
     info!("Register new user");
 
     // TODO: Register a new user
@@ -34,6 +53,7 @@ pub async fn register(Json(user): Json<NewUser>) -> axum::response::Result<Statu
         !is_email_valid(&email) ||
         !is_password_valid(&user.password) {
 
+        audit::record(Event::new("register", Outcome::Failure).subject(&email).client(&addr, &headers));
         return Err(StatusCode::BAD_REQUEST.into());
     }
 
@@ -42,46 +62,115 @@ pub async fn register(Json(user): Json<NewUser>) -> axum::response::Result<Statu
 
     // Check if the email already exists in the database
     match database::user::exists(&email) {
-        Ok(true) => return Err(StatusCode::BAD_REQUEST.into()),
+        Ok(true) => {
+            audit::record(Event::new("register", Outcome::Failure).subject(&email).client(&addr, &headers));
+            return Err(StatusCode::BAD_REQUEST.into())
+        },
         Ok(false) => database::user::create(&email, &user_hash).or(Err(StatusCode::BAD_REQUEST))?,
         Err(_) => return Err(StatusCode::BAD_REQUEST.into()),
     };
 
+    audit::record(Event::new("register", Outcome::Success).subject(&email).client(&addr, &headers));
+    metrics::REGISTRATIONS.inc();
+
+    send_verification_link(&email, locale(&headers)).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok(StatusCode::OK)
+}
+
+/// Send a new verification link by email
+pub(crate) fn send_verification_link(email: &str, locale: &str) -> anyhow::Result<()> {
     // Generate a unique verification token
     let uuid : String = Uuid::new_v4().to_string();
 
     // Add the token to the database with a expiration duration
-    database::token::add(&email, &uuid, core::time::Duration::from_secs(VERIFY_LINK_DURATION as u64)).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    database::token::add(email, &uuid, core::time::Duration::from_secs(config::get().tokens.verify_link_duration), Purpose::Verify)?;
 
     // Create a verification link for the email
-    let subject : String = "Confirm your account".to_string();
     let link : String = get_verification_url(&uuid);
-    let body : String = format!("Click on the following link to verify your account : {}", link);
 
     // Send the confirmation email
-    send_mail(&email, &subject, &body).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
-    Ok(StatusCode::OK)
+    send_template(email, "verify", locale, &json!({"email": email, "link": link}))
 }
 
-pub async fn verify(Path(token): Path<String>) -> Redirect {
+pub async fn verify(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(token): Path<String>
+) -> Redirect {
     info!("Verify account");
 
     // TODO: Flag user's account as verified (with the given token)
-    match database::token::consume(token) {
+    match database::token::consume(token, Purpose::Verify) {
         Ok(email) => {
             match database::user::verify(&email) {
                 // Redirect to a success page if verification is successful
-                Ok(true) => Redirect::to("/?verify=ok"),
+                Ok(true) => {
+                    audit::record(Event::new("verify", Outcome::Success).subject(&email).client(&addr, &headers));
+                    metrics::verification(true);
+                    Redirect::to("/?verify=ok")
+                },
                 // Redirect to a failure page if verification fails
-                _ => Redirect::to("/?verify=failed"),
+                _ => {
+                    audit::record(Event::new("verify", Outcome::Failure).subject(&email).client(&addr, &headers));
+                    metrics::verification(false);
+                    Redirect::to("/?verify=failed")
+                },
             }
         },
         // Redirect to a failure page if the token is invalid or expired
-        _ => Redirect::to("/?verify=failed"),
+        _ => {
+            audit::record(Event::new("verify", Outcome::Failure).client(&addr, &headers));
+            metrics::verification(false);
+            Redirect::to("/?verify=failed")
+        },
+    }
+}
+
+/// Confirm an email change from the link sent to the new address
+/// Everything linked to the old email moves to the new one, and every session is logged out :
+/// the JWTs name the old email, which anyone could register again
+pub async fn confirm_email_change(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(token): Path<String>
+) -> Redirect {
+    info!("Confirm email change");
+
+    match change_email(token) {
+        Ok((email, new_email)) => {
+            audit::record(Event::new("email.change", Outcome::Success).subject(&email).detail(&new_email).client(&addr, &headers));
+
+            // The old address is the last one to hear about the account
+            send_template(&email, "email_changed", locale(&headers), &json!({"email": email, "new_email": new_email}))
+                .unwrap_or_else(|_| warn!("Failed to send the email change notification"));
+
+            Redirect::to("/?email_change=ok")
+        },
+        Err(e) => {
+            debug!("Email change failed : {e}");
+            audit::record(Event::new("email.change", Outcome::Failure).client(&addr, &headers));
+            Redirect::to("/?email_change=failed")
+        },
+    }
+}
+
+fn change_email(token: String) -> anyhow::Result<(String, String)> {
+    let (email, new_email) = database::token::consume_change_email(token)?;
+
+    // Revoke first, a failed rename must not leave sessions alive under the old email
+    database::revocation::revoke_all(&email)?;
+    if !database::user::rename(&email, &new_email)? {
+        return Err(anyhow!("User doesn't exist or new email already used"));
     }
+    Ok((email, new_email))
 }
 
-pub async fn login(Json(user_login): Json<UserLogin>) -> axum::response::Result<Json<Token>> {
+pub async fn login(
+    session: Session,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(user_login): Json<UserLogin>
+) -> axum::response::Result<Response> {
     info!("Login user");
 
     // TODO: Login user
@@ -90,30 +179,468 @@ pub async fn login(Json(user_login): Json<UserLogin>) -> axum::response::Result<
     // Normalize email by trimming and converting to lowercase
     let email : String = user_login.email.trim().to_ascii_lowercase();
 
+    // Throttled attempts are refused before checking the password
+    if let Some(seconds) = retry_after(&addr, &email).or(Err(StatusCode::INTERNAL_SERVER_ERROR))? {
+        return Err(too_many_attempts(seconds).into());
+    }
+
     // Check if the user exists and the password matches
-    // Note : Using get and .verified (instead of .exists, .get, and .verify) prevents calling the database three times
-    let ok : bool = match database::user::get(&email) {
-        Some(user) => {
-            user.verified && verify_password(&user_login.password, &user.hash)
+    // Note : Using get and .can_login (instead of .exists, .get, and .verify) prevents calling the database three times
+    let user = match database::user::get(&email) {
+        Some(user) if user.can_login() && verify_password(&user_login.password, &user.hash) => user,
+        Some(_) => {
+            record_failure("login.password", &addr, &email, &headers);
+            return Err((StatusCode::UNAUTHORIZED, "Login failed").into())
         },
         // If the user doesn't exist, use a default hash to prevent timing attacks
         None => {
             verify_password(&user_login.password, &default_hash());
-            false
+            record_failure("login.password", &addr, &email, &headers);
+            return Err((StatusCode::UNAUTHORIZED, "Login failed").into())
         }
     };
 
-    match ok {
-        true => {
-            // Generate a refresh JWT token for the user
-            let jwt: String = jwt::create(&email, jwt::Role::Refresh).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
-            let token: Token = Token { token: jwt };
-            Ok(Json::from(token))
+    // If the user enabled the second factor, the refresh JWT is only given after a valid TOTP code
+    if user.totp.is_some_and(|t| t.confirmed) {
+        start_totp_login(&session, &email)?;
+        return Ok((StatusCode::ACCEPTED, Json(TotpRequired { totp_required: true })).into_response());
+    }
+
+    database::throttle::reset(&email_key(&email)).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    audit::record(Event::new("login.password", Outcome::Success).subject(&email).client(&addr, &headers));
+    metrics::login("password", true);
+
+    // Generate a refresh JWT token for the user
+    let jwt: String = new_refresh_token(&email, &addr, &headers).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok(Json(Token { token: jwt }).into_response())
+}
+
+/// First step of a passwordless login : the email is given, the passkeys of the user are challenged
+/// Returns the options for navigator.credentials.get()
+pub async fn login_passkey_start(
+    session: Session,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(parameters): Json<PasskeyLoginStart>
+) -> axum::response::Result<Json<RequestChallengeResponse>> {
+    info!("Start passkey login");
+
+    let email : String = parameters.email.trim().to_ascii_lowercase();
+
+    if let Some(seconds) = retry_after(&addr, &email).or(Err(StatusCode::INTERNAL_SERVER_ERROR))? {
+        return Err(too_many_attempts(seconds).into());
+    }
+
+    let passkeys: Vec<Passkey> = database::user::get(&email)
+        .filter(|u| u.can_login())
+        .and_then(|u| u.passkeys)
+        .map(|p| p.credentials.into_iter().map(|c| c.passkey).collect())
+        .unwrap_or_default();
+
+    // Without passkey, a look-alike challenge is given and the login fails at the end, like a wrong passkey
+    let (challenge, state) = if passkeys.is_empty() {
+        (webauthn::fake_authentication(&email).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?, None)
+    } else {
+        let (challenge, state) = webauthn::start_authentication(&passkeys).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+        (challenge, Some(state))
+    };
+    session.insert("passkey_email", email).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    session.insert("passkey_authentication", state).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok(Json(challenge))
+}
+
+/// Second step of a passwordless login : the signed challenge is checked
+/// A passkey already proves possession and user verification, no TOTP is asked
+pub async fn login_passkey_finish(
+    session: Session,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(parameters): Json<PasskeyLogin>
+) -> axum::response::Result<Json<Token>> {
+    info!("Finish passkey login");
+
+    // The challenge can only be used once
+    let email = session.remove::<String>("passkey_email")
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or((StatusCode::UNAUTHORIZED, "No pending login"))?;
+    let state = session.remove::<Option<PasskeyAuthentication>>("passkey_authentication")
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or((StatusCode::UNAUTHORIZED, "No pending login"))?;
+
+    if let Some(seconds) = retry_after(&addr, &email).or(Err(StatusCode::INTERNAL_SERVER_ERROR))? {
+        return Err(too_many_attempts(seconds).into());
+    }
+
+    let passkeys: Vec<Passkey> = database::user::get(&email)
+        .filter(|u| u.can_login())
+        .and_then(|u| u.passkeys)
+        .map(|p| p.credentials.into_iter().map(|c| c.passkey).collect())
+        .unwrap_or_default();
+
+    let result = state.ok_or(anyhow!("No passkey for this account"))
+        .and_then(|state| webauthn::finish_authentication(&parameters.credential, &state, passkeys));
+    let passkeys = match result {
+        Ok(passkeys) => passkeys,
+        Err(_) => {
+            record_failure("login.passkey", &addr, &email, &headers);
+            return Err((StatusCode::UNAUTHORIZED, "Login failed").into())
+        },
+    };
+
+    database::user::update_passkeys(&email, &passkeys).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    database::throttle::reset(&email_key(&email)).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    audit::record(Event::new("login.passkey", Outcome::Success).subject(&email).client(&addr, &headers));
+    metrics::login("passkey", true);
+
+    // Generate a refresh JWT token for the user
+    let jwt: String = new_refresh_token(&email, &addr, &headers).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok(Json(Token { token: jwt }))
+}
+
+/// Save a login waiting for the TOTP code in the session, the first factor has been checked
+fn start_totp_login(session: &Session, email: &str) -> Result<(), StatusCode> {
+    debug!("TOTP required, saving pending login in session");
+    let expiration = OffsetDateTime::now_utc() + Duration::seconds(TOTP_LOGIN_DURATION);
+
+    session.insert("totp_email", email).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    session.insert("totp_expiration", expiration.unix_timestamp()).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    session.insert("totp_attempts", 0u8).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok(())
+}
+
+/// Start a login at an external identity provider
+pub async fn login_oidc(
+    session: Session,
+    Path(provider): Path<String>,
+    Query(parameters): Query<OidcLogin>,
+) -> axum::response::Result<Redirect> {
+    info!("Start login at external identity provider");
+
+    let provider = oidc::provider(&provider).ok_or(StatusCode::NOT_FOUND)?;
+    let metadata = oidc::discover(provider).await.map_err(|e| {
+        warn!("Discovery of {} failed : {e}", provider.issuer);
+        StatusCode::BAD_GATEWAY
+    })?;
+
+    let login = PendingLogin::new(provider, parameters.next);
+    let url = oidc::authorization_url(provider, &metadata, &login).or(Err(StatusCode::BAD_GATEWAY))?;
+    session.insert("oidc_login", login).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    Ok(Redirect::to(&url))
+}
+
+/// Callback of the external identity provider
+/// The refresh JWT can't be returned as JSON to a redirect : a page saves it like the login page does
+pub async fn login_oidc_callback(
+    session: Session,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(provider): Path<String>,
+    headers: HeaderMap,
+    Query(callback): Query<OidcCallback>,
+) -> axum::response::Result<Response> {
+    info!("Login from external identity provider");
+    let failed = || {
+        audit::record(Event::new("login.oidc", Outcome::Failure).client(&addr, &headers));
+        metrics::login("oidc", false);
+        Redirect::to("/login?oidc=failed").into_response()
+    };
+
+    // The callback must answer the login started in this session
+    let login = session.remove::<PendingLogin>("oidc_login").or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let login = match login {
+        Some(l) if l.provider == provider && callback.state.as_ref() == Some(&l.state) => l,
+        _ => {
+            warn!("Callback without matching login");
+            return Ok(failed());
+        }
+    };
+    if login.expiration < OffsetDateTime::now_utc().unix_timestamp() {
+        info!("External login expired");
+        return Ok(failed());
+    }
+    let (Some(provider), Some(code)) = (oidc::provider(&provider), callback.code) else {
+        info!("External login refused : {}", callback.error.unwrap_or_default());
+        return Ok(failed());
+    };
+
+    let email = match external_login(provider, &login, &code, &headers).await {
+        Ok(email) => email,
+        Err(e) => {
+            warn!("External login failed : {e}");
+            return Ok(failed());
+        }
+    };
+
+    // Same second factor as a login with a password
+    let next: String = login.next.unwrap_or_default();
+    let user = database::user::get(&email).ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !user.can_login() {
+        info!("External login to a disabled account");
+        return Ok(failed());
+    }
+    if user.totp.is_some_and(|t| t.confirmed) {
+        start_totp_login(&session, &email)?;
+        let next: String = url::form_urlencoded::byte_serialize(next.as_bytes()).collect();
+        return Ok(Redirect::to(&format!("/login?totp=1&next={next}")).into_response());
+    }
+
+    audit::record(Event::new("login.oidc", Outcome::Success).subject(&email).client(&addr, &headers));
+    metrics::login("oidc", true);
+
+    let jwt = new_refresh_token(&email, &addr, &headers).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let page = HBS.render("oidc", &json!({"token": jwt, "next": next})).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok(Html(page).into_response())
+}
+
+/// Authenticate the user at the provider and return the email of its local account
+/// The identity is linked to the account with the same email, created if needed, only if the provider verified
+/// the email. Once linked, the identity logs in to the same account even if its email changes at the provider
+async fn external_login(provider: &Provider, login: &PendingLogin, code: &str, headers: &HeaderMap) -> anyhow::Result<String> {
+    let metadata = oidc::discover(provider).await?;
+    let token = oidc::exchange_code(provider, &metadata, code, &login.verifier).await?;
+    let jwks = oidc::fetch_jwks(&metadata).await?;
+    let identity = oidc::validate_id_token(provider, &metadata, &jwks, &token, &login.nonce)?;
+
+    if let Some(email) = database::user::find_identity(&provider.issuer, &identity.subject)? {
+        trace!("Known external identity");
+        return Ok(email);
+    }
+
+    let email = identity.email
+        .filter(|_| identity.email_verified)
+        .map(|e| e.trim().to_ascii_lowercase())
+        .filter(|e| is_email_valid(e))
+        .ok_or(anyhow!("No verified email for the external identity"))?;
+
+    // No usable password, the user can still set one with "forgot password"
+    let hash = hash_password(&oauth::generate_secret()).or(Err(anyhow!("Failed to hash password")))?;
+    database::user::create(&email, &hash)?;
+    database::user::verify_external(&email, &hash)?;
+    database::user::link_identity(&email, &provider.issuer, &identity.subject)?;
+
+    let data = json!({"email": email, "provider": provider.name, "link": get_login_url()});
+    send_template(&email, "identity_linked", locale(headers), &data)
+        .unwrap_or_else(|_| warn!("Failed to send the external identity notification"));
+
+    Ok(email)
+}
+
+/// Seconds to wait before the IP and the email are both allowed to try logging in again
+fn retry_after(addr: &SocketAddr, email: &str) -> anyhow::Result<Option<u64>> {
+    let ip = database::throttle::retry_after(&ip_key(addr), &IP_POLICY)?;
+    let email = database::throttle::retry_after(&email_key(email), &EMAIL_POLICY)?;
+    Ok(ip.max(email))
+}
+
+fn too_many_attempts(seconds: u64) -> Response {
+    info!("Login throttled");
+    let retry_after = [(RETRY_AFTER, seconds.to_string())];
+    (StatusCode::TOO_MANY_REQUESTS, retry_after, "Too many failed logins, retry later").into_response()
+}
+
+/// Count a failed login against the IP and the email
+/// The owner of the account is warned by email when it gets locked
+fn record_failure(event: &str, addr: &SocketAddr, email: &str, headers: &HeaderMap) {
+    audit::record(Event::new(event, Outcome::Failure).subject(email).client(addr, headers));
+    metrics::login(event.trim_start_matches("login."), false);
+
+    if database::throttle::fail(&ip_key(addr), &IP_POLICY).is_err() {
+        warn!("Failed to record the failed login of the IP");
+    }
+
+    match database::throttle::fail(&email_key(email), &EMAIL_POLICY) {
+        Ok(Some(locked_until)) if database::user::exists(email).unwrap_or(false) => {
+            let minutes = locked_until.saturating_sub(jsonwebtoken::get_current_timestamp()).div_ceil(60);
+            let data = json!({"email": email, "minutes": minutes, "link": get_login_url()});
+            send_template(email, "account_locked", locale(headers), &data)
+                .unwrap_or_else(|_| warn!("Failed to send the lockout notification"));
+        },
+        Ok(_) => {},
+        Err(_) => warn!("Failed to record the failed login of the email"),
+    }
+}
+
+/// Second step of the login, only for users with a confirmed TOTP
+/// Accepts either a TOTP code or one of the recovery codes
+pub async fn login_totp(
+    session: Session,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(login): Json<LoginTotp>
+) -> axum::response::Result<Json<Token>> {
+    info!("Login user with TOTP");
+
+    // Retrieve the pending login, the password has already been checked
+    let email = session.get::<String>("totp_email")
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or((StatusCode::UNAUTHORIZED, "No pending login"))?;
+    let expiration = session.get::<i64>("totp_expiration")
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let attempts = session.get::<u8>("totp_attempts")
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .unwrap_or(TOTP_MAX_ATTEMPTS);
+
+    if expiration < OffsetDateTime::now_utc().unix_timestamp() || attempts >= TOTP_MAX_ATTEMPTS {
+        info!("Pending login expired or too many attempts");
+        clear_pending_login(&session);
+        Err((StatusCode::UNAUTHORIZED, "Login expired, please log in again"))?;
+    }
+
+    // Wrong codes count as failed logins, the account may have been locked since the password was checked
+    if let Some(seconds) = retry_after(&addr, &email).or(Err(StatusCode::INTERNAL_SERVER_ERROR))? {
+        return Err(too_many_attempts(seconds).into());
+    }
+
+    let totp = database::user::get(&email)
+        .filter(|u| u.can_login())
+        .and_then(|u| u.totp)
+        .filter(|t| t.confirmed)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    if !check_second_factor(&email, &totp, &login.code).or(Err(StatusCode::INTERNAL_SERVER_ERROR))? {
+        session.insert("totp_attempts", attempts + 1).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+        record_failure("login.totp", &addr, &email, &headers);
+        Err((StatusCode::UNAUTHORIZED, "Invalid code"))?;
+    }
+
+    clear_pending_login(&session);
+    database::throttle::reset(&email_key(&email)).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    audit::record(Event::new("login.totp", Outcome::Success).subject(&email).client(&addr, &headers));
+    metrics::login("totp", true);
+
+    // Generate a refresh JWT token for the user
+    let jwt: String = new_refresh_token(&email, &addr, &headers).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok(Json(Token { token: jwt }))
+}
+
+/// Start a new family of refresh tokens for the device logging in and return its first refresh JWT
+fn new_refresh_token(email: &str, addr: &SocketAddr, headers: &HeaderMap) -> anyhow::Result<String> {
+    let user_agent = headers.get(USER_AGENT).and_then(|h| h.to_str().ok()).unwrap_or_default();
+    let family = database::family::create(email, user_agent, &addr.ip().to_string())?;
+    jwt::create(email, jwt::Role::Refresh, &family, 0, &[])
+}
+
+/// Check a TOTP code, or a recovery code if it doesn't look like a TOTP code
+/// Used codes are flagged in the DB so they can't be replayed
+fn check_second_factor(email: &str, totp: &Totp, code: &str) -> anyhow::Result<bool> {
+    if let Some(step) = totp::verify_current(&totp.secret, code, totp.last_step) {
+        return database::user::use_totp_step(email, step);
+    }
+
+    let code = totp::normalize_recovery_code(code);
+    match totp.recovery_codes.iter().find(|hash| verify_password(&code, hash)) {
+        Some(hash) => {
+            info!("Recovery code used");
+            database::user::use_recovery_code(email, hash)
         },
-        false => Err(axum::response::IntoResponse::into_response((StatusCode::UNAUTHORIZED, "Login failed")).into()),
+        None => Ok(false),
     }
 }
 
+fn clear_pending_login(session: &Session) {
+    session.remove::<String>("totp_email").ok();
+    session.remove::<i64>("totp_expiration").ok();
+    session.remove::<u8>("totp_attempts").ok();
+}
+
+/// Send a password reset link by email
+/// The response is the same whether the account exists or not, to avoid leaking registered emails
+pub async fn forgot_password(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(parameters): Json<ForgotPassword>
+) -> Response {
+    info!("Password reset requested");
+
+    // Normalize email by trimming and converting to lowercase
+    let email : String = parameters.email.trim().to_ascii_lowercase();
+
+    // Every request counts, whether the email is known or not, so that the answer gives nothing away
+    let ip = reset_request_key(&ip_key(&addr));
+    let account = reset_request_key(&email_key(&email));
+    let retry_after = database::throttle::retry_after(&ip, &RESET_IP_POLICY)
+        .and_then(|ip| Ok(ip.max(database::throttle::retry_after(&account, &RESET_EMAIL_POLICY)?)));
+    match retry_after {
+        Ok(Some(seconds)) => {
+            info!("Password reset throttled");
+            audit::record(Event::new("password.reset_request", Outcome::Failure).subject(&email).client(&addr, &headers));
+            let retry_after = [(RETRY_AFTER, seconds.to_string())];
+            return (StatusCode::TOO_MANY_REQUESTS, retry_after, "Too many password reset requests, retry later").into_response();
+        },
+        Ok(None) => {},
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+    if database::throttle::fail(&ip, &RESET_IP_POLICY).is_err()
+        || database::throttle::fail(&account, &RESET_EMAIL_POLICY).is_err() {
+        warn!("Failed to record the password reset request");
+    }
+    audit::record(Event::new("password.reset_request", Outcome::Success).subject(&email).client(&addr, &headers));
+
+    if database::user::exists(&email).unwrap_or(false) {
+        if send_reset_link(&email, locale(&headers)).is_err() {
+            warn!("Failed to send the password reset email");
+        }
+    } else {
+        trace!("Password reset for an unknown user, ignored");
+    }
+
+    StatusCode::OK.into_response()
+}
+
+/// Send a password reset link by email
+pub(crate) fn send_reset_link(email: &str, locale: &str) -> anyhow::Result<()> {
+    let token : String = Uuid::new_v4().to_string();
+    database::token::add(email, &token, core::time::Duration::from_secs(config::get().tokens.reset_link_duration), Purpose::Reset)?;
+
+    let link : String = get_reset_url(&token);
+    send_template(email, "reset", locale, &json!({"email": email, "link": link}))
+}
+
+/// Serve the page to choose a new password, the token is only consumed when the form is submitted
+pub async fn reset_password_page(Path(token): Path<String>) -> impl IntoResponse {
+    Html(HBS.render("reset", &json!({"token": token})).unwrap())
+}
+
+/// Set a new password with a reset token
+/// Every session of the user and every other reset link are revoked
+pub async fn reset_password(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+    Json(parameters): Json<ResetPassword>
+) -> axum::response::Result<StatusCode> {
+    info!("Reset password");
+
+    // Check the new password before burning the token
+    if parameters.password != parameters.password2 || !is_password_valid(&parameters.password) {
+        audit::record(Event::new("password.reset", Outcome::Failure).client(&addr, &headers));
+        Err((StatusCode::BAD_REQUEST, "Invalid password"))?;
+    }
+
+    let Ok(email) = database::token::consume(token, Purpose::Reset) else {
+        audit::record(Event::new("password.reset", Outcome::Failure).client(&addr, &headers));
+        Err((StatusCode::BAD_REQUEST, "Invalid or expired link"))?
+    };
+
+    let user_hash : String = hash_password(&parameters.password).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    database::user::change_password(&email, &user_hash).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    // The other reset links sent to the user can't be used anymore
+    database::token::revoke_all(&email, Purpose::Reset).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    // Following the link proves the ownership of the email
+    database::user::verify(&email).ok();
+
+    database::revocation::revoke_all(&email).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    audit::record(Event::new("password.reset", Outcome::Success).subject(&email).client(&addr, &headers));
+
+    // Security notification, the password is changed even if it can't be sent
+    send_template(&email, "password_changed", locale(&headers), &json!({"email": email, "link": get_login_url()}))
+        .unwrap_or_else(|_| warn!("Failed to send the password change notification"));
+
+    Ok(StatusCode::OK)
+}
 
 /// Serve index page
 /// If the user is logged, add a anti-CSRF token to the password change form
@@ -128,34 +655,63 @@ pub async fn home(
         Some(user) => {
             debug!("Add anti-CSRF token to home");
 
-            // Generate anti-CSRF token
-            let token = Uuid::new_v4().to_string();
-            let expiration = OffsetDateTime::now_utc() + Duration::minutes(10);
+            let token = csrf::issue(&session)?;
+
+            let db_user = database::user::get(&user.email);
+            let totp = db_user.as_ref()
+                .and_then(|u| u.totp.as_ref())
+                .is_some_and(|t| t.confirmed);
+            let passkeys: Vec<Value> = db_user
+                .and_then(|u| u.passkeys)
+                .map(|p| p.credentials)
+                .unwrap_or_default()
+                .iter()
+                .map(|c| json!({"id": webauthn::passkey_id(&c.passkey), "name": c.name, "created": c.created}))
+                .collect();
 
-            // Add token+exp to session
-            session.insert("csrf", token.clone()).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
-            session.insert("csrf_expiration", expiration.unix_timestamp()).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+            let sessions = device_sessions(&user).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
 
-            Some(json!({"email": user.email, "token": token}))
+            Some(json!({"email": user.email, "token": token, "totp": totp, "passkeys": passkeys, "sessions": sessions}))
         },
         None => None, // Can't use user.map, async move are experimental
     };
 
     Ok(Html(HBS.render("index", &infos).unwrap()))
 }
-/// DEBUG/ADMIN endpoint
-/// List pending emails to send
-pub async fn email(Path(email): Path<String>) -> axum::response::Result<Json<Vec<Email>>> {
-    match database::email::get(&email) {
-        Ok(emails) => Ok(Json(emails)),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR.into()),
+/// Remove the access JWT from the cookies and revoke it server-side
+/// The refresh JWT isn't sent here, its family is revoked instead
+/// Only POST, another site could log the user out with a mere link otherwise
+pub async fn logout(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    user: Option<AccessUser>,
+    jar: CookieJar
+) -> (CookieJar, Redirect) {
+    if let Some(user) = user {
+        info!("Revoke JWTs of the session");
+        database::revocation::deny(&user.jti, user.exp as u64).ok();
+        database::family::revoke(&user.family).ok();
+        audit::record(Event::new("logout", Outcome::Success).actor(&user.email).client(&addr, &headers));
     }
-}
-pub async fn logout(jar: CookieJar) -> (CookieJar, Redirect) {
+
     let jar = jar.remove(Cookie::from("access"));
     (jar, Redirect::to("/"))
 }
+/// Public keys verifying the access JWTs, for the other services
+pub async fn jwks() -> axum::response::Result<Json<JwkSet>> {
+    Ok(Json(jwt::jwks().or(Err(StatusCode::INTERNAL_SERVER_ERROR))?))
+}
+/// Metrics for Prometheus, protected by METRICS_TOKEN if set
+pub async fn metrics(headers: HeaderMap) -> axum::response::Result<String> {
+    if !metrics::authorized(&headers) {
+        Err(StatusCode::UNAUTHORIZED)?;
+    }
+    Ok(metrics::render().or(Err(StatusCode::INTERNAL_SERVER_ERROR))?)
+}
 pub async fn login_page() -> impl IntoResponse {
-    Html(HBS.render("login", &Some(())).unwrap())
+    let providers: Vec<Value> = oidc::providers().iter()
+        .map(|p| json!({"id": p.id, "name": p.name}))
+        .collect();
+    Html(HBS.render("login", &json!({"providers": providers})).unwrap())
 }
 
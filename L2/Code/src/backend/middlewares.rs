@@ -1,19 +1,55 @@
+use std::marker::PhantomData;
+use std::ops::Deref;
 use axum::async_trait;
 use axum::extract::FromRequestParts;
+use axum::response::{IntoResponse, Response};
 use axum_extra::extract::CookieJar;
 use http::request::Parts;
 use http::{header, HeaderMap, StatusCode};
 use log::{debug, info, trace};
 use serde::Serialize;
-use crate::utils::jwt::{Role, verify};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use axum::extract::{ConnectInfo, Request};
+use axum::middleware::Next;
+use crate::{audit, config, database};
+use crate::audit::{Event, Outcome};
+use crate::database::user::UserRole;
+use crate::utils::jwt::{Role, verify, verify_oauth_access};
+use crate::utils::proxy;
+use crate::utils::proxy::Scheme;
 
 #[derive(Serialize)]
 pub struct RefreshUser {
-    pub(crate) email: String
+    pub(crate) email: String,
+    pub(crate) family: String,
+    pub(crate) generation: u64,
 }
 #[derive(Serialize, Debug)]
 pub struct AccessUser {
-    pub(crate) email: String
+    pub(crate) email: String,
+    pub(crate) family: String,
+    pub(crate) jti: String,
+    pub(crate) exp: usize,
+    pub(crate) roles: Vec<UserRole>,
+}
+
+/// Role checked by `RequireRole`
+pub trait RequiredRole {
+    const ROLE: UserRole;
+}
+
+pub struct Admin;
+
+impl RequiredRole for Admin {
+    const ROLE: UserRole = UserRole::Admin;
+}
+
+/// User authenticated by its access JWT and granted the role `R`
+#[derive(Debug)]
+pub struct RequireRole<R> {
+    pub(crate) user: AccessUser,
+    role: PhantomData<fn() -> R>,
 }
 
 #[async_trait]
@@ -30,14 +66,65 @@ impl<S> FromRequestParts<S> for RefreshUser
             .ok_or(StatusCode::BAD_REQUEST)?;
 
         // Verify JWT and retrieve email
-        let email = verify(jwt, Role::Refresh)
+        let claims = verify(jwt, Role::Refresh)
             .or(Err(StatusCode::BAD_REQUEST))?;
 
+        if is_revoked(&claims.jti, &claims.sub, claims.iat)? {
+            debug!("Refresh JWT revoked");
+            refresh_failure(parts, &claims.sub);
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+
+        // Only the latest refresh JWT of a family is valid, replaying an older one revokes the family
+        if !database::family::check(&claims.fam, claims.gen).or(Err(StatusCode::INTERNAL_SERVER_ERROR))? {
+            debug!("Refresh JWT revoked or already rotated");
+            refresh_failure(parts, &claims.sub);
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+
         trace!("Refresh JWT validated from headers");
-        Ok(Self { email })
+        Ok(Self { email: claims.sub, family: claims.fam, generation: claims.gen })
+    }
+}
+
+/// Replace the address of a trusted reverse proxy by the one of its client, for the handlers extracting
+/// `ConnectInfo` (rate limiting, audit), and record the scheme the client used
+pub async fn forwarded(mut request: Request, next: Next) -> Response {
+    if let Some(ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>().copied() {
+        let config = config::get();
+        let direct = if config.tls_enabled() { Scheme::Https } else { Scheme::Http };
+        let (ip, scheme) = proxy::resolve(peer.ip(), request.headers(), &config.server.trusted_proxies, direct);
+        if ip != peer.ip() {
+            trace!("Request forwarded by {}", peer.ip());
+            // The port of the client is unknown
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::new(ip, 0)));
+        }
+        request.extensions_mut().insert(scheme);
+    }
+    next.run(request).await
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Scheme
+    where S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get::<Scheme>().copied().unwrap_or_default())
     }
 }
 
+/// Audit a refresh JWT rejected after its signature was checked, most likely a replay
+fn refresh_failure(parts: &Parts, email: &str) {
+    let event = Event::new("token.refresh", Outcome::Failure).subject(email);
+    let event = match parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => event.client(addr, &parts.headers),
+        None => event,
+    };
+    audit::record(event);
+}
+
 #[async_trait]
 impl<S> FromRequestParts<S> for AccessUser
     where S: Send + Sync,
@@ -60,15 +147,97 @@ impl<S> FromRequestParts<S> for AccessUser
         let jwt = jwt_cookie.value();
 
         // Validate cookie
-        let email = verify(jwt, Role::Access)
+        let claims = verify(jwt, Role::Access)
             .or(Err(StatusCode::BAD_REQUEST))?;
 
+        if is_revoked(&claims.jti, &claims.sub, claims.iat)? {
+            debug!("Access JWT revoked");
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+
+        // The access JWT dies with the family of the refresh JWT that created it
+        if !database::family::is_active(&claims.fam).or(Err(StatusCode::INTERNAL_SERVER_ERROR))? {
+            debug!("Access JWT from a revoked family");
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+
         // Return validated email
         trace!("Access JWT retrieved, returning email");
-        Ok(Self { email })
+        Ok(Self { email: claims.sub, family: claims.fam, jti: claims.jti, exp: claims.exp, roles: claims.roles })
+    }
+}
+
+impl<R> Deref for RequireRole<R> {
+    type Target = AccessUser;
+
+    fn deref(&self) -> &AccessUser {
+        &self.user
     }
 }
 
+#[async_trait]
+impl<S, R> FromRequestParts<S> for RequireRole<R>
+    where S: Send + Sync,
+          R: RequiredRole,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, s: &S) -> Result<Self, Self::Rejection> {
+        let user = AccessUser::from_request_parts(parts, s).await?;
+
+        if !user.roles.contains(&R::ROLE) {
+            info!("Missing role {:?}", R::ROLE);
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        trace!("Role {:?} granted", R::ROLE);
+        Ok(Self { user, role: PhantomData })
+    }
+}
+
+/// User on whose behalf an OAuth client calls the API, authenticated by an OAuth access token
+#[derive(Serialize, Debug)]
+pub struct OAuthUser {
+    pub(crate) email: String,
+    pub(crate) client_id: String,
+    pub(crate) scopes: Vec<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for OAuthUser
+    where S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        trace!("Verify OAuth access token");
+
+        // RFC 6750 : the client is told how to authenticate
+        let unauthorized = |challenge| (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, challenge)]).into_response();
+
+        let token = get_jwt_from_headers(&parts.headers).ok_or_else(|| unauthorized("Bearer"))?;
+        let claims = verify_oauth_access(token).or(Err(unauthorized(r#"Bearer error="invalid_token""#)))?;
+
+        // Logging out everywhere also revokes the tokens given to the clients
+        if is_revoked(&claims.jti, &claims.sub, claims.iat).map_err(IntoResponse::into_response)? {
+            debug!("OAuth access token revoked");
+            return Err(unauthorized(r#"Bearer error="invalid_token""#));
+        }
+
+        Ok(Self {
+            email: claims.sub,
+            client_id: claims.client_id,
+            scopes: claims.scope.split(' ').map(String::from).collect(),
+        })
+    }
+}
+
+/// Check the JWT against the denylist and the "logout everywhere" timestamp of its user
+fn is_revoked(jti: &str, email: &str, issued_at: usize) -> Result<bool, StatusCode> {
+    database::revocation::is_revoked(jti, email, issued_at as u64)
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
 fn get_jwt_from_headers(headers: &HeaderMap) -> Option<&str> {
     // Retrieve JWT from headers and parse its value to UTF-8 String
     let value = headers
@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+use webauthn_rs::prelude::{PublicKeyCredential, RegisterPublicKeyCredential};
+use crate::database::user::UserRole;
 
 #[derive(Deserialize)]
 pub struct NewUser {
@@ -23,5 +25,188 @@ pub struct ChangePassword {
     pub old_password: String,
     pub password: String,
     pub password2: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangeEmail {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct TotpRequired {
+    pub totp_required: bool,
+}
+
+#[derive(Deserialize)]
+pub struct LoginTotp {
+    pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct Csrf {
     pub csrf: String,
 }
+
+/// Device holding a refresh token family of the user
+#[derive(Serialize)]
+pub struct DeviceSession {
+    pub id: String,
+    pub label: String,
+    pub user_agent: String,
+    pub ip: String,
+    pub created: u64,
+    pub last_used: u64,
+    pub current: bool, // Session of the device making the request
+}
+
+#[derive(Serialize)]
+pub struct TotpSetup {
+    pub secret: String,
+    pub url: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpConfirm {
+    pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    pub codes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPassword {
+    pub password: String,
+    pub password2: String,
+}
+
+#[derive(Deserialize)]
+pub struct EmailPreview {
+    pub locale: Option<String>,
+    pub part: Option<String>, // "html" (default), "txt" or "subject"
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyRegister {
+    pub name: String,
+    pub credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyRemove {
+    pub id: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyLoginStart {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyLogin {
+    pub credential: PublicKeyCredential,
+}
+
+#[derive(Deserialize)]
+pub struct NewClient {
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub confidential: bool, // Confidential clients get a secret, public ones only rely on PKCE
+}
+
+#[derive(Serialize)]
+pub struct ClientCredentials {
+    pub client_id: String,
+    pub client_secret: Option<String>, // Only shown once
+}
+
+/// Parameters of /authorize, also posted back by the consent page
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuthorizeRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Consent {
+    pub approve: Option<String>, // Set by the "Allow" button only
+    #[serde(flatten)]
+    pub request: AuthorizeRequest,
+}
+
+#[derive(Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub code: String,
+    pub redirect_uri: String,
+    pub code_verifier: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: usize,
+    pub id_token: String,
+    pub scope: String,
+}
+
+#[derive(Deserialize)]
+pub struct OidcLogin {
+    pub next: Option<String>,
+}
+
+/// Parameters sent back by the external identity provider
+#[derive(Deserialize)]
+pub struct OidcCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Search and pagination of the admin user listing, pages start at 1
+#[derive(Deserialize)]
+pub struct UserQuery {
+    pub search: Option<String>,
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+}
+
+/// User as shown to the admins, without its secrets
+#[derive(Serialize)]
+pub struct UserSummary {
+    pub email: String,
+    pub verified: bool,
+    pub disabled: bool,
+    pub role: UserRole,
+    pub totp: bool,
+    pub passkeys: usize,
+}
+
+#[derive(Serialize)]
+pub struct UserPage {
+    pub total: usize,
+    pub page: usize,
+    pub per_page: usize,
+    pub users: Vec<UserSummary>,
+}
+
+#[derive(Deserialize)]
+pub struct RoleChange {
+    pub role: UserRole,
+}
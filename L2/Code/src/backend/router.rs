@@ -1,13 +1,16 @@
 use axum::error_handling::HandleErrorLayer;
-use axum::middleware::from_extractor;
+use axum::middleware::{from_extractor, from_fn};
 use axum::{BoxError, Router};
-use axum::routing::{get, post};
-use http::StatusCode;
+use axum::routing::{delete, get, post, put};
+use http::header::STRICT_TRANSPORT_SECURITY;
+use http::{HeaderValue, StatusCode};
 use log::{debug, info, trace, warn};
 use tower_http::cors;
 use tower_http::cors::{AllowMethods, CorsLayer};
-use tower_sessions::{SessionManagerLayer, MemoryStore};
-use crate::backend::middlewares::{AccessUser, RefreshUser};
+use tower_http::set_header::SetResponseHeaderLayer;
+use crate::config;
+use crate::backend::{csrf, session};
+use crate::backend::middlewares::{forwarded, AccessUser, Admin, RefreshUser, RequireRole};
 
 pub fn get_router() -> Router {
     trace!("Init main router");
@@ -24,21 +27,33 @@ pub fn get_router() -> Router {
     };
 
     // Session manager layer
-    let store = MemoryStore::default();
-    let manager = SessionManagerLayer::new(store).with_http_only(true);
     let service = tower::ServiceBuilder::new()
         .layer(HandleErrorLayer::new(|e: BoxError| async move {
             warn!("Session manager catched an error");
             debug!("Error catched : {e}");
             StatusCode::BAD_REQUEST
         }))
-        .layer(manager);
+        .layer(session::layer());
 
-    router
+    let router = router
         .merge(unauth())
         .merge(access())
         .merge(refresh())
+        .merge(oauth())
+        .merge(admin())
+        .route_layer(from_fn(crate::metrics::track)) // Latency of the matched routes
         .layer(service)
+        .layer(from_fn(session::signed_cookies)) // The session manager only sees verified cookies
+        .layer(from_fn(forwarded)); // Client behind the reverse proxies, before anything reads its address
+
+    // Browsers remember to only use HTTPS for this host
+    if let Some(hsts) = config::get().hsts_header() {
+        info!("Send HSTS header");
+        let hsts = HeaderValue::from_str(&hsts).expect("Invalid HSTS header");
+        router.layer(SetResponseHeaderLayer::if_not_present(STRICT_TRANSPORT_SECURITY, hsts))
+    } else {
+        router
+    }
 }
 
 fn unauth() -> Router {
@@ -48,12 +63,23 @@ fn unauth() -> Router {
 
     Router::new()
         .route("/", get(home))
-        .route("/email/:email", get(email))
         .route("/register", post(register))
         .route("/verify/:token", get(verify))
+        .route("/change-email/:token", get(confirm_email_change))
         .route("/login", get(login_page))
         .route("/login", post(login))
-        .route("/logout", get(logout))
+        .route("/login/totp", post(login_totp))
+        .route("/login/passkey/start", post(login_passkey_start))
+        .route("/login/passkey/finish", post(login_passkey_finish))
+        .route("/login/oidc/:provider", get(login_oidc))
+        .route("/login/oidc/:provider/callback", get(login_oidc_callback))
+        .route("/logout", post(logout))
+        .route("/forgot-password", post(forgot_password))
+        .route("/reset-password/:token", get(reset_password_page))
+        .route("/reset-password/:token", post(reset_password))
+        .route("/.well-known/jwks.json", get(jwks))
+        .route("/metrics", get(metrics))
+        .route_layer(from_fn(csrf::same_origin)) // No anti-CSRF token without a session, the Origin is checked instead
 }
 
 fn access() -> Router {
@@ -63,6 +89,17 @@ fn access() -> Router {
 
     Router::new()
         .route("/change-password", post(change_password))
+        .route("/change-email", post(change_email))
+        .route("/logout-all", post(logout_all))
+        .route("/totp/enroll", post(totp_enroll))
+        .route("/totp/confirm", post(totp_confirm))
+        .route("/passkey/register/start", post(passkey_register_start))
+        .route("/passkey/register/finish", post(passkey_register_finish))
+        .route("/passkey/remove", post(passkey_remove))
+        .route("/csrf", get(csrf_token))
+        .route("/sessions", get(sessions))
+        .route("/sessions/:id", delete(revoke_session))
+        .route_layer(from_fn(csrf::protect)) // Anti-CSRF token required, the access JWT is a cookie
         .layer(from_extractor::<AccessUser>()) // Middleware checking for access JWT
 }
 
@@ -75,3 +112,43 @@ fn refresh() -> Router {
         .route("/get-access", get(get_access))
         .layer(from_extractor::<RefreshUser>()) // Middleware checking for refresh JWT
 }
+
+fn oauth() -> Router {
+    use crate::backend::handlers_oauth::*;
+
+    trace!("Init router for OAuth 2.0 / OpenID Connect");
+
+    // Each handler authenticates on its own : the user by its access JWT, the client by its credentials
+    // or its OAuth access token
+    Router::new()
+        .route("/authorize", get(authorize))
+        .route("/authorize", post(consent).layer(from_fn(csrf::protect)))
+        .route("/token", post(token))
+        .route("/userinfo", get(userinfo).post(userinfo))
+        .route("/.well-known/openid-configuration", get(discovery))
+}
+
+fn admin() -> Router {
+    use crate::backend::handlers_admin::*;
+    use crate::backend::handlers_oauth::register_client;
+
+    trace!("Init router for admins");
+
+    Router::new()
+        .route("/email/:email", get(email))
+        .route("/email-preview/:name", get(email_preview))
+        .route("/clients", post(register_client))
+        .route("/admin/users", get(list_users))
+        .route("/admin/users/:email", get(get_user).delete(delete_user))
+        .route("/admin/users/:email/disable", post(disable_user))
+        .route("/admin/users/:email/enable", post(enable_user))
+        .route("/admin/users/:email/unverify", post(unverify_user))
+        .route("/admin/users/:email/verification", post(resend_verification))
+        .route("/admin/users/:email/reset-password", post(force_password_reset))
+        .route("/admin/users/:email/revoke-sessions", post(revoke_sessions))
+        .route("/admin/users/:email/role", put(set_role))
+        .route("/admin/audit", get(audit_log))
+        .route("/admin/audit/verify", get(audit_verify))
+        .route_layer(from_fn(csrf::protect)) // Anti-CSRF token required, the access JWT is a cookie
+        .layer(from_extractor::<RequireRole<Admin>>()) // Middleware checking for the admin role
+}
@@ -0,0 +1,158 @@
+use std::fmt;
+use axum::async_trait;
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use http::header::{COOKIE, SET_COOKIE};
+use http::HeaderValue;
+use log::{info, trace, warn};
+use once_cell::sync::OnceCell;
+use rand::RngCore;
+use ring::hmac;
+use tower_sessions::cookie::{Cookie, SameSite};
+use tower_sessions::session::Id;
+use tower_sessions::session_store::ExpiredDeletion;
+use tower_sessions::{Expiry, Session, SessionManagerLayer, SessionStore};
+use crate::{config, database};
+use crate::utils::crypto::{sign_cookie, verify_cookie};
+
+/// Sessions persisted in the database, they survive a restart
+#[derive(Clone, Debug, Default)]
+pub struct DbStore;
+
+#[derive(Debug)]
+pub struct StoreError(String);
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Session store error : {}", self.0)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+fn store_error(e: impl fmt::Display) -> StoreError {
+    StoreError(e.to_string())
+}
+
+#[async_trait]
+impl SessionStore for DbStore {
+    type Error = StoreError;
+
+    async fn save(&self, session: &Session) -> Result<(), Self::Error> {
+        trace!("Save session");
+        let data = serde_json::to_string(session).map_err(store_error)?;
+        database::session::save(&session.id().to_string(), data).map_err(store_error)
+    }
+
+    async fn load(&self, session_id: &Id) -> Result<Option<Session>, Self::Error> {
+        database::session::get(&session_id.to_string())
+            .map_err(store_error)?
+            .map(|data| serde_json::from_str(&data).map_err(store_error))
+            .transpose()
+    }
+
+    async fn delete(&self, session_id: &Id) -> Result<(), Self::Error> {
+        trace!("Delete session");
+        database::session::delete(&session_id.to_string()).map_err(store_error)
+    }
+}
+
+#[async_trait]
+impl ExpiredDeletion for DbStore {
+    async fn delete_expired(&self) -> Result<(), Self::Error> {
+        database::session::delete_expired().map_err(store_error)
+    }
+}
+
+/// Session manager configured from the `session` settings
+pub fn layer() -> SessionManagerLayer<DbStore> {
+    let config = config::get();
+    let session = &config.session;
+
+    let mut layer = SessionManagerLayer::new(DbStore)
+        .with_name(&session.cookie_name)
+        .with_http_only(true)
+        .with_same_site(match session.same_site {
+            config::SameSite::Strict => SameSite::Strict,
+            config::SameSite::Lax => SameSite::Lax,
+            config::SameSite::None => SameSite::None,
+        })
+        .with_secure(config.secure_session_cookie())
+        .with_path(session.path.clone())
+        // Upper bound for the browser, the lifetime and the idle timeout are enforced by the store
+        .with_expiry(Expiry::OnInactivity(time::Duration::seconds(session.lifetime as i64)));
+    if let Some(domain) = &session.domain {
+        layer = layer.with_domain(domain.clone());
+    }
+    layer
+}
+
+static KEY: OnceCell<hmac::Key> = OnceCell::new();
+
+/// Set the key signing the session cookies, must be called once at startup
+/// Without a secret, a random key is used : the sessions are lost on restart and can't be shared between replicas
+pub fn init(secret: Option<String>) -> anyhow::Result<()> {
+    let key = match secret {
+        Some(secret) => hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+        None => {
+            warn!("session.secret not set, the sessions won't survive a restart");
+            let mut secret = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut secret);
+            hmac::Key::new(hmac::HMAC_SHA256, &secret)
+        }
+    };
+    info!("Session cookies signed");
+    KEY.set(key).or(Err(anyhow::anyhow!("Session key already set")))
+}
+
+/// Middleware signing the session cookie, around the session manager
+/// The signature is checked and removed from the request cookie, a cookie with an invalid signature is dropped,
+/// and the cookie set in the response is signed
+pub async fn signed_cookies(mut request: Request, next: Next) -> Response {
+    let (Some(key), name) = (KEY.get(), &config::get().session.cookie_name) else {
+        return next.run(request).await;
+    };
+
+    let cookies: Vec<String> = request.headers().get_all(COOKIE).iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .filter_map(|c| match c.split_once('=') {
+            Some((n, signed)) if n == name => match verify_cookie(key, name, signed) {
+                Some(value) => Some(format!("{n}={value}")),
+                None => {
+                    warn!("Session cookie with an invalid signature");
+                    None
+                },
+            },
+            _ => Some(c.to_string()),
+        })
+        .collect();
+    request.headers_mut().remove(COOKIE);
+    if let Ok(header) = HeaderValue::from_str(&cookies.join("; ")) {
+        if !cookies.is_empty() {
+            request.headers_mut().insert(COOKIE, header);
+        }
+    }
+
+    let mut response = next.run(request).await;
+
+    let set_cookies: Vec<HeaderValue> = response.headers().get_all(SET_COOKIE).iter()
+        .map(|h| match h.to_str().ok().and_then(|c| Cookie::parse(c).ok()) {
+            // An empty value removes the cookie, nothing to sign
+            Some(mut cookie) if cookie.name() == name && !cookie.value().is_empty() => {
+                cookie.set_value(sign_cookie(key, name, cookie.value()));
+                HeaderValue::from_str(&cookie.to_string()).unwrap_or(h.clone())
+            },
+            _ => h.clone(),
+        })
+        .collect();
+    response.headers_mut().remove(SET_COOKIE);
+    for header in set_cookies {
+        response.headers_mut().append(SET_COOKIE, header);
+    }
+
+    response
+}
@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+use anyhow::Result;
+use axum::extract::Host;
+use axum::response::Redirect;
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use http::uri::Authority;
+use http::Uri;
+use log::{error, info, trace, warn};
+use crate::config;
+use crate::config::Config;
+
+/// Load the certificate chain and the private key (PEM)
+pub async fn load(cert: &PathBuf, key: &PathBuf) -> Result<RustlsConfig> {
+    info!("Load TLS certificate {}", cert.display());
+
+    // Only ring is compiled in, install it explicitly instead of relying on the crate features
+    rustls::crypto::ring::default_provider().install_default().ok();
+    Ok(RustlsConfig::from_pem_file(cert, key).await?)
+}
+
+/// Reload the certificate each time the process receives SIGHUP, without dropping the connections
+/// The current certificate is kept if the new one is invalid
+pub async fn reload_on_signal(tls: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signal = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Can't listen to SIGHUP, certificate reload disabled : {e}");
+            return;
+        }
+    };
+
+    while signal.recv().await.is_some() {
+        match tls.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => info!("TLS certificate reloaded"),
+            Err(e) => error!("Failed to reload TLS certificate, keeping the current one : {e}"),
+        }
+    }
+}
+
+/// Router of the plain HTTP listener, every request is redirected to HTTPS
+pub fn redirect_router() -> Router {
+    Router::new().fallback(redirect)
+}
+
+async fn redirect(Host(host): Host, uri: Uri) -> Redirect {
+    trace!("Redirect to HTTPS");
+    Redirect::permanent(&redirect_url(config::get(), &host, &uri))
+}
+
+/// Same path and query, on the HTTPS origin
+fn redirect_url(config: &Config, host: &str, uri: &Uri) -> String {
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    format!("{}{path}", https_base(config, host))
+}
+
+/// Origin to redirect to : the public URL if configured, else the host the client asked for on the HTTPS port
+fn https_base(config: &Config, host: &str) -> String {
+    if config.server.public_url.is_some() {
+        return config.public_url();
+    }
+
+    let host = host.parse::<Authority>().map_or("localhost".to_string(), |a| a.host().to_string());
+    match config.server.port {
+        443 => format!("https://{host}"),
+        port => format!("https://{host}:{port}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+    public_url,
+    port,
+    host,
+    uri,
+    expected,
+    case(None, 8443, "example.com:8080", "/login?next=%2F", "https://example.com:8443/login?next=%2F"),
+    case(None, 443, "example.com:80", "/", "https://example.com/"),
+    case(None, 443, "example.com", "/reset-password/abc", "https://example.com/reset-password/abc"),
+    case(None, 8443, "[::1]:8080", "/", "https://[::1]:8443/"),
+    case(None, 8443, "in valid", "/a", "https://localhost:8443/a"),
+    case(Some("https://auth.example.com/"), 8443, "internal:8080", "/login?a=1&b=2", "https://auth.example.com/login?a=1&b=2"),
+    )]
+    pub fn redirect_url_test(public_url: Option<&str>, port: u16, host: &str, uri: &str, expected: &str) {
+        let mut config = Config::default();
+        config.server.port = port;
+        config.server.public_url = public_url.map(|url| url.parse().unwrap());
+        assert_eq!(redirect_url(&config, host, &uri.parse().unwrap()), expected);
+    }
+}
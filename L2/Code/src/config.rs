@@ -0,0 +1,779 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use ipnet::IpNet;
+use log::info;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use url::Url;
+use crate::consts::{ACCESS_KEYS_DIR, AUDIT_LOG_PATH, EMAIL_FROM};
+use crate::database::store::{Backend, Startup};
+use crate::utils::input_val::is_email_valid;
+
+/// Settings changing between environments
+/// Read from the TOML file, then overridden by the env vars, then by the command line flags
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: Server,
+    pub tls: Tls,
+    pub tokens: Tokens,
+    pub session: Session,
+    pub password: Password,
+    pub argon2: Argon2,
+    pub storage: Storage,
+    pub smtp: Smtp,
+    pub oidc: Oidc,
+    pub webauthn: Webauthn,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    pub bind_address: IpAddr,
+    pub port: u16,
+    /// URL the users reach the server at, used in the links sent by email, the OAuth issuer and the cookies
+    /// Defaults to the local port, for development
+    pub public_url: Option<Url>,
+    /// Reverse proxies whose X-Forwarded-For and X-Forwarded-Proto headers are trusted
+    pub trusted_proxies: Vec<IpNet>,
+    /// Accounts granted the admin role at startup, the role is kept when an email is removed from the list
+    pub admin_emails: Vec<String>,
+    /// Bearer token the monitoring stack sends to read /metrics, they are public if unset
+    pub metrics_token: Option<String>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8090,
+            public_url: None,
+            trusted_proxies: vec![],
+            admin_emails: vec![],
+            metrics_token: None,
+        }
+    }
+}
+
+/// HTTPS served directly, enabled when the certificate and the key are set
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    /// PEM certificate chain and private key, reloaded on SIGHUP
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Plain HTTP port redirecting to HTTPS, none to only listen on HTTPS
+    pub redirect_port: Option<u16>,
+    /// Strict-Transport-Security max-age in seconds, 0 to not send it
+    pub hsts_max_age: u64,
+}
+
+impl Default for Tls {
+    fn default() -> Self {
+        Self { cert: None, key: None, redirect_port: None, hsts_max_age: 365 * 24 * 3600 } // 1 year
+    }
+}
+
+/// Lifetimes, in seconds
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tokens {
+    pub access_duration: u64,
+    pub refresh_duration: u64,
+    pub verify_link_duration: u64,
+    pub reset_link_duration: u64,
+    /// Directory of the keys signing the access JWTs, rotated on SIGUSR1
+    pub access_keys: PathBuf,
+}
+
+impl Default for Tokens {
+    fn default() -> Self {
+        Self {
+            access_duration: 60 * 15, // 15 minutes
+            refresh_duration: 3600 * 24 * 7, // 7 days
+            verify_link_duration: 30 * 60, // 30 minutes
+            reset_link_duration: 15 * 60, // 15 minutes
+            access_keys: PathBuf::from(ACCESS_KEYS_DIR),
+        }
+    }
+}
+
+/// Browser sessions, holding the anti-CSRF tokens and the logins in progress
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Session {
+    /// Seconds after the creation of a session it is dropped, even if it is used
+    pub lifetime: u64,
+    /// Seconds without any request after which a session is dropped
+    pub idle_timeout: u64,
+    pub cookie_name: String,
+    pub same_site: SameSite,
+    pub domain: Option<String>,
+    pub path: String,
+    /// Secure flag of the cookie, derived from the public URL and TLS if not set
+    pub secure: Option<bool>,
+    /// Key signing the cookies, a random one is drawn if unset and the sessions don't survive a restart
+    pub secret: Option<String>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            lifetime: 24 * 3600, // 1 day
+            idle_timeout: 2 * 3600, // 2 hours
+            cookie_name: "id".to_string(),
+            same_site: SameSite::Strict,
+            domain: None,
+            path: "/".to_string(),
+            secure: None,
+            secret: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Password {
+    pub min_length: usize,
+    pub max_length: usize,
+    /// ZXCVBN offers 5 levels of password strength (0 to 4), 3 is qualified as safely unguessable
+    pub min_strength: u8,
+}
+
+impl Default for Password {
+    fn default() -> Self {
+        Self { min_length: 8, max_length: 64, min_strength: 3 }
+    }
+}
+
+/// Cost of the Argon2id hashes, the existing hashes keep the cost they were created with
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Argon2 {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for Argon2 {
+    fn default() -> Self {
+        Self { memory_kib: 64 * 1024, iterations: 3, parallelism: 1 }
+    }
+}
+
+/// Where everything the server keeps is written
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Storage {
+    /// "bincode[:<dir>]" or "sqlite[:<path>]"
+    pub backend: String,
+    /// What to do with a damaged database : "strict" refuses to start, "repair" drops an incomplete last record,
+    /// "lenient" also moves the corrupted files aside
+    pub startup: String,
+    pub audit_log: PathBuf,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self { backend: "bincode".to_string(), startup: "strict".to_string(), audit_log: PathBuf::from(AUDIT_LOG_PATH) }
+    }
+}
+
+/// SMTP relay delivering the outbox, the emails are only kept in the outbox if no host is set
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Smtp {
+    pub host: Option<String>,
+    /// Defaults to the port of the security
+    pub port: Option<u16>,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+}
+
+impl Default for Smtp {
+    fn default() -> Self {
+        Self { host: None, port: None, security: SmtpSecurity::StartTls, username: None, password: None, from: EMAIL_FROM.to_string() }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    #[value(name = "starttls")]
+    StartTls,
+    Tls,
+    None,
+}
+
+/// OpenID Connect : the provider this server is to its clients, and the external ones the users can sign in with
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Oidc {
+    /// Issuer of the ID tokens, the public URL by default
+    pub issuer: Option<Url>,
+    pub providers: Vec<OidcProvider>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OidcProvider {
+    /// Used in the URLs
+    pub id: String,
+    /// Displayed on the login page, the id by default
+    pub name: Option<String>,
+    pub issuer: Url,
+    pub client_id: String,
+    /// None for a public client, PKCE is used in any case
+    pub client_secret: Option<String>,
+}
+
+/// Relying party of the passkeys, the browser only allows it for pages served from its origin
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Webauthn {
+    /// The public URL by default
+    pub origin: Option<Url>,
+    /// A domain, IP addresses are refused by browsers, the host of the origin by default
+    pub rp_id: Option<String>,
+}
+
+/// Command line flags, each one can also be set by the env var named after it
+#[derive(Parser, Debug, Default)]
+#[command(about = "KingAuth authentication server")]
+pub struct Args {
+    /// TOML configuration file, optional if it is the default one
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+    #[arg(long, env = "BIND_ADDRESS")]
+    pub bind_address: Option<IpAddr>,
+    #[arg(long, env = "HTTP_PORT")]
+    pub port: Option<u16>,
+    #[arg(long, env = "PUBLIC_URL")]
+    pub public_url: Option<Url>,
+    /// Comma separated addresses or networks (CIDR)
+    #[arg(long, env = "TRUSTED_PROXIES", value_delimiter = ',')]
+    pub trusted_proxies: Option<Vec<IpNet>>,
+    #[arg(long, env = "TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long, env = "TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+    #[arg(long, env = "TLS_REDIRECT_PORT")]
+    pub tls_redirect_port: Option<u16>,
+    #[arg(long, env = "HSTS_MAX_AGE")]
+    pub hsts_max_age: Option<u64>,
+    #[arg(long, env = "ACCESS_TOKEN_DURATION")]
+    pub access_token_duration: Option<u64>,
+    #[arg(long, env = "REFRESH_TOKEN_DURATION")]
+    pub refresh_token_duration: Option<u64>,
+    #[arg(long, env = "VERIFY_LINK_DURATION")]
+    pub verify_link_duration: Option<u64>,
+    #[arg(long, env = "RESET_LINK_DURATION")]
+    pub reset_link_duration: Option<u64>,
+    #[arg(long, env = "SESSION_LIFETIME")]
+    pub session_lifetime: Option<u64>,
+    #[arg(long, env = "SESSION_IDLE_TIMEOUT")]
+    pub session_idle_timeout: Option<u64>,
+    #[arg(long, env = "SESSION_COOKIE_NAME")]
+    pub session_cookie_name: Option<String>,
+    #[arg(long, env = "SESSION_SAME_SITE")]
+    pub session_same_site: Option<SameSite>,
+    #[arg(long, env = "SESSION_COOKIE_DOMAIN")]
+    pub session_cookie_domain: Option<String>,
+    #[arg(long, env = "SESSION_COOKIE_PATH")]
+    pub session_cookie_path: Option<String>,
+    #[arg(long, env = "SESSION_COOKIE_SECURE")]
+    pub session_cookie_secure: Option<bool>,
+    #[arg(long, env = "PASSWORD_MIN_LENGTH")]
+    pub password_min_length: Option<usize>,
+    #[arg(long, env = "PASSWORD_MAX_LENGTH")]
+    pub password_max_length: Option<usize>,
+    #[arg(long, env = "PASSWORD_MIN_STRENGTH")]
+    pub password_min_strength: Option<u8>,
+    #[arg(long, env = "ARGON2_MEMORY_KIB")]
+    pub argon2_memory_kib: Option<u32>,
+    #[arg(long, env = "ARGON2_ITERATIONS")]
+    pub argon2_iterations: Option<u32>,
+    #[arg(long, env = "ARGON2_PARALLELISM")]
+    pub argon2_parallelism: Option<u32>,
+    /// Comma separated emails
+    #[arg(long, env = "ADMIN_EMAILS", value_delimiter = ',')]
+    pub admin_emails: Option<Vec<String>>,
+    #[arg(long, env = "METRICS_TOKEN")]
+    pub metrics_token: Option<String>,
+    #[arg(long, env = "JWT_ACCESS_KEYS")]
+    pub jwt_access_keys: Option<PathBuf>,
+    #[arg(long, env = "SESSION_SECRET")]
+    pub session_secret: Option<String>,
+    #[arg(long, env = "DB_BACKEND")]
+    pub db_backend: Option<String>,
+    #[arg(long, env = "DB_STARTUP")]
+    pub db_startup: Option<String>,
+    #[arg(long, env = "AUDIT_LOG")]
+    pub audit_log: Option<PathBuf>,
+    #[arg(long, env = "SMTP_HOST")]
+    pub smtp_host: Option<String>,
+    #[arg(long, env = "SMTP_PORT")]
+    pub smtp_port: Option<u16>,
+    #[arg(long, env = "SMTP_SECURITY")]
+    pub smtp_security: Option<SmtpSecurity>,
+    #[arg(long, env = "SMTP_USERNAME")]
+    pub smtp_username: Option<String>,
+    #[arg(long, env = "SMTP_PASSWORD")]
+    pub smtp_password: Option<String>,
+    #[arg(long, env = "SMTP_FROM")]
+    pub smtp_from: Option<String>,
+    #[arg(long, env = "OIDC_ISSUER")]
+    pub oidc_issuer: Option<Url>,
+    /// Comma separated ids of the external providers, replacing the ones of the file
+    /// Each one is configured by OIDC_<ID>_ISSUER, OIDC_<ID>_CLIENT_ID, and optionally OIDC_<ID>_CLIENT_SECRET
+    /// and OIDC_<ID>_NAME
+    #[arg(long, env = "OIDC_PROVIDERS", value_delimiter = ',')]
+    pub oidc_providers: Option<Vec<String>>,
+    #[arg(long, env = "WEBAUTHN_ORIGIN")]
+    pub webauthn_origin: Option<Url>,
+    #[arg(long, env = "WEBAUTHN_RP_ID")]
+    pub webauthn_rp_id: Option<String>,
+}
+
+// Configuration file read when none is given
+const DEFAULT_CONFIG_FILE: &str = "king_auth.toml";
+
+impl Config {
+    /// Read the configuration file, apply the overrides then check the result
+    pub fn load(args: Args) -> Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None if PathBuf::from(DEFAULT_CONFIG_FILE).exists() => Self::from_file(&PathBuf::from(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+        config.apply(args)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &PathBuf) -> Result<Self> {
+        info!("Read configuration from {}", path.display());
+        let content = std::fs::read_to_string(path).with_context(|| format!("Can't read {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Invalid configuration in {}", path.display()))
+    }
+
+    fn apply(&mut self, args: Args) -> Result<()> {
+        fn set<T>(value: Option<T>, field: &mut T) {
+            if let Some(v) = value {
+                *field = v;
+            }
+        }
+
+        set(args.bind_address, &mut self.server.bind_address);
+        set(args.port, &mut self.server.port);
+        set(args.public_url.map(Some), &mut self.server.public_url);
+        set(args.trusted_proxies, &mut self.server.trusted_proxies);
+        set(args.tls_cert.map(Some), &mut self.tls.cert);
+        set(args.tls_key.map(Some), &mut self.tls.key);
+        set(args.tls_redirect_port.map(Some), &mut self.tls.redirect_port);
+        set(args.hsts_max_age, &mut self.tls.hsts_max_age);
+        set(args.access_token_duration, &mut self.tokens.access_duration);
+        set(args.refresh_token_duration, &mut self.tokens.refresh_duration);
+        set(args.verify_link_duration, &mut self.tokens.verify_link_duration);
+        set(args.reset_link_duration, &mut self.tokens.reset_link_duration);
+        set(args.session_lifetime, &mut self.session.lifetime);
+        set(args.session_idle_timeout, &mut self.session.idle_timeout);
+        set(args.session_cookie_name, &mut self.session.cookie_name);
+        set(args.session_same_site, &mut self.session.same_site);
+        set(args.session_cookie_domain.map(Some), &mut self.session.domain);
+        set(args.session_cookie_path, &mut self.session.path);
+        set(args.session_cookie_secure.map(Some), &mut self.session.secure);
+        set(args.password_min_length, &mut self.password.min_length);
+        set(args.password_max_length, &mut self.password.max_length);
+        set(args.password_min_strength, &mut self.password.min_strength);
+        set(args.argon2_memory_kib, &mut self.argon2.memory_kib);
+        set(args.argon2_iterations, &mut self.argon2.iterations);
+        set(args.argon2_parallelism, &mut self.argon2.parallelism);
+        set(args.admin_emails, &mut self.server.admin_emails);
+        set(args.metrics_token.map(Some), &mut self.server.metrics_token);
+        set(args.jwt_access_keys, &mut self.tokens.access_keys);
+        set(args.session_secret.map(Some), &mut self.session.secret);
+        set(args.db_backend, &mut self.storage.backend);
+        set(args.db_startup, &mut self.storage.startup);
+        set(args.audit_log, &mut self.storage.audit_log);
+        set(args.smtp_host.map(Some), &mut self.smtp.host);
+        set(args.smtp_port.map(Some), &mut self.smtp.port);
+        set(args.smtp_security, &mut self.smtp.security);
+        set(args.smtp_username.map(Some), &mut self.smtp.username);
+        set(args.smtp_password.map(Some), &mut self.smtp.password);
+        set(args.smtp_from, &mut self.smtp.from);
+        set(args.oidc_issuer.map(Some), &mut self.oidc.issuer);
+        set(args.webauthn_origin.map(Some), &mut self.webauthn.origin);
+        set(args.webauthn_rp_id.map(Some), &mut self.webauthn.rp_id);
+
+        if let Some(ids) = args.oidc_providers {
+            self.oidc.providers = ids.iter()
+                .map(|id| id.trim())
+                .filter(|id| !id.is_empty())
+                .map(|id| OidcProvider::from_env(id, |name| std::env::var(name).ok()))
+                .collect::<Result<_>>()?;
+        }
+        Ok(())
+    }
+
+    /// Refuse the settings that would make the server unusable or insecure
+    pub fn validate(&self) -> Result<()> {
+        if self.server.port == 0 {
+            bail!("server.port can't be 0");
+        }
+        if let Some(url) = &self.server.public_url {
+            if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+                bail!("server.public_url must be an http(s) URL");
+            }
+            if url.query().is_some() || url.fragment().is_some() {
+                bail!("server.public_url can't have a query or a fragment");
+            }
+        }
+
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            bail!("tls.cert and tls.key must be set together");
+        }
+        match self.tls.redirect_port {
+            Some(_) if !self.tls_enabled() => bail!("tls.redirect_port needs TLS"),
+            Some(port) if port == 0 || port == self.server.port => bail!("tls.redirect_port must be another port"),
+            _ => {},
+        }
+
+        let tokens = &self.tokens;
+        if [tokens.access_duration, tokens.refresh_duration, tokens.verify_link_duration, tokens.reset_link_duration].contains(&0) {
+            bail!("Token durations can't be 0");
+        }
+        if tokens.access_duration > tokens.refresh_duration {
+            bail!("tokens.access_duration can't be longer than tokens.refresh_duration");
+        }
+
+        let session = &self.session;
+        if session.lifetime == 0 || session.idle_timeout == 0 || session.idle_timeout > session.lifetime {
+            bail!("session.idle_timeout must be between 1 and session.lifetime");
+        }
+        if session.cookie_name.is_empty() || !session.cookie_name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b)) {
+            bail!("session.cookie_name must be made of letters, digits, '-', '_' and '.'");
+        }
+        if !session.path.starts_with('/') {
+            bail!("session.path must start with /");
+        }
+        // Browsers refuse SameSite=None without Secure
+        if session.same_site == SameSite::None && !self.secure_session_cookie() {
+            bail!("session.same_site = \"none\" needs a secure cookie");
+        }
+
+        let password = &self.password;
+        if password.min_length == 0 || password.min_length > password.max_length {
+            bail!("password.min_length must be between 1 and password.max_length");
+        }
+        if password.min_strength > 4 {
+            bail!("password.min_strength must be between 0 and 4");
+        }
+
+        self.argon2.params().context("Invalid argon2 parameters")?;
+
+        if let Some(email) = self.server.admin_emails.iter().find(|e| !is_email_valid(e)) {
+            bail!("server.admin_emails has an invalid email {email}");
+        }
+        if self.server.metrics_token.as_ref().is_some_and(String::is_empty) {
+            bail!("server.metrics_token can't be empty");
+        }
+        // Short secrets can be guessed from a signed cookie
+        if self.session.secret.as_ref().is_some_and(|s| s.len() < 32) {
+            bail!("session.secret must be at least 32 characters");
+        }
+
+        self.storage.backend().context("Invalid storage.backend")?;
+        self.storage.startup().context("Invalid storage.startup")?;
+
+        let smtp = &self.smtp;
+        if smtp.host.as_ref().is_some_and(String::is_empty) || smtp.port == Some(0) {
+            bail!("smtp.host and smtp.port can't be empty");
+        }
+        if smtp.username.is_some() != smtp.password.is_some() {
+            bail!("smtp.username and smtp.password must be set together");
+        }
+        if smtp.from.parse::<lettre::message::Mailbox>().is_err() {
+            bail!("smtp.from must be an address, optionally with a name : Name <address>");
+        }
+
+        let oidc = &self.oidc;
+        if oidc.issuer.as_ref().is_some_and(|url| !is_http_url(url)) {
+            bail!("oidc.issuer must be an http(s) URL");
+        }
+        for (i, provider) in oidc.providers.iter().enumerate() {
+            let id = &provider.id;
+            if id.is_empty() || !id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+                bail!("Invalid OIDC provider id {id}");
+            }
+            if oidc.providers[..i].iter().any(|p| p.id.eq_ignore_ascii_case(id)) {
+                bail!("OIDC provider {id} configured twice");
+            }
+            if !is_http_url(&provider.issuer) || provider.client_id.is_empty() {
+                bail!("OIDC provider {id} needs an http(s) issuer and a client id");
+            }
+        }
+
+        let origin = self.webauthn_origin();
+        if !is_http_url(&origin) {
+            bail!("webauthn.origin must be an http(s) URL");
+        }
+        // The browser only accepts a relying party the origin is part of
+        let host = origin.host_str().unwrap_or_default();
+        if let Some(rp_id) = &self.webauthn.rp_id {
+            if rp_id.is_empty() || (host != rp_id && !host.ends_with(&format!(".{rp_id}"))) {
+                bail!("webauthn.rp_id must be the host of webauthn.origin or one of its parent domains");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn listen_address(&self) -> SocketAddr {
+        SocketAddr::new(self.server.bind_address, self.server.port)
+    }
+
+    /// Public URL without trailing slash, paths are appended to it
+    pub fn public_url(&self) -> String {
+        match &self.server.public_url {
+            Some(url) => url.as_str().trim_end_matches('/').to_string(),
+            None if self.tls_enabled() => format!("https://localhost:{}", self.server.port),
+            None => format!("http://localhost:{}", self.server.port),
+        }
+    }
+
+    pub fn secure_session_cookie(&self) -> bool {
+        self.session.secure.unwrap_or(self.secure_cookies())
+    }
+
+    pub fn tls_enabled(&self) -> bool {
+        self.tls.cert.is_some() && self.tls.key.is_some()
+    }
+
+    /// Cookies are only sent over HTTPS when the users reach the server with it
+    pub fn secure_cookies(&self) -> bool {
+        self.tls_enabled() || self.server.public_url.as_ref().is_some_and(|url| url.scheme() == "https")
+    }
+
+    /// Strict-Transport-Security value, only sent when this server serves HTTPS itself
+    pub fn hsts_header(&self) -> Option<String> {
+        (self.tls_enabled() && self.tls.hsts_max_age > 0).then(|| format!("max-age={}", self.tls.hsts_max_age))
+    }
+
+    /// Issuer of the ID tokens, without trailing slash
+    pub fn oidc_issuer(&self) -> String {
+        match &self.oidc.issuer {
+            Some(url) => url.as_str().trim_end_matches('/').to_string(),
+            None => self.public_url(),
+        }
+    }
+
+    pub fn webauthn_origin(&self) -> Url {
+        self.webauthn.origin.clone()
+            .unwrap_or_else(|| Url::parse(&self.public_url()).expect("Public URL should be valid"))
+    }
+}
+
+fn is_http_url(url: &Url) -> bool {
+    matches!(url.scheme(), "http" | "https") && url.host().is_some()
+}
+
+impl Storage {
+    pub fn backend(&self) -> Result<Backend> {
+        self.backend.parse()
+    }
+
+    pub fn startup(&self) -> Result<Startup> {
+        self.startup.parse()
+    }
+}
+
+impl OidcProvider {
+    /// Read a provider from the OIDC_<ID>_* env vars
+    fn from_env(id: &str, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let var = |name: &str| var(&format!("OIDC_{}_{name}", id.to_ascii_uppercase()));
+
+        Ok(Self {
+            id: id.to_string(),
+            name: var("NAME"),
+            issuer: var("ISSUER").ok_or(anyhow!("Missing issuer of the OIDC provider {id}"))?
+                .parse()
+                .with_context(|| format!("Invalid issuer of the OIDC provider {id}"))?,
+            client_id: var("CLIENT_ID").ok_or(anyhow!("Missing client id of the OIDC provider {id}"))?,
+            client_secret: var("CLIENT_SECRET"),
+        })
+    }
+}
+
+impl Argon2 {
+    pub fn params(&self) -> Result<argon2::Params> {
+        argon2::Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32)).map_err(|e| anyhow!("{e}"))
+    }
+}
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// Set the configuration, must be called once at startup before anything reads it
+pub fn init(config: Config) -> Result<()> {
+    CONFIG.set(config).or(Err(anyhow!("Configuration already set")))
+}
+
+/// Configuration of the server, the default one if it hasn't been set (unit tests)
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    pub fn file_test() {
+        let config: Config = toml::from_str(r#"
+            [server]
+            port = 8443
+            public_url = "https://auth.example.com/"
+
+            [tokens]
+            access_duration = 300
+
+            [smtp]
+            host = "smtp.example.com"
+            security = "tls"
+
+            [[oidc.providers]]
+            id = "corp"
+            issuer = "https://idp.example.com"
+            client_id = "king"
+        "#).unwrap();
+
+        assert_eq!(config.server.port, 8443);
+        assert_eq!(config.public_url(), "https://auth.example.com");
+        assert_eq!(config.tokens.access_duration, 300);
+        assert_eq!(config.tokens.refresh_duration, Tokens::default().refresh_duration);
+        assert_eq!(config.smtp.security, SmtpSecurity::Tls);
+        assert_eq!(config.oidc.providers[0].client_id, "king");
+        assert_eq!(config.webauthn_origin().as_str(), "https://auth.example.com/");
+        assert!(toml::from_str::<Config>("[server]\nprot = 1").is_err());
+    }
+
+    #[rstest]
+    pub fn example_file_test() {
+        let config: Config = toml::from_str(include_str!("../king_auth.example.toml")).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[rstest]
+    pub fn override_test() {
+        let mut config = Config::default();
+        config.apply(Args::try_parse_from([
+            "king_auth", "--port", "9000", "--password-min-length", "12", "--trusted-proxies", "10.0.0.0/8,::1/128",
+            "--admin-emails", "a@test.com,b@test.com", "--db-backend", "sqlite", "--smtp-host", "smtp.test.com",
+            "--smtp-security", "starttls", "--oidc-issuer", "https://auth.test.com/",
+        ]).unwrap()).unwrap();
+
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.password.min_length, 12);
+        assert_eq!(config.server.trusted_proxies.len(), 2);
+        assert_eq!(config.public_url(), "http://localhost:9000");
+        assert_eq!(config.server.admin_emails, ["a@test.com", "b@test.com"]);
+        assert_eq!(config.storage.backend().unwrap(), Backend::Sqlite("king_auth.db".into()));
+        assert_eq!(config.smtp.host.as_deref(), Some("smtp.test.com"));
+        assert_eq!(config.oidc_issuer(), "https://auth.test.com");
+    }
+
+    #[rstest]
+    pub fn oidc_provider_env_test() {
+        let env = |name: &str| match name {
+            "OIDC_CORP_ISSUER" => Some("https://idp.corp.com".to_string()),
+            "OIDC_CORP_CLIENT_ID" => Some("king".to_string()),
+            _ => None,
+        };
+        let provider = OidcProvider::from_env("corp", env).unwrap();
+        assert_eq!(provider.issuer.as_str(), "https://idp.corp.com/");
+        assert_eq!(provider.client_id, "king");
+        assert_eq!((provider.name, provider.client_secret), (None, None));
+        assert!(OidcProvider::from_env("other", env).is_err());
+    }
+
+    #[rstest(
+    file,
+    secure_cookies,
+    secure_session_cookie,
+    hsts,
+    case("", false, false, None),
+    case("[server]\npublic_url = \"http://auth.example.com\"", false, false, None),
+    case("[server]\npublic_url = \"https://auth.example.com\"", true, true, None),
+    case("[server]\npublic_url = \"https://auth.example.com\"\n[session]\nsecure = false", true, false, None),
+    case("[session]\nsecure = true", false, true, None),
+    case("[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"", true, true, Some("max-age=31536000")),
+    case("[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"\nhsts_max_age = 60", true, true, Some("max-age=60")),
+    case("[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"\nhsts_max_age = 0", true, true, None),
+    case("[tls]\ncert = \"cert.pem\"", false, false, None),
+    )]
+    pub fn https_test(file: &str, secure_cookies: bool, secure_session_cookie: bool, hsts: Option<&str>) {
+        let config: Config = toml::from_str(file).unwrap();
+        assert_eq!(config.secure_cookies(), secure_cookies);
+        assert_eq!(config.secure_session_cookie(), secure_session_cookie);
+        assert_eq!(config.hsts_header().as_deref(), hsts);
+    }
+
+    #[rstest(
+    file,
+    valid,
+    case("", true),
+    case("[server]\nport = 0", false),
+    case("[server]\npublic_url = \"ftp://example.com\"", false),
+    case("[server]\npublic_url = \"https://example.com/auth?a=1\"", false),
+    case("[tokens]\naccess_duration = 0", false),
+    case("[tokens]\naccess_duration = 3600\nrefresh_duration = 60", false),
+    case("[password]\nmin_length = 80", false),
+    case("[password]\nmin_strength = 5", false),
+    case("[argon2]\nmemory_kib = 1", false),
+    case("[session]\nidle_timeout = 0", false),
+    case("[session]\nlifetime = 60", false),
+    case("[session]\ncookie_name = \"a b\"", false),
+    case("[session]\nsame_site = \"none\"", false),
+    case("[session]\nsame_site = \"none\"\nsecure = true", true),
+    case("[tls]\ncert = \"cert.pem\"", false),
+    case("[tls]\nredirect_port = 8080", false),
+    case("[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"\nredirect_port = 8090", false),
+    case("[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"\nredirect_port = 8080", true),
+    case("[argon2]\nmemory_kib = 19456\niterations = 2", true),
+    case("[server]\nadmin_emails = [\"admin\"]", false),
+    case("[server]\nadmin_emails = [\"admin@test.com\"]", true),
+    case("[server]\nmetrics_token = \"\"", false),
+    case("[session]\nsecret = \"short\"", false),
+    case("[storage]\nbackend = \"postgres\"", false),
+    case("[storage]\nbackend = \"sqlite:/tmp/king_auth.db\"\nstartup = \"repair\"", true),
+    case("[storage]\nstartup = \"sometimes\"", false),
+    case("[smtp]\nhost = \"smtp.test.com\"\nusername = \"king\"", false),
+    case("[smtp]\nhost = \"smtp.test.com\"\nusername = \"king\"\npassword = \"secret\"", true),
+    case("[smtp]\nfrom = \"not an address\"", false),
+    case("[oidc]\nissuer = \"ftp://auth.test.com\"", false),
+    case("[[oidc.providers]]\nid = \"a-b\"\nissuer = \"https://idp.test.com\"\nclient_id = \"king\"", false),
+    case("[[oidc.providers]]\nid = \"corp\"\nissuer = \"https://idp.test.com\"\nclient_id = \"\"", false),
+    case("[[oidc.providers]]\nid = \"a\"\nissuer = \"https://a.test.com\"\nclient_id = \"king\"\n\
+          [[oidc.providers]]\nid = \"A\"\nissuer = \"https://b.test.com\"\nclient_id = \"king\"", false),
+    case("[webauthn]\nrp_id = \"example.com\"", false),
+    case("[webauthn]\norigin = \"https://auth.example.com\"\nrp_id = \"example.com\"", true),
+    case("[webauthn]\norigin = \"https://auth.example.com\"\nrp_id = \"ample.com\"", false),
+    )]
+    pub fn validate_test(file: &str, valid: bool) {
+        let config: Config = toml::from_str(file).unwrap();
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+}
@@ -1,23 +1,118 @@
-pub const HTTP_PORT: u16 = 8090;
+// Default directory of the Ed25519 keys signing the access tokens (PKCS#8 PEM), overridden by JWT_ACCESS_KEYS
+pub const ACCESS_KEYS_DIR: &str = "jwt_keys";
 
-// Duration for the access token
-pub const ACCESS_TOKEN_DURATION : usize = 60 * 15; // 15 minutes
+// Number of keys kept in the keyring : the current key and the previous ones still verifying tokens
+pub const KEYRING_SIZE: usize = 3;
 
-// Duration for the refresh token
-pub const REFRESH_TOKEN_DURATION: usize = 3600 * 24 * 7; // 7 day
+// Number of records appended to a bincode journal before it is compacted into a new snapshot
+pub const JOURNAL_COMPACTION_THRESHOLD: usize = 1000;
 
-// Duration for the verify link sent to user by email
-pub const VERIFY_LINK_DURATION: usize = 30 * 60; // 10 minutes
+// Default sender of the emails, overridden by SMTP_FROM
+pub const EMAIL_FROM: &str = "KingAuth <noreply@localhost>";
+
+// Locales having email templates, the first one is the default
+pub const EMAIL_LOCALES: [&str; 2] = ["en", "fr"];
+
+// Interval between two checks of the outbox, new emails wake the worker up immediately
+pub const OUTBOX_POLL_INTERVAL: u64 = 30; // 30 seconds
+
+// Number of delivery attempts before an email is marked as failed
+pub const EMAIL_MAX_ATTEMPTS: u32 = 8;
+
+// Delay before the first retry, doubled after each failed attempt up to the maximum
+pub const EMAIL_RETRY_BASE_DELAY: u64 = 30; // 30 seconds
+pub const EMAIL_RETRY_MAX_DELAY: u64 = 3600; // 1 hour
 
 // Regex for email validation
 pub const MAIL_REGEX: &str = r#"(?:[a-z0-9!#$%&'*+/=?^_`{|}~-]+(?:\.[a-z0-9!#$%&'*+/=?^_`{|}~-]+)*|"(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21\x23-\x5b\x5d-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])*")@(?:(?:[a-z0-9](?:[a-z0-9-]*[a-z0-9])?\.)+[a-z0-9](?:[a-z0-9-]*[a-z0-9])?|\[(?:(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.){3}(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?|[a-z0-9-]*[a-z0-9]:(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21-\x5a\x53-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])+)\])"#;
 
-// ZXCVBN offers 5 levels of password strength. This is the level qualified as safely unguessable
-pub const ZXCVBN_THRESHOLD: u8 = 3;
+// Issuer displayed by the authenticator apps
+pub const TOTP_ISSUER: &str = "KingAuth";
+
+// Time allowed to enter the TOTP code once the password has been checked
+pub const TOTP_LOGIN_DURATION: i64 = 5 * 60; // 5 minutes
+
+// Number of wrong TOTP codes allowed before the pending login is dropped
+pub const TOTP_MAX_ATTEMPTS: u8 = 5;
+
+// Sliding window in which the failed logins of an IP or an email are counted
+pub const LOGIN_WINDOW: u64 = 15 * 60; // 15 minutes
+
+// Failed logins per email : the first ones are free, then each retry is delayed (doubling up to the max),
+// and the account is temporarily locked once the maximum is reached
+pub const LOGIN_FREE_FAILURES_EMAIL: u32 = 3;
+pub const LOGIN_MAX_FAILURES_EMAIL: u32 = 10;
+
+// Failed logins per IP, higher than per email since an IP can be shared (NAT, proxies)
+pub const LOGIN_FREE_FAILURES_IP: u32 = 10;
+pub const LOGIN_MAX_FAILURES_IP: u32 = 100;
+
+// Password reset requests per email and per IP, counted like failed logins in a separate key
+pub const RESET_FREE_REQUESTS_EMAIL: u32 = 2;
+pub const RESET_MAX_REQUESTS_EMAIL: u32 = 5;
+pub const RESET_FREE_REQUESTS_IP: u32 = 5;
+pub const RESET_MAX_REQUESTS_IP: u32 = 30;
+
+// Maximum delay between two attempts before the lockout
+pub const LOGIN_MAX_DELAY: u64 = 60; // 1 minute
+
+// Duration of the first lockout, doubled for each new lockout up to the max
+pub const LOCKOUT_DURATION: u64 = 15 * 60; // 15 minutes
+pub const MAX_LOCKOUT_DURATION: u64 = 24 * 3600; // 1 day
+
+// Number of recovery codes generated when the TOTP is confirmed
+pub const RECOVERY_CODES_COUNT: usize = 10;
+
+// Relying party name displayed by the authenticators when registering a passkey
+pub const WEBAUTHN_RP_NAME: &str = "KingAuth";
+
+// Maximum number of passkeys per user
+pub const MAX_PASSKEYS: usize = 10;
+
+// Maximum length of the name given to a passkey
+pub const MAX_PASSKEY_NAME_LENGTH: usize = 64;
+
+// Scopes a client can request, openid is mandatory
+pub const OAUTH_SCOPES: [&str; 2] = ["openid", "email"];
+
+// Time allowed to exchange an authorization code for tokens
+pub const AUTHORIZATION_CODE_DURATION: u64 = 60; // 1 minute
+
+// Duration of the access and ID tokens issued to the clients
+pub const OAUTH_TOKEN_DURATION: usize = 60 * 15; // 15 minutes
+
+// Time allowed to sign in at an external identity provider and come back
+pub const OIDC_LOGIN_DURATION: i64 = 10 * 60; // 10 minutes
+
+// Timeout of the requests sent to the external identity providers
+pub const OIDC_HTTP_TIMEOUT: u64 = 10; // 10 seconds
+
+// Interval between two sweeps of the expired sessions
+pub const SESSION_SWEEP_INTERVAL: u64 = 10 * 60; // 10 minutes
+
+// A session used again is only saved once this time has passed since its last recorded use, to limit the writes
+pub const SESSION_TOUCH_INTERVAL: u64 = 60; // 1 minute
+
+// Lifetime of an anti-CSRF token, a new one is issued by each page load, each protected request and /csrf
+pub const CSRF_TOKEN_DURATION: i64 = 10 * 60; // 10 minutes
+
+// Header carrying the anti-CSRF token, in the requests and in the responses rotating it
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+// Largest body read to find the anti-CSRF token when the header is missing, the default limit of axum
+pub const CSRF_MAX_BODY_SIZE: usize = 2 * 1024 * 1024; // 2 MiB
+
+// Users per page of the admin listing, and the maximum a request can ask for
+pub const ADMIN_PAGE_SIZE: usize = 20;
+pub const ADMIN_MAX_PAGE_SIZE: usize = 100;
 
-// Minimum length for a password
-pub const MIN_PASSWORD_LENGTH : usize = 8;
+// Default path of the audit log, overridden by AUDIT_LOG
+pub const AUDIT_LOG_PATH: &str = "audit.log";
 
-// Maximum length for a password
-pub const MAX_PASSWORD_LENGTH : usize = 64;
+// Size of the audit log before it is rotated, and number of rotated files kept
+pub const AUDIT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024; // 10 MiB
+pub const AUDIT_LOG_MAX_FILES: usize = 10;
 
+// Entries returned by a search in the audit log, and the maximum a request can ask for
+pub const AUDIT_QUERY_LIMIT: usize = 100;
+pub const AUDIT_QUERY_MAX_LIMIT: usize = 1000;
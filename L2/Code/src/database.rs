@@ -1,26 +1,118 @@
-use std::fs::File;
-use std::sync::{RwLock, RwLockWriteGuard};
-use anyhow::{anyhow, Result};
-use log::{debug, info, warn};
-use std::ops::Deref;
-use serde::{Deserialize, Serialize};
+mod file_store;
+mod journal;
+mod sqlite_store;
+pub mod store;
+
+use store::store;
 
 pub mod user {
-    use std::collections::HashMap;
-    use std::sync::{RwLock, RwLockWriteGuard};
-    use anyhow::{anyhow, Context, Result};
+    use anyhow::Result;
     use log::{info, trace, warn};
-    use once_cell::sync::Lazy;
     use serde::{Serialize, Deserialize};
+    use uuid::Uuid;
+    use webauthn_rs::prelude::Passkey;
+    use crate::consts::MAX_PASSKEYS;
+    use crate::utils::webauthn::{as_json, passkey_id};
+    use super::journal::Versioned;
+    use super::store;
 
     #[derive(Clone, Serialize, Deserialize, Debug)]
     pub struct User {
         pub hash: String,
-        pub verified: bool
+        pub verified: bool,
+        pub totp: Option<Totp>,
+        pub passkeys: Option<Passkeys>,
+        pub role: UserRole,
+        pub disabled: bool, // Disabled by an admin, can't log in
+        pub id: Uuid, // Stable identifier given to the OAuth clients, unlike the email it never changes
+    }
+
+    impl User {
+        /// Only verified accounts that aren't disabled can log in
+        pub fn can_login(&self) -> bool {
+            self.verified && !self.disabled
+        }
+    }
+
+    /// Layout of the users before the versioning of the files
+    #[derive(Deserialize)]
+    struct UserV0 {
+        hash: String,
+        verified: bool,
+    }
+
+    /// Layout of the users before their stable identifier
+    #[derive(Deserialize)]
+    struct UserV1 {
+        hash: String,
+        verified: bool,
+        totp: Option<Totp>,
+        passkeys: Option<Passkeys>,
+        role: UserRole,
+        disabled: bool,
+    }
+
+    impl Versioned for User {
+        const VERSION: u32 = 2;
+
+        // The users of the older layouts are given an identifier, saved by the upgrade of the file
+        fn decode<'de, D: serde::Deserializer<'de>>(version: u32, deserializer: D) -> Result<Self, D::Error> {
+            match version {
+                0 => {
+                    let user = UserV0::deserialize(deserializer)?;
+                    Ok(User { hash: user.hash, verified: user.verified, totp: None, passkeys: None, role: UserRole::User, disabled: false, id: Uuid::new_v4() })
+                },
+                1 => {
+                    let user = UserV1::deserialize(deserializer)?;
+                    Ok(User { hash: user.hash, verified: user.verified, totp: user.totp, passkeys: user.passkeys, role: user.role, disabled: user.disabled, id: Uuid::new_v4() })
+                },
+                _ => Self::deserialize(deserializer),
+            }
+        }
+    }
+
+    /// Role of a user, each role is granted the permissions of the roles below it
+    #[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+    #[serde(rename_all = "lowercase")]
+    pub enum UserRole {
+        #[default]
+        User,
+        Admin,
     }
 
-    type Db = HashMap<String, User>;
-    static DB: Lazy<RwLock<Db>> = Lazy::new(Default::default); // Map email to user
+    impl UserRole {
+        const ALL: [UserRole; 2] = [UserRole::User, UserRole::Admin];
+
+        /// Roles granted to a user with this role, embedded in its access JWTs
+        pub fn granted(self) -> Vec<UserRole> {
+            Self::ALL.into_iter().filter(|r| *r <= self).collect()
+        }
+    }
+
+    /// Second factor of a user
+    /// The TOTP is only checked at login once it has been confirmed with a valid code
+    #[derive(Clone, Serialize, Deserialize, Debug)]
+    pub struct Totp {
+        pub secret: String,
+        pub confirmed: bool,
+        pub last_step: u64, // Last time step used, prevents replaying a code
+        pub recovery_codes: Vec<String>, // Argon2 hashes of the unused recovery codes
+    }
+
+    /// Passkeys of a user, for the passwordless login
+    #[derive(Clone, Serialize, Deserialize, Debug)]
+    pub struct Passkeys {
+        pub user_id: Uuid, // WebAuthn user handle, random so that it doesn't reveal the email
+        pub credentials: Vec<PasskeyCredential>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize, Debug)]
+    pub struct PasskeyCredential {
+        pub name: String,
+        pub created: u64, // Unix timestamp
+        #[serde(with = "as_json")]
+        pub passkey: Passkey,
+    }
 
     pub fn create(email: &str, hash: &str) -> Result<bool> {
         info!("Creating new user");
@@ -28,47 +120,131 @@ pub mod user {
         let user = User {
             hash: hash.to_string(),
             verified: false,
+            totp: None,
+            passkeys: None,
+            role: UserRole::default(),
+            disabled: false,
+            id: Uuid::new_v4(),
         };
-        
-        let mut db  = DB.write().or(Err(anyhow!("DB poisoned")))?;
 
-        if db.contains_key(email) {
+        if !store()?.insert_user(email, &user)? {
             info!("User already exists");
             return Ok(false);
         }
 
-        db.insert(email.to_string(), user);
-
         trace!("User created");
-        save(db).ok();
         Ok(true)
     }
     pub fn get(email: &str) -> Option<User> {
         info!("Retrieve user from DB");
-        DB.read().ok()?.get(email).cloned()
+        store().ok()?.get_user(email).ok()?
     }
     pub fn exists(email: &str) -> Result<bool> {
         info!("Check if user exists in DB");
-        Ok(DB.read().or(Err(anyhow!("DB poisoned")))?.contains_key(email))
+        Ok(store()?.get_user(email)?.is_some())
     }
 
     pub fn change_password(email: &str, new_hash: &str) -> Result<bool> {
         info!("Change password of user");
-        let mut db = DB.write().or(Err(anyhow!("DB poisoned")))?;
 
-        let user = match db.get_mut(email) {
-            None => {
-                trace!("User not found");
-                return Ok(false)
-            },
-            Some(u) => u,
-        };
+        let changed = store()?.update_user(email, &mut |user| {
+            user.hash = new_hash.to_string();
+            true
+        })?;
 
-        user.hash = new_hash.to_string();
+        match changed {
+            true => trace!("Password changed"),
+            false => trace!("User not found"),
+        }
+        Ok(changed)
+    }
 
-        trace!("Password changed");
-        save(db).ok();  // Added by me to save the DB, it was missing
-        Ok(true)
+    /// Users whose email contains `search`, ordered by email, with the total number of matches
+    pub fn list(search: &str, offset: usize, limit: usize) -> Result<(usize, Vec<(String, User)>)> {
+        info!("List users from DB");
+        store()?.list_users(&search.to_ascii_lowercase(), offset, limit)
+    }
+
+    /// Delete a user with everything keyed by its email, except the revocation of its JWTs
+    /// Returns false if the user does not exist
+    pub fn delete(email: &str) -> Result<bool> {
+        info!("Delete user");
+        let deleted = store()?.delete_user(email)?;
+
+        if deleted {
+            trace!("User deleted");
+        }
+        Ok(deleted)
+    }
+
+    /// Change the email of a user, with its external identities, pending tokens, pending emails, consents,
+    /// refresh token families and failed logins
+    /// Returns false if the user does not exist or the new email is already taken
+    pub fn rename(email: &str, new_email: &str) -> Result<bool> {
+        info!("Change email of user");
+        let renamed = store()?.rename_user(email, new_email)?;
+
+        if renamed {
+            trace!("User renamed");
+        }
+        Ok(renamed)
+    }
+
+    /// Disable or enable a user
+    /// Returns false if the user does not exist or is already in this state
+    pub fn set_disabled(email: &str, disabled: bool) -> Result<bool> {
+        info!("Change disabled flag of user");
+
+        let changed = store()?.update_user(email, &mut |user| {
+            if user.disabled == disabled {
+                return false
+            }
+            user.disabled = disabled;
+            true
+        })?;
+
+        if changed {
+            trace!("Disabled flag changed");
+        }
+        Ok(changed)
+    }
+
+    /// Flag a user as unverified, it must follow a new verification link to log in again
+    /// Returns false if the user does not exist or is not verified
+    pub fn unverify(email: &str) -> Result<bool> {
+        info!("Flag user as unverified");
+
+        let changed = store()?.update_user(email, &mut |user| {
+            if !user.verified {
+                return false
+            }
+            user.verified = false;
+            true
+        })?;
+
+        if changed {
+            trace!("User flagged as unverified");
+        }
+        Ok(changed)
+    }
+
+    /// Change the role of a user
+    /// Returns false if the user does not exist or already has this role
+    pub fn set_role(email: &str, role: UserRole) -> Result<bool> {
+        info!("Change role of user");
+
+        let changed = store()?.update_user(email, &mut |user| {
+            if user.role == role {
+                return false
+            }
+            user.role = role;
+            true
+        })?;
+
+        if changed {
+            trace!("Role changed");
+        }
+        Ok(changed)
     }
 
     /// Flag a user as verified
@@ -76,63 +252,283 @@ pub mod user {
     /// Returns true if everything is fine :)
     pub fn verify(email: &str) -> Result<bool> {
         info!("Flag user as verified");
-        let mut db = DB.write().or(Err(anyhow!("DB poisoned")))?;
 
-        let user = match db.get_mut(email) {
-            None => {
-                trace!("User doesn't exist");
-                return Ok(false)
-            },
-            Some(u) => u,
-        };
-        if user.verified {
-            warn!("User already verified");
-            return Ok(false)
+        let verified = store()?.update_user(email, &mut |user| {
+            if user.verified {
+                warn!("User already verified");
+                return false
+            }
+            user.verified = true;
+            true
+        })?;
+
+        if verified {
+            trace!("User flagged as verified");
         }
+        Ok(verified)
+    }
 
-        user.verified = true;
+    /// Verify an account whose email has been proven by an external identity provider
+    /// The password of an unverified account may have been chosen by someone else, it is replaced
+    /// Returns false if the user does not exist or if it is already verified
+    pub fn verify_external(email: &str, hash: &str) -> Result<bool> {
+        info!("Flag user as verified by an external identity");
 
-        trace!("User flagged as verified");
-        save(db).ok();
-        Ok(true)
+        let verified = store()?.update_user(email, &mut |user| {
+            if user.verified {
+                return false
+            }
+            user.hash = hash.to_string();
+            user.verified = true;
+            true
+        })?;
+
+        if verified {
+            trace!("User flagged as verified");
+        }
+        Ok(verified)
+    }
+
+    /// Returns the email of the user an external identity is linked to
+    pub fn find_identity(issuer: &str, subject: &str) -> Result<Option<String>> {
+        info!("Retrieve external identity from DB");
+        store()?.get_identity(issuer, subject)
+    }
+
+    /// Link an external identity to a user, it then logs in as this user even if its email changes
+    /// Returns false if the identity is already linked
+    pub fn link_identity(email: &str, issuer: &str, subject: &str) -> Result<bool> {
+        info!("Link external identity to user");
+        let linked = store()?.link_identity(issuer, subject, email)?;
+
+        if linked {
+            trace!("External identity linked");
+        }
+        Ok(linked)
+    }
+
+    /// Start a TOTP enrolment, replacing any unconfirmed secret
+    /// Returns false if the user does not exist or if a TOTP is already confirmed
+    pub fn enroll_totp(email: &str, secret: &str) -> Result<bool> {
+        info!("Enroll TOTP for user");
+
+        let enrolled = store()?.update_user(email, &mut |user| {
+            if user.totp.as_ref().is_some_and(|t| t.confirmed) {
+                warn!("TOTP already confirmed");
+                return false
+            }
+            user.totp = Some(Totp {
+                secret: secret.to_string(),
+                confirmed: false,
+                last_step: 0,
+                recovery_codes: vec![],
+            });
+            true
+        })?;
+
+        if enrolled {
+            trace!("TOTP enrolment started");
+        }
+        Ok(enrolled)
+    }
+
+    /// Flag the TOTP of a user as confirmed and store the hashes of its recovery codes
+    /// Returns false if the user does not exist or has no pending TOTP
+    pub fn confirm_totp(email: &str, step: u64, recovery_codes: Vec<String>) -> Result<bool> {
+        info!("Confirm TOTP of user");
+
+        let confirmed = store()?.update_user(email, &mut |user| {
+            match user.totp.as_mut() {
+                Some(totp) if !totp.confirmed => {
+                    totp.confirmed = true;
+                    totp.last_step = step;
+                    totp.recovery_codes = recovery_codes.clone();
+                    true
+                },
+                _ => false,
+            }
+        })?;
+
+        match confirmed {
+            true => trace!("TOTP confirmed"),
+            false => trace!("No pending TOTP"),
+        }
+        Ok(confirmed)
     }
-    
-    pub fn verified(email: &str) -> Result<bool> {
-        info!("Check if user is verified");
-        Ok(get(email).context("User not found")?.verified)
+
+    /// Remember the last time step used by a user
+    /// Returns false if the step isn't newer than the saved one, meaning the code was replayed
+    pub fn use_totp_step(email: &str, step: u64) -> Result<bool> {
+        info!("Use TOTP step of user");
+
+        let used = store()?.update_user(email, &mut |user| {
+            match user.totp.as_mut() {
+                Some(totp) if totp.last_step < step => {
+                    totp.last_step = step;
+                    true
+                },
+                _ => false,
+            }
+        })?;
+
+        match used {
+            true => trace!("TOTP step saved"),
+            false => warn!("TOTP code replayed"),
+        }
+        Ok(used)
+    }
+
+    /// Remove a recovery code once used
+    /// Returns false if the code was already removed
+    pub fn use_recovery_code(email: &str, hash: &str) -> Result<bool> {
+        info!("Use recovery code of user");
+
+        let used = store()?.update_user(email, &mut |user| {
+            match user.totp.as_mut() {
+                Some(totp) => {
+                    let len = totp.recovery_codes.len();
+                    totp.recovery_codes.retain(|c| c != hash);
+                    totp.recovery_codes.len() != len
+                },
+                None => false,
+            }
+        })?;
+
+        match used {
+            true => trace!("Recovery code removed"),
+            false => warn!("Recovery code already used"),
+        }
+        Ok(used)
     }
 
-    pub fn load() -> Result<()> {
-        super::load(&DB, "users.bincode")
+    /// Returns the WebAuthn user handle of a user, created on the first passkey registration
+    pub fn passkey_user_id(email: &str) -> Result<Option<Uuid>> {
+        store()?.update_user(email, &mut |user| {
+            if user.passkeys.is_some() {
+                return false
+            }
+            user.passkeys = Some(Passkeys { user_id: Uuid::new_v4(), credentials: vec![] });
+            true
+        })?;
+
+        Ok(store()?.get_user(email)?.and_then(|u| u.passkeys).map(|p| p.user_id))
     }
-    fn save(db: RwLockWriteGuard<'_, Db>) -> Result<()> {
-        super::save(db, "users.bincode")
+
+    /// Add a passkey to a user
+    /// Returns false if the user has too many passkeys or if the passkey is already registered
+    pub fn add_passkey(email: &str, name: &str, passkey: &Passkey) -> Result<bool> {
+        info!("Add passkey to user");
+
+        let id = passkey_id(passkey);
+        let added = store()?.update_user(email, &mut |user| {
+            let passkeys = match user.passkeys.as_mut() {
+                Some(p) => p,
+                None => return false,
+            };
+            if passkeys.credentials.len() >= MAX_PASSKEYS
+                || passkeys.credentials.iter().any(|c| passkey_id(&c.passkey) == id) {
+                warn!("Passkey refused");
+                return false
+            }
+            passkeys.credentials.push(PasskeyCredential {
+                name: name.to_string(),
+                created: jsonwebtoken::get_current_timestamp(),
+                passkey: passkey.clone(),
+            });
+            true
+        })?;
+
+        if added {
+            trace!("Passkey added");
+        }
+        Ok(added)
+    }
+
+    /// Save the passkeys returned by a login, their signature counters are updated
+    pub fn update_passkeys(email: &str, updated: &[Passkey]) -> Result<bool> {
+        store()?.update_user(email, &mut |user| {
+            let passkeys = match user.passkeys.as_mut() {
+                Some(p) => p,
+                None => return false,
+            };
+            for credential in passkeys.credentials.iter_mut() {
+                let id = passkey_id(&credential.passkey);
+                if let Some(passkey) = updated.iter().find(|p| passkey_id(p) == id) {
+                    credential.passkey = passkey.clone();
+                }
+            }
+            true
+        })
+    }
+
+    /// Remove a passkey by its id
+    /// Returns false if the user has no such passkey
+    pub fn remove_passkey(email: &str, id: &str) -> Result<bool> {
+        info!("Remove passkey of user");
+
+        let removed = store()?.update_user(email, &mut |user| {
+            match user.passkeys.as_mut() {
+                Some(passkeys) => {
+                    let len = passkeys.credentials.len();
+                    passkeys.credentials.retain(|c| passkey_id(&c.passkey) != id);
+                    passkeys.credentials.len() != len
+                },
+                None => false,
+            }
+        })?;
+
+        match removed {
+            true => trace!("Passkey removed"),
+            false => trace!("Passkey not found"),
+        }
+        Ok(removed)
     }
 }
 
 pub mod token {
-    use std::{collections::HashMap, sync::RwLockWriteGuard};
-    use std::sync::RwLock;
     use anyhow::{anyhow, bail, Result};
     use log::{info, trace};
-    use once_cell::sync::Lazy;
     use serde::{Serialize, Deserialize};
     use crate::database::user;
-    extern crate serde_millis;
+    use super::journal::Versioned;
+    use super::store;
 
-    type Db = HashMap<String, Tokens>;
-    static DB: Lazy<RwLock<Db>> = Lazy::new(Default::default); // token to email
+    #[derive(Clone, Serialize, Deserialize)]
+    pub struct Token {
+        pub email : String,
+        pub expiration : u64, // Unix timestamp
+        pub purpose: Purpose,
+        pub new_email: Option<String>, // Address to confirm, only for ChangeEmail
+    }
 
-    #[derive(Serialize, Deserialize)]
-    struct Tokens {
-        email : String, 
-        #[serde(with = "serde_millis")]
-        expiration : std::time::Instant
+    /// Layout of the tokens before the versioning of the files, only used to verify the accounts
+    #[derive(Deserialize)]
+    struct TokenV0 {
+        email: String,
+        expiration: u64, // Unix timestamp in milliseconds
+    }
+
+    impl Versioned for Token {
+        fn decode<'de, D: serde::Deserializer<'de>>(version: u32, deserializer: D) -> Result<Self, D::Error> {
+            if version > 0 {
+                return Self::deserialize(deserializer);
+            }
+            let token = TokenV0::deserialize(deserializer)?;
+            Ok(Token { email: token.email, expiration: token.expiration / 1000, purpose: Purpose::Verify, new_email: None })
+        }
+    }
+
+    /// What a token can be used for, a token is only accepted for its own purpose
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+    pub enum Purpose {
+        Verify,
+        Reset,
+        ChangeEmail,
     }
 
     /// Add a token for a user
     /// The function checks if the user exists
-    pub fn add(email: &str, token: &str, duration: std::time::Duration) -> Result<()> {
+    pub fn add(email: &str, token: &str, duration: std::time::Duration, purpose: Purpose) -> Result<()> {
         info!("Add token for user");
         if !user::exists(email)? {
             trace!("User doesn't exist");
@@ -140,130 +536,752 @@ pub mod token {
         }
 
         // Generate token
-        let expiration = std::time::Instant::now() + duration;
-        
+        let expiration = jsonwebtoken::get_current_timestamp() + duration.as_secs();
+
         // Save token in DB
-        let mut db = DB.write().or(Err(anyhow!("DB poisoned")))?;
-        db.insert(token.to_string(), Tokens {email: email.to_string(), expiration });
+        store()?.add_token(token, &Token { email: email.to_string(), expiration, purpose, new_email: None })?;
 
         // Return token
         trace!("Token added");
-        save(db).ok();
+        Ok(())
+    }
+
+    /// Add a token confirming that a user owns the new address it asked for
+    pub fn add_change_email(email: &str, new_email: &str, token: &str, duration: std::time::Duration) -> Result<()> {
+        info!("Add email change token for user");
+        if !user::exists(email)? {
+            trace!("User doesn't exist");
+            bail!("Invalid user");
+        }
+
+        let expiration = jsonwebtoken::get_current_timestamp() + duration.as_secs();
+        store()?.add_token(token, &Token {
+            email: email.to_string(),
+            expiration,
+            purpose: Purpose::ChangeEmail,
+            new_email: Some(new_email.to_string()),
+        })?;
+
+        trace!("Token added");
         Ok(())
     }
 
     /// Returns email linked to the token, only if :
     /// - Token exists in the DB
+    /// - Token is used for its purpose (a token used for another purpose is left untouched)
     /// - Token isn't expired
     /// - DB hasn't crashed
-    pub fn consume(token: String) -> Result<String> {
+    pub fn consume(token: String, purpose: Purpose) -> Result<String> {
+        Ok(take(&token, purpose)?.email)
+    }
+
+    /// Same as `consume`, returns the current and the new email of the user
+    pub fn consume_change_email(token: String) -> Result<(String, String)> {
+        let entry = take(&token, Purpose::ChangeEmail)?;
+        let new_email = entry.new_email.ok_or(anyhow!("Token without new email"))?;
+        Ok((entry.email, new_email))
+    }
+
+    fn take(token: &str, purpose: Purpose) -> Result<Token> {
         info!("Use token");
-        let mut db = DB.write().or(Err(anyhow!("DB poisoned")))?;
-        let entry = db.remove(&token).ok_or(anyhow!("Token not found"))?;
+        let entry = store()?.take_token(token, purpose)?.ok_or(anyhow!("Token not found"))?;
 
-        if entry.expiration < std::time::Instant::now() {
+        if entry.expiration < jsonwebtoken::get_current_timestamp() {
             info!("Token expired");
             bail!("Token expired");
         }
 
-        trace!("Token consumed, email returned");
-        save(db).ok();
-        Ok(entry.email)
+        trace!("Token consumed");
+        Ok(entry)
     }
 
-    fn save(db : RwLockWriteGuard<'_, Db>) -> Result<()> {
-        super::save(db, "tokens.bincode")
+    /// Invalidate every token of a user created for a purpose, such as the other reset links once one is used
+    pub fn revoke_all(email: &str, purpose: Purpose) -> Result<()> {
+        info!("Revoke tokens of user");
+        let revoked = store()?.delete_tokens(email, purpose)?;
+
+        trace!("{revoked} tokens revoked");
+        Ok(())
     }
-    pub fn load() -> Result<()> {
-        super::load(&DB, "tokens.bincode")
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use rstest::rstest;
+        use std::time::Duration;
+        use uuid::Uuid;
+        use crate::database::store::init_test_store;
+        use crate::database::user::{User, UserRole};
+
+        fn user() -> String {
+            init_test_store();
+            let email = format!("{}@test.com", Uuid::new_v4());
+            let user = User { hash: "hash".into(), verified: true, totp: None, passkeys: None, role: UserRole::User, disabled: false, id: Uuid::new_v4() };
+            store().unwrap().insert_user(&email, &user).unwrap();
+            email
+        }
+
+        #[rstest]
+        pub fn purpose_test() {
+            let email = user();
+            add(&email, "verify-token", Duration::from_secs(60), Purpose::Verify).unwrap();
+
+            // A verification link can't reset the password, and is still valid for its own purpose
+            assert!(consume("verify-token".into(), Purpose::Reset).is_err());
+            assert_eq!(consume("verify-token".into(), Purpose::Verify).unwrap(), email);
+        }
+
+        #[rstest]
+        pub fn revoke_all_test() {
+            let email = user();
+            add(&email, "reset-1", Duration::from_secs(60), Purpose::Reset).unwrap();
+            add(&email, "reset-2", Duration::from_secs(60), Purpose::Reset).unwrap();
+            add(&email, "verify-1", Duration::from_secs(60), Purpose::Verify).unwrap();
+
+            assert_eq!(consume("reset-1".into(), Purpose::Reset).unwrap(), email);
+            revoke_all(&email, Purpose::Reset).unwrap();
+            assert!(consume("reset-2".into(), Purpose::Reset).is_err());
+            assert!(consume("verify-1".into(), Purpose::Verify).is_ok());
+        }
+    }
+}
+
+pub mod family {
+    use anyhow::Result;
+    use log::{info, trace, warn};
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+    use crate::config;
+    use crate::utils::device;
+    use super::journal::Versioned;
+    use super::store;
+
+    /// Family of refresh tokens, created at login
+    /// Each refresh rotates the token, only the latest generation of the family is valid
+    /// A family is the session of a device, shown to the user so it can be revoked
+    #[derive(Clone, Serialize, Deserialize, Debug)]
+    pub struct Family {
+        pub email: String,
+        pub generation: u64,
+        pub revoked: bool,
+        pub expiration: u64, // Unix timestamp after which the latest refresh token is expired
+        pub device: Device,
+    }
+
+    impl Versioned for Family {}
+
+    /// Device which logged in, as seen by the server
+    #[derive(Clone, Serialize, Deserialize, Debug)]
+    pub struct Device {
+        pub label: String, // Browser and OS read from the user agent
+        pub user_agent: String,
+        pub ip: String, // Updated at each refresh
+        pub created: u64,
+        pub last_used: u64,
+    }
+
+    fn now() -> u64 {
+        jsonwebtoken::get_current_timestamp()
+    }
+
+    /// Create a new family for a user and return its id
+    pub fn create(email: &str, user_agent: &str, ip: &str) -> Result<String> {
+        info!("Create refresh token family");
+
+        // Families whose last token expired can't be used anymore, forget them
+        let now = now();
+        store()?.delete_expired_families(now)?;
+
+        let id = Uuid::new_v4().to_string();
+        store()?.add_family(&id, &Family {
+            email: email.to_string(),
+            generation: 0,
+            revoked: false,
+            expiration: now + config::get().tokens.refresh_duration,
+            device: Device {
+                label: device::label(user_agent),
+                user_agent: user_agent.to_string(),
+                ip: ip.to_string(),
+                created: now,
+                last_used: now,
+            },
+        })?;
+
+        trace!("Family created");
+        Ok(id)
+    }
+
+    /// Check that a refresh token is the latest of its family
+    /// If an older generation is presented, the token has been replayed (stolen or leaked) :
+    /// the whole family is revoked
+    pub fn check(id: &str, generation: u64) -> Result<bool> {
+        info!("Check refresh token family");
+
+        let mut latest = false;
+        store()?.update_family(id, &mut |family| {
+            if family.revoked {
+                trace!("Family revoked");
+                return false;
+            }
+            if family.generation != generation {
+                warn!("Refresh token reused, revoking its family");
+                family.revoked = true;
+                return true;
+            }
+            latest = true;
+            false
+        })?;
+
+        Ok(latest)
+    }
+
+    /// Rotate the refresh token of a family, used from the given IP
+    /// Returns the new generation, or None if the given generation isn't the latest one (the family is then revoked)
+    pub fn rotate(id: &str, generation: u64, ip: &str) -> Result<Option<u64>> {
+        info!("Rotate refresh token family");
+
+        let mut rotated = None;
+        store()?.update_family(id, &mut |family| {
+            if family.revoked {
+                trace!("Family revoked");
+                return false;
+            }
+            if family.generation != generation {
+                warn!("Refresh token reused, revoking its family");
+                family.revoked = true;
+                return true;
+            }
+
+            let now = now();
+            family.generation += 1;
+            family.expiration = now + config::get().tokens.refresh_duration;
+            family.device.ip = ip.to_string();
+            family.device.last_used = now;
+            rotated = Some(family.generation);
+            true
+        })?;
+
+        trace!("Family rotated");
+        Ok(rotated)
+    }
+
+    /// Revoke every family of a user
+    pub fn revoke_all(email: &str) -> Result<()> {
+        info!("Revoke all refresh token families of user");
+        store()?.revoke_families(email)?;
+
+        trace!("Families revoked");
+        Ok(())
+    }
+
+    /// Revoke a single family, used at logout
+    pub fn revoke(id: &str) -> Result<()> {
+        info!("Revoke refresh token family");
+        store()?.update_family(id, &mut |f| !std::mem::replace(&mut f.revoked, true))?;
+
+        trace!("Family revoked");
+        Ok(())
+    }
+
+    /// Revoke a family of a user, from its list of devices
+    /// Returns false if the family doesn't exist, belongs to someone else or is already revoked
+    pub fn revoke_of(email: &str, id: &str) -> Result<bool> {
+        info!("Revoke refresh token family of user");
+
+        let revoked = store()?.update_family(id, &mut |f| {
+            f.email == email && !std::mem::replace(&mut f.revoked, true)
+        })?;
+        if !revoked {
+            trace!("Family not found");
+            return Ok(false)
+        }
+
+        trace!("Family revoked");
+        Ok(true)
+    }
+
+    /// Families of a user still usable, with their id, the most recently used first
+    pub fn list(email: &str) -> Result<Vec<(String, Family)>> {
+        let now = now();
+        let mut families: Vec<(String, Family)> = store()?.list_families(email)?.into_iter()
+            .filter(|(_, f)| !f.revoked && f.expiration > now)
+            .collect();
+        families.sort_by_key(|(_, f)| std::cmp::Reverse(f.device.last_used));
+        Ok(families)
+    }
+
+    /// Check that a family exists and isn't revoked
+    pub fn is_active(id: &str) -> Result<bool> {
+        Ok(store()?.get_family(id)?.is_some_and(|f| !f.revoked))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use rstest::rstest;
+        use crate::database::store::init_test_store;
+
+        fn family() -> String {
+            init_test_store();
+            create(&format!("{}@test.com", Uuid::new_v4()), "curl/8.5.0", "127.0.0.1").unwrap()
+        }
+
+        #[rstest]
+        pub fn rotate_test() {
+            let id = family();
+            assert_eq!(rotate(&id, 0, "127.0.0.2").unwrap(), Some(1));
+            assert_eq!(rotate(&id, 1, "127.0.0.2").unwrap(), Some(2));
+            assert!(check(&id, 2).unwrap());
+            assert_eq!(store().unwrap().get_family(&id).unwrap().unwrap().device.ip, "127.0.0.2");
+            assert!(is_active(&id).unwrap());
+        }
+
+        #[rstest]
+        pub fn reuse_test() {
+            let id = family();
+            assert_eq!(rotate(&id, 0, "127.0.0.1").unwrap(), Some(1));
+
+            // The previous token is replayed : the whole family is revoked, the latest token included
+            assert_eq!(rotate(&id, 0, "127.0.0.1").unwrap(), None);
+            assert!(!is_active(&id).unwrap());
+            assert_eq!(rotate(&id, 1, "127.0.0.1").unwrap(), None);
+            assert!(!check(&id, 1).unwrap());
+        }
+
+        #[rstest]
+        pub fn check_reuse_test() {
+            let id = family();
+            assert_eq!(rotate(&id, 0, "127.0.0.1").unwrap(), Some(1));
+            assert!(!check(&id, 0).unwrap());
+            assert!(!is_active(&id).unwrap());
+            assert!(!check(&id, 1).unwrap());
+        }
+    }
+}
+
+pub mod revocation {
+    use anyhow::Result;
+    use log::{info, trace};
+    use super::store;
+
+    /// Add a JWT to the denylist until its expiration
+    pub fn deny(jti: &str, expiration: u64) -> Result<()> {
+        info!("Add JWT to the denylist");
+
+        // Expired JWTs are refused by the signature check, no need to keep them
+        store()?.delete_expired_denied(jsonwebtoken::get_current_timestamp())?;
+        store()?.deny_jwt(jti, expiration)?;
+
+        trace!("JWT denied");
+        Ok(())
+    }
+
+    /// Revoke every JWT issued to a user before the given timestamp
+    pub fn revoke_before(email: &str, timestamp: u64) -> Result<()> {
+        info!("Revoke JWTs of user issued before a timestamp");
+        store()?.set_revoked_before(email, timestamp)?;
+
+        trace!("JWTs revoked");
+        Ok(())
+    }
+
+    /// Logout everywhere : revoke every JWT issued to a user until now
+    /// JWTs issued before now are revoked by their timestamp,
+    /// JWTs issued during the current second are caught by the revocation of their family
+    pub fn revoke_all(email: &str) -> Result<()> {
+        revoke_before(email, jsonwebtoken::get_current_timestamp())?;
+        super::family::revoke_all(email)
+    }
+
+    /// Check if a JWT has been revoked, either by its id or by its issue date
+    pub fn is_revoked(jti: &str, email: &str, issued_at: u64) -> Result<bool> {
+        Ok(store()?.is_jwt_denied(jti)?
+            || store()?.get_revoked_before(email)?.is_some_and(|ts| issued_at < ts))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use rstest::rstest;
+        use uuid::Uuid;
+        use crate::database::store::init_test_store;
+
+        fn id() -> String {
+            init_test_store();
+            Uuid::new_v4().to_string()
+        }
+
+        #[rstest]
+        pub fn deny_test() {
+            let (jti, email) = (id(), id());
+            let now = jsonwebtoken::get_current_timestamp();
+            deny(&jti, now + 60).unwrap();
+            assert!(is_revoked(&jti, &email, now).unwrap());
+            assert!(!is_revoked(&id(), &email, now).unwrap());
+        }
+
+        #[rstest]
+        pub fn revoke_before_test() {
+            let email = id();
+            revoke_before(&email, 1_700_000_000).unwrap();
+            assert!(is_revoked(&id(), &email, 1_699_999_999).unwrap());
+            // Issued during the second of the revocation, left to the revocation of its family
+            assert!(!is_revoked(&id(), &email, 1_700_000_000).unwrap());
+            assert!(!is_revoked(&id(), &id(), 1_699_999_999).unwrap());
+        }
+
+        #[rstest]
+        pub fn deny_prune_test() {
+            let (expired, jti) = (id(), id());
+            let now = jsonwebtoken::get_current_timestamp();
+            deny(&expired, now - 1).unwrap();
+            assert!(store().unwrap().is_jwt_denied(&expired).unwrap());
+
+            // The next JWT denied drops the ones already expired
+            deny(&jti, now + 60).unwrap();
+            assert!(!store().unwrap().is_jwt_denied(&expired).unwrap());
+            assert!(store().unwrap().is_jwt_denied(&jti).unwrap());
+        }
+    }
+}
+
+pub mod throttle {
+    use std::net::SocketAddr;
+    use anyhow::Result;
+    use log::{info, trace, warn};
+    use crate::utils::rate_limit::{Attempts, Policy};
+    use super::journal::Versioned;
+    use super::store;
+
+    impl Versioned for Attempts {}
+
+    fn now() -> u64 {
+        jsonwebtoken::get_current_timestamp()
+    }
+
+    /// Key counting the failed logins from an IP
+    pub fn ip_key(addr: &SocketAddr) -> String {
+        format!("ip:{}", addr.ip())
+    }
+
+    /// Key counting the failed logins of an account, moved along when the email changes
+    pub fn email_key(email: &str) -> String {
+        format!("email:{email}")
+    }
+
+    /// Key counting the password reset requests of an IP or email key, apart from its failed logins
+    pub fn reset_request_key(key: &str) -> String {
+        format!("reset:{key}")
+    }
+
+    /// Seconds to wait before the key can try to log in again, None if it can now
+    pub fn retry_after(key: &str, policy: &Policy) -> Result<Option<u64>> {
+        Ok(store()?.get_attempts(key)?.and_then(|a| a.retry_after(policy, now())))
+    }
+
+    /// Record a failed login for a key
+    /// Returns the end of the lockout if this failure locks the key
+    pub fn fail(key: &str, policy: &Policy) -> Result<Option<u64>> {
+        info!("Record failed login");
+
+        // Forget the keys with nothing left to enforce
+        let now = now();
+        store()?.delete_expired_attempts(now)?;
+
+        let mut locked_until = None;
+        store()?.update_attempts(key, &mut |attempts| locked_until = attempts.fail(policy, now))?;
+
+        if locked_until.is_some() {
+            warn!("Too many failed logins, key locked");
+        }
+        Ok(locked_until)
+    }
+
+    /// Forget the failed logins of a key, after a successful login
+    pub fn reset(key: &str) -> Result<()> {
+        if store()?.delete_attempts(key)? {
+            trace!("Failed logins forgotten");
+        }
+        Ok(())
+    }
+}
+
+pub mod client {
+    use anyhow::Result;
+    use log::{info, trace};
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+    use super::journal::Versioned;
+    use super::store;
+
+    /// Application delegating its login to this server (OAuth 2.0 / OpenID Connect)
+    #[derive(Clone, Serialize, Deserialize, Debug)]
+    pub struct Client {
+        pub name: String, // Displayed on the consent page
+        pub secret_hash: Option<String>, // Argon2 hash of the secret, None for a public client (SPA, mobile app)
+        pub redirect_uris: Vec<String>, // Compared exactly to the redirect_uri of the requests
+        pub created: u64, // Unix timestamp
+    }
+
+    impl Versioned for Client {}
+
+    /// Register a new client and return its id
+    pub fn create(client: Client) -> Result<String> {
+        info!("Register OAuth client");
+
+        let id = Uuid::new_v4().to_string();
+        store()?.add_client(&id, &client)?;
+
+        trace!("Client registered");
+        Ok(id)
+    }
+
+    pub fn get(id: &str) -> Result<Option<Client>> {
+        store()?.get_client(id)
+    }
+}
+
+pub mod authorization {
+    use anyhow::Result;
+    use log::{info, trace};
+    use serde::{Deserialize, Serialize};
+    use crate::consts::AUTHORIZATION_CODE_DURATION;
+    use super::journal::Versioned;
+    use super::store;
+
+    /// Authorization given to a client by /authorize, exchanged for tokens by /token
+    #[derive(Clone, Serialize, Deserialize, Debug)]
+    pub struct Code {
+        pub email: String,
+        pub client_id: String,
+        pub redirect_uri: String,
+        pub scopes: Vec<String>,
+        pub nonce: Option<String>,
+        pub code_challenge: String, // PKCE, S256
+        pub expiration: u64, // Unix timestamp
+    }
+
+    impl Versioned for Code {}
+
+    fn now() -> u64 {
+        jsonwebtoken::get_current_timestamp()
+    }
+
+    /// Save a new authorization code, valid for a short time
+    pub fn add_code(code: &str, mut grant: Code) -> Result<()> {
+        info!("Add authorization code");
+
+        // Codes never exchanged are useless once expired
+        let now = now();
+        store()?.delete_expired_codes(now)?;
+
+        grant.expiration = now + AUTHORIZATION_CODE_DURATION;
+        store()?.add_code(code, &grant)?;
+
+        trace!("Authorization code added");
+        Ok(())
+    }
+
+    /// Remove and return an authorization code, a code can only be used once
+    /// Returns None if the code doesn't exist or is expired
+    pub fn take_code(code: &str) -> Result<Option<Code>> {
+        info!("Use authorization code");
+        Ok(store()?.take_code(code)?.filter(|c| c.expiration > now()))
+    }
+
+    /// Remember the scopes a user granted to a client, the consent page isn't shown again for them
+    pub fn consent(email: &str, client_id: &str, scopes: &[String]) -> Result<()> {
+        info!("Save consent");
+
+        let mut granted = store()?.get_consent(email, client_id)?.unwrap_or_default();
+        granted.extend(scopes.iter().filter(|s| !granted.contains(s)).cloned().collect::<Vec<_>>());
+        store()?.save_consent(email, client_id, &granted)?;
+
+        trace!("Consent saved");
+        Ok(())
+    }
+
+    /// Check that a user already granted all the scopes to a client
+    pub fn has_consent(email: &str, client_id: &str, scopes: &[String]) -> Result<bool> {
+        Ok(store()?.get_consent(email, client_id)?.is_some_and(|granted| scopes.iter().all(|s| granted.contains(s))))
     }
 }
 
 pub mod email {
     use std::collections::HashMap;
-    use std::sync::{RwLock, RwLockWriteGuard};
-    use anyhow::{anyhow, Result};
-    use once_cell::sync::Lazy;
+    use std::hash::Hash;
+    use anyhow::Result;
+    use log::{info, trace, warn};
+    use serde::de::DeserializeOwned;
     use serde::{Deserialize, Serialize};
+    use super::journal::Versioned;
+    use super::store;
 
     #[derive(Clone, Serialize, Deserialize)]
     pub struct Email {
+        pub pk: u64,
+        pub to: String,
+        pub subject: String,
+        pub body: String, // Plain text part
+        pub html: Option<String>, // HTML alternative of the body
+        pub status: Status,
+        pub attempts: u32, // Failed delivery attempts
+        pub next_attempt: u64, // Unix timestamp before which the email isn't retried
+        pub error: Option<String>, // Last delivery error
+    }
+
+    /// Delivery status of an email in the outbox
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+    pub enum Status {
+        Pending,
+        Sent,
+        Failed, // Given up after too many attempts
+    }
+
+    /// Layout of the emails before the versioning of the files, they were only kept to be read
+    #[derive(Deserialize)]
+    struct EmailV0 {
         pk: u64,
         to: String,
         subject: String,
         body: String,
     }
-    #[derive(Default, Serialize, Deserialize)]
-    struct Db {
-        next_pk: u64,
-        emails: HashMap<u64, Email>,
+
+    impl Versioned for Email {
+        /// The whole table was saved with the next pk, which is now the max pk + 1
+        fn decode_snapshot<K>(version: u32, data: &[u8]) -> anyhow::Result<HashMap<K, Self>>
+            where K: Eq + Hash + DeserializeOwned
+        {
+            if version > 0 {
+                return super::journal::decode_snapshot(version, data);
+            }
+            let (_next_pk, emails): (u64, HashMap<K, EmailV0>) = bincode::deserialize(data)?;
+            Ok(emails.into_iter().map(|(k, e)| (k, Email {
+                pk: e.pk, to: e.to, subject: e.subject, body: e.body, html: None,
+                status: Status::Sent, attempts: 0, next_attempt: 0, error: None,
+            })).collect())
+        }
     }
 
-    static DB: Lazy<RwLock<Db>> = Lazy::new(Default::default);
+    pub fn add(to: &str, subject: &str, body: &str, html: Option<&str>) -> Result<()> {
+        store()?.add_email(to, subject, body, html)
+    }
+    pub fn get(to: &str) -> Result<Vec<Email>> {
+        store()?.get_emails(to)
+    }
 
-    pub fn add(to: &str, subject: &str, body: &str) -> Result<()> {
-        let mut db = DB.write().or(Err(anyhow!("DB poisoned")))?;
+    /// Returns the pending emails whose next attempt is due, oldest first
+    pub fn due(now: u64) -> Result<Vec<Email>> {
+        store()?.due_emails(now)
+    }
 
-        let pk = db.next_pk;
-        db.next_pk += 1;
-        let email = Email { pk, to: to.into(), subject: subject.into(), body: body.into() };
+    pub fn mark_sent(pk: u64) -> Result<bool> {
+        info!("Mark email as sent");
 
-        db.emails.insert(pk, email);
+        let sent = store()?.update_email(pk, &mut |email| {
+            email.status = Status::Sent;
+            email.error = None;
+            true
+        })?;
 
-        save(db)
+        if sent {
+            trace!("Email marked as sent");
+        }
+        Ok(sent)
     }
-    pub fn get(to: &str) -> Result<Vec<Email>> {
-        let db = DB.read().or(Err(anyhow!("DB poisoned")))?;
 
-        Ok(db.emails
-            .iter()
-            .filter(|e| e.1.to == to)
-            .map(|e| e.1.clone())
-            .collect())
-    }
-    pub fn remove(pk: u64) -> Result<()> {
-        let mut db = DB.write().or(Err(anyhow!("DB poisoned")))?;
-        db.emails.remove(&pk);
-        save(db)
-    }
-    fn save(db: RwLockWriteGuard<Db>) -> Result<()> {
-        super::save(db, "emails.bincode")
-    }
-    pub fn load() -> Result<()> {
-        super::load(&DB, "emails.bincode")
+    /// Record a failed delivery attempt
+    /// The email is retried at `retry_at`, or marked as failed for good if None
+    pub fn mark_failed(pk: u64, error: &str, retry_at: Option<u64>) -> Result<bool> {
+        info!("Mark email delivery as failed");
+
+        let failed = store()?.update_email(pk, &mut |email| {
+            email.attempts += 1;
+            email.error = Some(error.to_string());
+            match retry_at {
+                Some(timestamp) => email.next_attempt = timestamp,
+                None => email.status = Status::Failed,
+            }
+            true
+        })?;
+
+        if failed && retry_at.is_none() {
+            warn!("Email delivery given up");
+        }
+        Ok(failed)
     }
 }
 
-fn save<T: Serialize>(db: RwLockWriteGuard<'_, T>, path: &str) -> Result<()> {
-    let file = File::create(path)?;
+pub mod session {
+    use anyhow::Result;
+    use log::{info, trace};
+    use serde::{Deserialize, Serialize};
+    use crate::config;
+    use crate::consts::SESSION_TOUCH_INTERVAL;
+    use super::journal::Versioned;
+    use super::store;
 
-    bincode::serialize_into(file, db.deref()).or(Err(anyhow!("Failed to serialize DB")))?;
+    /// Browser session, its data is kept as JSON since bincode can't read back `serde_json::Value`
+    #[derive(Clone, Serialize, Deserialize, Debug)]
+    pub struct Session {
+        pub data: String,
+        pub created: u64, // Unix timestamp
+        pub last_seen: u64, // Unix timestamp, up to SESSION_TOUCH_INTERVAL old
+    }
 
-    Ok(())
-}
+    impl Versioned for Session {}
 
-fn load<T: for<'de> Deserialize<'de>>(db: &RwLock<T>, path: &str) -> Result<()> {
-    info!("Loading {path}");
-    // Create path to file
-    let file = File::open(path)
-        .or_else(|e| {
-            warn!("Failed to open DB file");
-            debug!("Error : {e}");
-            Err(e)
-        })?;
+    impl Session {
+        fn is_expired(&self, now: u64) -> bool {
+            let config = &config::get().session;
+            now >= self.created + config.lifetime || now >= self.last_seen + config.idle_timeout
+        }
+    }
 
-    // Read content
-    let db_content: T = bincode::deserialize_from(file)
-        .or_else(|e| {
-            warn!("Failed to deserialize email DB content");
-            debug!("Deserialization error : {e}");
-            Err(e)
-        })?;
+    fn now() -> u64 {
+        jsonwebtoken::get_current_timestamp()
+    }
 
-    // Open DB mutex and set content
-    let mut db = db.write().or(Err(anyhow!("DB poisoned")))?;
+    /// Returns the data of a session, None if it doesn't exist or is expired
+    pub fn get(id: &str) -> Result<Option<String>> {
+        let now = now();
+        let Some(mut session) = store()?.get_session(id)? else {
+            return Ok(None);
+        };
+        if session.is_expired(now) {
+            trace!("Session expired");
+            store()?.delete_session(id)?;
+            return Ok(None);
+        }
+
+        // Only saved when used for the first time in a while
+        if now >= session.last_seen + SESSION_TOUCH_INTERVAL {
+            session.last_seen = now;
+            store()?.save_session(id, &session)?;
+        }
+        Ok(Some(session.data))
+    }
+
+    /// Create or update a session, the creation date of an existing one is kept
+    pub fn save(id: &str, data: String) -> Result<()> {
+        let now = now();
+        store()?.save_session(id, &Session { data, created: now, last_seen: now })
+    }
+
+    pub fn delete(id: &str) -> Result<()> {
+        store()?.delete_session(id)
+    }
 
-    *db = db_content;
+    /// Delete the sessions past their lifetime or idle timeout
+    pub fn delete_expired() -> Result<()> {
+        let config = &config::get().session;
+        let now = now();
 
-    Ok(())
+        let deleted = store()?.delete_expired_sessions(
+            now.saturating_sub(config.lifetime),
+            now.saturating_sub(config.idle_timeout),
+        )?;
+        info!("{deleted} expired sessions deleted");
+        Ok(())
+    }
 }
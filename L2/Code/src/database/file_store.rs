@@ -0,0 +1,497 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use anyhow::{anyhow, Result};
+use log::warn;
+use crate::database::authorization::Code;
+use crate::database::client::Client;
+use crate::database::email::{Email, Status};
+use crate::database::family::Family;
+use crate::database::journal::{self, Table};
+use crate::database::session::Session;
+use crate::database::throttle::email_key;
+use crate::database::store::{Startup, Store};
+use crate::database::token::{Purpose, Token};
+use crate::database::user::User;
+use crate::utils::rate_limit::Attempts;
+
+/// Store keeping everything in memory, each map is persisted to its own bincode snapshot and journal
+pub struct FileStore {
+    dir: PathBuf,
+    users: RwLock<Table<String, User>>, // Map email to user
+    identities: RwLock<Table<(String, String), String>>, // Map (issuer, subject) to email
+    tokens: RwLock<Table<String, Token>>, // Map token to its email
+    emails: RwLock<Table<u64, Email>>,
+    sessions: RwLock<Table<String, Session>>, // Map session id to session
+    families: RwLock<Table<String, Family>>, // Map family id to family
+    denylist: RwLock<Table<String, u64>>, // Map JWT id to its expiration, kept until the JWT is expired anyway
+    revoked_before: RwLock<Table<String, u64>>, // Map email to timestamp, JWTs issued before are revoked
+    throttle: RwLock<Table<String, Attempts>>, // Map IP or email to its failed logins
+    clients: RwLock<Table<String, Client>>, // Map client id to client
+    codes: RwLock<Table<String, Code>>, // Map authorization code to its grant
+    consents: RwLock<Table<(String, String), Vec<String>>>, // Map (email, client id) to the scopes granted
+}
+
+const USERS: &str = "users.bincode";
+const IDENTITIES: &str = "identities.bincode";
+const TOKENS: &str = "tokens.bincode";
+const EMAILS: &str = "emails.bincode";
+const SESSIONS: &str = "sessions.bincode";
+const FAMILIES: &str = "families.bincode";
+const DENYLIST: &str = "denylist.bincode";
+const REVOKED_BEFORE: &str = "revoked_before.bincode";
+const THROTTLE: &str = "throttle.bincode";
+const CLIENTS: &str = "clients.bincode";
+const CODES: &str = "authorization_codes.bincode";
+const CONSENTS: &str = "consents.bincode";
+const RENAME: &str = "rename.pending"; // Email change written before moving anything, replayed if interrupted
+
+impl FileStore {
+    /// Open the store, reloading the files found in the directory
+    /// Unless lenient, a corrupted file is an error instead of an empty map
+    pub fn open<P: AsRef<Path>>(dir: P, startup: Startup) -> Result<Self> {
+        let dir: PathBuf = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut users = Table::new(dir.join(USERS));
+        let mut identities = Table::new(dir.join(IDENTITIES));
+        let mut tokens = Table::new(dir.join(TOKENS));
+        let mut emails = Table::new(dir.join(EMAILS));
+        let mut sessions = Table::new(dir.join(SESSIONS));
+        let mut families = Table::new(dir.join(FAMILIES));
+        let mut denylist = Table::new(dir.join(DENYLIST));
+        let mut revoked_before = Table::new(dir.join(REVOKED_BEFORE));
+        let mut throttle = Table::new(dir.join(THROTTLE));
+        let mut clients = Table::new(dir.join(CLIENTS));
+        let mut codes = Table::new(dir.join(CODES));
+        let mut consents = Table::new(dir.join(CONSENTS));
+
+        // Missing files are expected on the first start
+        users.load(startup)?;
+        identities.load(startup)?;
+        tokens.load(startup)?;
+        emails.load(startup)?;
+        sessions.load(startup)?;
+        families.load(startup)?;
+        denylist.load(startup)?;
+        revoked_before.load(startup)?;
+        throttle.load(startup)?;
+        clients.load(startup)?;
+        codes.load(startup)?;
+        consents.load(startup)?;
+
+        let store = Self {
+            dir,
+            users: RwLock::new(users),
+            identities: RwLock::new(identities),
+            tokens: RwLock::new(tokens),
+            emails: RwLock::new(emails),
+            sessions: RwLock::new(sessions),
+            families: RwLock::new(families),
+            denylist: RwLock::new(denylist),
+            revoked_before: RwLock::new(revoked_before),
+            throttle: RwLock::new(throttle),
+            clients: RwLock::new(clients),
+            codes: RwLock::new(codes),
+            consents: RwLock::new(consents),
+        };
+
+        // The user is spread over several files, a rename interrupted by a crash is finished before serving
+        let marker = store.dir.join(RENAME);
+        if marker.exists() {
+            warn!("Finish an interrupted email change");
+            let (email, new_email): (String, String) = bincode::deserialize(&fs::read(&marker)?)
+                .or(Err(anyhow!("Corrupted {RENAME}")))?;
+            store.rename(&email, &new_email, true)?;
+        }
+
+        Ok(store)
+    }
+
+    /// Move a user to a new email, table by table
+    /// Every move can be done again, so resuming after a crash finishes the job
+    fn rename(&self, email: &str, new_email: &str, resume: bool) -> Result<bool> {
+        // Every table is locked first, nobody sees the user half moved
+        let mut users = self.users.write().or(Err(anyhow!("DB poisoned")))?;
+        let mut identities = self.identities.write().or(Err(anyhow!("DB poisoned")))?;
+        let mut tokens = self.tokens.write().or(Err(anyhow!("DB poisoned")))?;
+        let mut emails = self.emails.write().or(Err(anyhow!("DB poisoned")))?;
+        let mut consents = self.consents.write().or(Err(anyhow!("DB poisoned")))?;
+        let mut families = self.families.write().or(Err(anyhow!("DB poisoned")))?;
+        let mut throttle = self.throttle.write().or(Err(anyhow!("DB poisoned")))?;
+
+        let marker = self.dir.join(RENAME);
+        if !resume {
+            if users.contains_key(new_email) || !users.contains_key(email) {
+                return Ok(false);
+            }
+            let data = bincode::serialize(&(email, new_email)).or(Err(anyhow!("Failed to serialize rename")))?;
+            journal::write_atomic(&marker, &data)?;
+        }
+
+        if let Some(user) = users.get(email).cloned() {
+            users.insert(new_email.to_string(), user)?;
+            users.remove(email)?;
+        }
+
+        let moved: Vec<(String, String)> = identities.iter()
+            .filter(|(_, e)| *e == email)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in moved {
+            identities.insert(key, new_email.to_string())?;
+        }
+
+        let moved: Vec<(String, Token)> = tokens.iter()
+            .filter(|(_, t)| t.email == email)
+            .map(|(k, t)| (k.clone(), Token { email: new_email.to_string(), ..t.clone() }))
+            .collect();
+        for (key, token) in moved {
+            tokens.insert(key, token)?;
+        }
+
+        let moved: Vec<Email> = emails.values()
+            .filter(|e| e.to == email && e.status == Status::Pending)
+            .map(|e| Email { to: new_email.to_string(), ..e.clone() })
+            .collect();
+        for email in moved {
+            emails.insert(email.pk, email)?;
+        }
+
+        let moved: Vec<(String, Vec<String>)> = consents.iter()
+            .filter(|((e, _), _)| e == email)
+            .map(|((_, client_id), scopes)| (client_id.clone(), scopes.clone()))
+            .collect();
+        for (client_id, scopes) in moved {
+            consents.insert((new_email.to_string(), client_id.clone()), scopes)?;
+            consents.remove(&(email.to_string(), client_id))?;
+        }
+
+        let moved: Vec<(String, Family)> = families.iter()
+            .filter(|(_, f)| f.email == email)
+            .map(|(id, f)| (id.clone(), Family { email: new_email.to_string(), ..f.clone() }))
+            .collect();
+        for (id, family) in moved {
+            families.insert(id, family)?;
+        }
+
+        // The failed logins follow the account, replacing whatever was counted against the unused email
+        let key = email_key(email);
+        if let Some(attempts) = throttle.get(&key).cloned() {
+            throttle.insert(email_key(new_email), attempts)?;
+            throttle.remove(&key)?;
+        }
+
+        journal::remove_durable(&marker)?;
+        Ok(true)
+    }
+}
+
+impl Store for FileStore {
+    fn get_user(&self, email: &str) -> Result<Option<User>> {
+        Ok(self.users.read().or(Err(anyhow!("DB poisoned")))?.get(email).cloned())
+    }
+
+    fn insert_user(&self, email: &str, user: &User) -> Result<bool> {
+        let mut db = self.users.write().or(Err(anyhow!("DB poisoned")))?;
+
+        if db.contains_key(email) {
+            return Ok(false);
+        }
+        db.insert(email.to_string(), user.clone())?;
+
+        Ok(true)
+    }
+
+    fn update_user(&self, email: &str, update: &mut dyn FnMut(&mut User) -> bool) -> Result<bool> {
+        let mut db = self.users.write().or(Err(anyhow!("DB poisoned")))?;
+
+        // Modify a copy, the table is only changed once the change is journaled
+        let mut user = match db.get(email) {
+            Some(user) => user.clone(),
+            None => return Ok(false),
+        };
+        if !update(&mut user) {
+            return Ok(false);
+        }
+
+        db.insert(email.to_string(), user)?;
+        Ok(true)
+    }
+
+    fn list_users(&self, search: &str, offset: usize, limit: usize) -> Result<(usize, Vec<(String, User)>)> {
+        let db = self.users.read().or(Err(anyhow!("DB poisoned")))?;
+
+        // Case insensitive for ASCII, like the LIKE of SQLite
+        let search = search.to_ascii_lowercase();
+        let mut users: Vec<(&String, &User)> = db.iter()
+            .filter(|(email, _)| email.to_ascii_lowercase().contains(&search))
+            .collect();
+        users.sort_by(|a, b| a.0.cmp(b.0));
+
+        let page = users.iter().skip(offset).take(limit).map(|(e, u)| (e.to_string(), (*u).clone())).collect();
+        Ok((users.len(), page))
+    }
+
+    fn delete_user(&self, email: &str) -> Result<bool> {
+        // Same locks as a rename, nobody sees the user half deleted
+        let mut users = self.users.write().or(Err(anyhow!("DB poisoned")))?;
+        let mut identities = self.identities.write().or(Err(anyhow!("DB poisoned")))?;
+        let mut tokens = self.tokens.write().or(Err(anyhow!("DB poisoned")))?;
+        let mut consents = self.consents.write().or(Err(anyhow!("DB poisoned")))?;
+        let mut families = self.families.write().or(Err(anyhow!("DB poisoned")))?;
+        let mut throttle = self.throttle.write().or(Err(anyhow!("DB poisoned")))?;
+
+        if users.remove(email)?.is_none() {
+            return Ok(false);
+        }
+        identities.retain(|_, e| e != email)?;
+        tokens.retain(|_, t| t.email != email)?;
+        consents.retain(|(e, _), _| e != email)?;
+        families.retain(|_, f| f.email != email)?;
+        throttle.remove(&email_key(email))?;
+
+        Ok(true)
+    }
+
+    fn rename_user(&self, email: &str, new_email: &str) -> Result<bool> {
+        self.rename(email, new_email, false)
+    }
+
+    fn get_identity(&self, issuer: &str, subject: &str) -> Result<Option<String>> {
+        let db = self.identities.read().or(Err(anyhow!("DB poisoned")))?;
+        Ok(db.get(&(issuer.to_string(), subject.to_string())).cloned())
+    }
+
+    fn link_identity(&self, issuer: &str, subject: &str, email: &str) -> Result<bool> {
+        let mut db = self.identities.write().or(Err(anyhow!("DB poisoned")))?;
+
+        let key = (issuer.to_string(), subject.to_string());
+        if db.contains_key(&key) {
+            return Ok(false);
+        }
+        db.insert(key, email.to_string())?;
+
+        Ok(true)
+    }
+
+    fn add_token(&self, token: &str, entry: &Token) -> Result<()> {
+        let mut db = self.tokens.write().or(Err(anyhow!("DB poisoned")))?;
+        db.insert(token.to_string(), entry.clone())
+    }
+
+    fn take_token(&self, token: &str, purpose: Purpose) -> Result<Option<Token>> {
+        let mut db = self.tokens.write().or(Err(anyhow!("DB poisoned")))?;
+
+        if db.get(token).is_none_or(|t| t.purpose != purpose) {
+            return Ok(None);
+        }
+        db.remove(token)
+    }
+
+    fn delete_tokens(&self, email: &str, purpose: Purpose) -> Result<usize> {
+        let mut db = self.tokens.write().or(Err(anyhow!("DB poisoned")))?;
+
+        let before = db.iter().count();
+        db.retain(|_, t| t.email != email || t.purpose != purpose)?;
+        Ok(before - db.iter().count())
+    }
+
+    fn add_email(&self, to: &str, subject: &str, body: &str, html: Option<&str>) -> Result<()> {
+        let mut db = self.emails.write().or(Err(anyhow!("DB poisoned")))?;
+
+        let pk = db.iter().map(|(pk, _)| pk + 1).max().unwrap_or(0);
+        let email = Email {
+            pk,
+            to: to.into(),
+            subject: subject.into(),
+            body: body.into(),
+            html: html.map(Into::into),
+            status: Status::Pending,
+            attempts: 0,
+            next_attempt: 0,
+            error: None,
+        };
+
+        db.insert(pk, email)
+    }
+
+    fn get_emails(&self, to: &str) -> Result<Vec<Email>> {
+        let db = self.emails.read().or(Err(anyhow!("DB poisoned")))?;
+
+        Ok(db
+            .values()
+            .filter(|e| e.to == to)
+            .cloned()
+            .collect())
+    }
+
+    fn due_emails(&self, now: u64) -> Result<Vec<Email>> {
+        let db = self.emails.read().or(Err(anyhow!("DB poisoned")))?;
+
+        let mut emails: Vec<Email> = db
+            .values()
+            .filter(|e| e.status == Status::Pending && e.next_attempt <= now)
+            .cloned()
+            .collect();
+        emails.sort_by_key(|e| e.pk);
+        Ok(emails)
+    }
+
+    fn update_email(&self, pk: u64, update: &mut dyn FnMut(&mut Email) -> bool) -> Result<bool> {
+        let mut db = self.emails.write().or(Err(anyhow!("DB poisoned")))?;
+
+        let mut email = match db.get(&pk) {
+            Some(email) => email.clone(),
+            None => return Ok(false),
+        };
+        if !update(&mut email) {
+            return Ok(false);
+        }
+
+        db.insert(pk, email)?;
+        Ok(true)
+    }
+
+    fn get_session(&self, id: &str) -> Result<Option<Session>> {
+        Ok(self.sessions.read().or(Err(anyhow!("DB poisoned")))?.get(id).cloned())
+    }
+
+    fn save_session(&self, id: &str, session: &Session) -> Result<()> {
+        let mut db = self.sessions.write().or(Err(anyhow!("DB poisoned")))?;
+
+        let created = db.get(id).map_or(session.created, |s| s.created);
+        db.insert(id.to_string(), Session { created, ..session.clone() })
+    }
+
+    fn delete_session(&self, id: &str) -> Result<()> {
+        self.sessions.write().or(Err(anyhow!("DB poisoned")))?.remove(id)?;
+        Ok(())
+    }
+
+    fn delete_expired_sessions(&self, created: u64, last_seen: u64) -> Result<usize> {
+        let mut db = self.sessions.write().or(Err(anyhow!("DB poisoned")))?;
+
+        let before = db.iter().count();
+        db.retain(|_, s| s.created > created && s.last_seen > last_seen)?;
+        Ok(before - db.iter().count())
+    }
+
+    fn add_family(&self, id: &str, family: &Family) -> Result<()> {
+        self.families.write().or(Err(anyhow!("DB poisoned")))?.insert(id.to_string(), family.clone())
+    }
+
+    fn get_family(&self, id: &str) -> Result<Option<Family>> {
+        Ok(self.families.read().or(Err(anyhow!("DB poisoned")))?.get(id).cloned())
+    }
+
+    fn update_family(&self, id: &str, update: &mut dyn FnMut(&mut Family) -> bool) -> Result<bool> {
+        let mut db = self.families.write().or(Err(anyhow!("DB poisoned")))?;
+
+        let mut family = match db.get(id) {
+            Some(family) => family.clone(),
+            None => return Ok(false),
+        };
+        if !update(&mut family) {
+            return Ok(false);
+        }
+
+        db.insert(id.to_string(), family)?;
+        Ok(true)
+    }
+
+    fn list_families(&self, email: &str) -> Result<Vec<(String, Family)>> {
+        let db = self.families.read().or(Err(anyhow!("DB poisoned")))?;
+        Ok(db.iter()
+            .filter(|(_, f)| f.email == email)
+            .map(|(id, f)| (id.clone(), f.clone()))
+            .collect())
+    }
+
+    fn revoke_families(&self, email: &str) -> Result<()> {
+        let mut db = self.families.write().or(Err(anyhow!("DB poisoned")))?;
+
+        let revoked: Vec<(String, Family)> = db.iter()
+            .filter(|(_, f)| f.email == email && !f.revoked)
+            .map(|(id, f)| (id.clone(), Family { revoked: true, ..f.clone() }))
+            .collect();
+        for (id, family) in revoked {
+            db.insert(id, family)?;
+        }
+        Ok(())
+    }
+
+    fn delete_expired_families(&self, now: u64) -> Result<()> {
+        self.families.write().or(Err(anyhow!("DB poisoned")))?.retain(|_, f| f.expiration > now)
+    }
+
+    fn deny_jwt(&self, jti: &str, expiration: u64) -> Result<()> {
+        self.denylist.write().or(Err(anyhow!("DB poisoned")))?.insert(jti.to_string(), expiration)
+    }
+
+    fn is_jwt_denied(&self, jti: &str) -> Result<bool> {
+        Ok(self.denylist.read().or(Err(anyhow!("DB poisoned")))?.contains_key(jti))
+    }
+
+    fn delete_expired_denied(&self, now: u64) -> Result<()> {
+        self.denylist.write().or(Err(anyhow!("DB poisoned")))?.retain(|_, exp| *exp > now)
+    }
+
+    fn get_revoked_before(&self, email: &str) -> Result<Option<u64>> {
+        Ok(self.revoked_before.read().or(Err(anyhow!("DB poisoned")))?.get(email).copied())
+    }
+
+    fn set_revoked_before(&self, email: &str, timestamp: u64) -> Result<()> {
+        self.revoked_before.write().or(Err(anyhow!("DB poisoned")))?.insert(email.to_string(), timestamp)
+    }
+
+    fn get_attempts(&self, key: &str) -> Result<Option<Attempts>> {
+        Ok(self.throttle.read().or(Err(anyhow!("DB poisoned")))?.get(key).cloned())
+    }
+
+    fn update_attempts(&self, key: &str, update: &mut dyn FnMut(&mut Attempts)) -> Result<()> {
+        let mut db = self.throttle.write().or(Err(anyhow!("DB poisoned")))?;
+
+        let mut attempts = db.get(key).cloned().unwrap_or_default();
+        update(&mut attempts);
+        db.insert(key.to_string(), attempts)
+    }
+
+    fn delete_attempts(&self, key: &str) -> Result<bool> {
+        Ok(self.throttle.write().or(Err(anyhow!("DB poisoned")))?.remove(key)?.is_some())
+    }
+
+    fn delete_expired_attempts(&self, now: u64) -> Result<()> {
+        self.throttle.write().or(Err(anyhow!("DB poisoned")))?.retain(|_, a| !a.is_expired(now))
+    }
+
+    fn add_client(&self, id: &str, client: &Client) -> Result<()> {
+        self.clients.write().or(Err(anyhow!("DB poisoned")))?.insert(id.to_string(), client.clone())
+    }
+
+    fn get_client(&self, id: &str) -> Result<Option<Client>> {
+        Ok(self.clients.read().or(Err(anyhow!("DB poisoned")))?.get(id).cloned())
+    }
+
+    fn add_code(&self, code: &str, grant: &Code) -> Result<()> {
+        self.codes.write().or(Err(anyhow!("DB poisoned")))?.insert(code.to_string(), grant.clone())
+    }
+
+    fn take_code(&self, code: &str) -> Result<Option<Code>> {
+        self.codes.write().or(Err(anyhow!("DB poisoned")))?.remove(code)
+    }
+
+    fn delete_expired_codes(&self, now: u64) -> Result<()> {
+        self.codes.write().or(Err(anyhow!("DB poisoned")))?.retain(|_, c| c.expiration > now)
+    }
+
+    fn get_consent(&self, email: &str, client_id: &str) -> Result<Option<Vec<String>>> {
+        let db = self.consents.read().or(Err(anyhow!("DB poisoned")))?;
+        Ok(db.get(&(email.to_string(), client_id.to_string())).cloned())
+    }
+
+    fn save_consent(&self, email: &str, client_id: &str, scopes: &[String]) -> Result<()> {
+        let mut db = self.consents.write().or(Err(anyhow!("DB poisoned")))?;
+        db.insert((email.to_string(), client_id.to_string()), scopes.to_vec())
+    }
+}
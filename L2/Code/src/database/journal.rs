@@ -0,0 +1,635 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hash;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Context, Result};
+use bincode::Options;
+use log::{debug, error, info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use crate::consts::JOURNAL_COMPACTION_THRESHOLD;
+use crate::database::store::Startup;
+use crate::metrics::DB_SAVE_DURATION;
+
+/// Mutation of a table, appended to its journal
+#[derive(Serialize, Deserialize)]
+enum Record<K, V> {
+    Put(K, V),
+    Delete(K),
+}
+
+/// Start of the snapshots and journals, followed by the version of the layout of the values (u32 LE)
+/// Files written before the versioning have no header, they are read as version 0
+const MAGIC: &[u8; 4] = b"KATB";
+const HEADER_LEN: usize = 8;
+
+/// Value stored in a table, its layout version is written in the header of the snapshot and of the journal
+/// Bump VERSION when the serialized layout changes, and decode the previous layouts in `decode`
+pub trait Versioned: Clone + Serialize + DeserializeOwned {
+    const VERSION: u32 = 1;
+
+    /// Decode a value serialized with an older version of the layout, by default the same as the current one
+    fn decode<'de, D: Deserializer<'de>>(version: u32, deserializer: D) -> Result<Self, D::Error> {
+        let _ = version;
+        Self::deserialize(deserializer)
+    }
+
+    /// Decode a snapshot serialized with the given version, for the layouts whose map itself changed
+    fn decode_snapshot<K>(version: u32, data: &[u8]) -> Result<HashMap<K, Self>>
+        where K: Eq + Hash + DeserializeOwned
+    {
+        decode_snapshot(version, data)
+    }
+}
+
+/// Decode a map whose values may have an older layout
+pub fn decode_snapshot<K, V>(version: u32, data: &[u8]) -> Result<HashMap<K, V>>
+    where K: Eq + Hash + DeserializeOwned, V: Versioned
+{
+    let mut deserializer = bincode::Deserializer::from_slice(data, bincode_options());
+    // Length of the map then its entries, as serialized by bincode
+    let len = u64::deserialize(&mut deserializer)?;
+    (0..len)
+        .map(|_| Ok((K::deserialize(&mut deserializer)?, V::decode(version, &mut deserializer)?)))
+        .collect()
+}
+
+impl Versioned for String {}
+impl Versioned for u64 {}
+impl Versioned for Vec<String> {}
+
+/// Map persisted as a bincode snapshot and an append-only journal of the mutations since the snapshot
+///
+/// Each mutation is appended (and synced) to the journal instead of rewriting the whole file.
+/// Once the journal is long enough, the snapshot is atomically replaced and the journal emptied.
+/// At startup, the snapshot is loaded then the journal is replayed on top of it.
+/// Files written with an older layout are rewritten in the current one once loaded.
+pub struct Table<K, V> {
+    path: PathBuf,
+    map: HashMap<K, V>,
+    journal: Option<File>,
+    records: usize,
+    loaded: bool,
+    torn: Option<u64>, // Length to cut the journal back to, a failed append couldn't remove its partial record
+    #[cfg(test)]
+    fail_after: Option<usize>, // Bytes written by the next append before it fails
+}
+
+impl<K, V> Table<K, V>
+    where K: Eq + Hash + Clone + Serialize + DeserializeOwned,
+          V: Versioned,
+{
+    /// Create an empty table saved at the given path, `load` must be called before any mutation
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            map: HashMap::new(),
+            journal: None,
+            records: 0,
+            loaded: false,
+            torn: None,
+            #[cfg(test)]
+            fail_after: None,
+        }
+    }
+
+    /// Name of the table in the metrics, the file name without its extension like the SQLite tables
+    fn table_name(&self) -> String {
+        self.path.file_stem().map(|n| n.to_string_lossy().to_string()).unwrap_or_default()
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        with_suffix(&self.path, "journal")
+    }
+
+    /// Load the snapshot and replay the journal
+    /// A missing file is an empty table. In strict mode, a corrupted file is an error and is left untouched.
+    /// The repair mode also cuts an incomplete record at the end of the journal, left by a stop while appending it.
+    /// In lenient mode, the files corrupted in any other way are moved aside and the table starts empty.
+    /// In every mode, files with an older layout are rewritten in the current one once loaded.
+    pub fn load(&mut self, startup: Startup) -> Result<()> {
+        info!("Loading {}", self.path.display());
+
+        let mut outdated = false;
+        match self.read(startup != Startup::Strict) {
+            Ok((map, records, version)) => {
+                self.map = map;
+                self.records = records;
+                outdated = version != V::VERSION;
+            },
+            Err(e) if startup != Startup::Lenient => {
+                error!("{} is corrupted, refusing to start", self.path.display());
+                return Err(e);
+            },
+            Err(e) => {
+                error!("{} is corrupted, starting with an empty table : {e:#}", self.path.display());
+                let suffix = format!("corrupt-{}", jsonwebtoken::get_current_timestamp());
+                for path in [self.path.clone(), self.journal_path()] {
+                    if path.exists() {
+                        fs::rename(&path, with_suffix(&path, &suffix))?;
+                    }
+                }
+                self.map = HashMap::new();
+                self.records = 0;
+            },
+        }
+
+        self.loaded = true;
+        if outdated {
+            info!("Upgrading {} to version {}", self.path.display(), V::VERSION);
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the table, the number of records in the journal and the oldest version of the layout found
+    fn read(&self, repair: bool) -> Result<(HashMap<K, V>, usize, u32)> {
+        // Read snapshot
+        let (mut map, mut oldest) = match fs::read(&self.path) {
+            Ok(content) => {
+                let (version, data) = split_header::<V>(&content)
+                    .with_context(|| format!("Invalid header in {}", self.path.display()))?;
+                let map = V::decode_snapshot(version, data)
+                    .with_context(|| format!("Failed to deserialize {}", self.path.display()))?;
+                (map, version)
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                debug!("No snapshot found");
+                (HashMap::new(), V::VERSION)
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        // Replay journal
+        let journal_path = self.journal_path();
+        let content = match fs::read(&journal_path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok((map, 0, oldest)),
+            Err(e) => return Err(e.into()),
+        };
+
+        // The header is written with the first record, the process may have stopped in the middle
+        if content.len() < HEADER_LEN && MAGIC.starts_with(&content[..content.len().min(MAGIC.len())]) {
+            if !content.is_empty() {
+                if !repair {
+                    bail!("Incomplete header in {}, start with DB_STARTUP=repair to drop it", journal_path.display());
+                }
+                warn!("Dropping incomplete header of {}", journal_path.display());
+                let file = OpenOptions::new().write(true).open(&journal_path)?;
+                file.set_len(0)?;
+                file.sync_all()?;
+            }
+            return Ok((map, 0, oldest));
+        }
+        let (version, mut reader) = split_header::<V>(&content)
+            .with_context(|| format!("Invalid header in {}", journal_path.display()))?;
+        oldest = oldest.min(version);
+
+        let mut records = 0;
+        while !reader.is_empty() {
+            let offset = content.len() - reader.len();
+            let record = match read_record(&mut reader) {
+                Ok(r) => r,
+                // Only the last record can be incomplete : the process stopped while appending it
+                // Cut it so the next records are appended after the last valid one
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof && is_torn_tail(&content[offset..]) => {
+                    if !repair {
+                        bail!("Incomplete record at the end of {}, start with DB_STARTUP=repair to drop it", journal_path.display());
+                    }
+                    warn!("Dropping incomplete record at the end of {}", journal_path.display());
+                    let file = OpenOptions::new().write(true).open(&journal_path)?;
+                    file.set_len(offset as u64)?;
+                    file.sync_all()?;
+                    break;
+                },
+                Err(e) => return Err(e).with_context(|| format!("Corrupted record in {}", journal_path.display())),
+            };
+
+            match decode_record::<K, V>(version, &record)
+                .with_context(|| format!("Invalid record in {}", journal_path.display()))? {
+                Record::Put(k, v) => { map.insert(k, v); },
+                Record::Delete(k) => { map.remove(&k); },
+            }
+            records += 1;
+        }
+
+        Ok((map, records, oldest))
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        self.map.get(key)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        self.map.contains_key(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.map.iter()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.map.values()
+    }
+
+    /// Insert or replace a value
+    pub fn insert(&mut self, key: K, value: V) -> Result<()> {
+        self.append(&Record::Put(key.clone(), value.clone()))?;
+        self.map.insert(key, value);
+        self.compact_if_needed()
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Result<Option<V>>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        let (key, value) = match self.map.remove_entry(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if let Err(e) = self.append(&Record::<K, V>::Delete(key.clone())) {
+            self.map.insert(key, value);
+            return Err(e);
+        }
+        self.compact_if_needed()?;
+        Ok(Some(value))
+    }
+
+    /// Remove every entry not matching the predicate
+    pub fn retain<F: FnMut(&K, &V) -> bool>(&mut self, mut keep: F) -> Result<()> {
+        let removed: Vec<K> = self.map.iter()
+            .filter(|(k, v)| !keep(k, v))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in removed {
+            self.remove(&key)?;
+        }
+        Ok(())
+    }
+
+    /// Append a record to the journal, and wait for it to reach the disk
+    fn append(&mut self, record: &Record<K, V>) -> Result<()> {
+        if !self.loaded {
+            bail!("{} hasn't been loaded", self.path.display());
+        }
+
+        let _timer = DB_SAVE_DURATION.with_label_values(&[&self.table_name()]).start_timer();
+
+        let mut frame = Vec::new();
+        if self.journal.is_none() {
+            let file = OpenOptions::new().create(true).append(true).open(self.journal_path())?;
+            if let Some(len) = self.torn {
+                file.set_len(len)?;
+                self.torn = None;
+            }
+            if file.metadata()?.len() == 0 {
+                frame.extend_from_slice(&header::<V>());
+            }
+            self.journal = Some(file);
+        }
+        let journal = self.journal.as_mut().ok_or(anyhow!("Journal not opened"))?;
+        let len = journal.metadata()?.len();
+
+        let data = bincode::serialize(record).or(Err(anyhow!("Failed to serialize record")))?;
+        frame.extend_from_slice(&encode_record(&data));
+        if let Err(e) = self.write(&frame) {
+            // Cut the partial record, the next ones must not be appended after it
+            // If that fails too, it is cut when the journal is reopened
+            error!("Failed to append to {}, cutting the partial record : {e}", self.journal_path().display());
+            if let Some(journal) = self.journal.take() {
+                if journal.set_len(len).is_err() {
+                    self.torn = Some(len);
+                }
+            }
+            return Err(e);
+        }
+
+        self.records += 1;
+        Ok(())
+    }
+
+    fn write(&mut self, frame: &[u8]) -> Result<()> {
+        let journal = self.journal.as_mut().ok_or(anyhow!("Journal not opened"))?;
+
+        #[cfg(test)]
+        if let Some(written) = self.fail_after.take() {
+            journal.write_all(&frame[..written])?;
+            bail!("Injected failure");
+        }
+
+        journal.write_all(frame)?;
+        journal.sync_data()?;
+        Ok(())
+    }
+
+    fn compact_if_needed(&mut self) -> Result<()> {
+        if self.records < JOURNAL_COMPACTION_THRESHOLD {
+            return Ok(());
+        }
+        self.compact()
+    }
+
+    /// Write a new snapshot then empty the journal
+    /// If the process stops in between, replaying the journal on the new snapshot gives the same table
+    pub fn compact(&mut self) -> Result<()> {
+        info!("Compacting {}", self.path.display());
+        let _timer = DB_SAVE_DURATION.with_label_values(&[&self.table_name()]).start_timer();
+
+        let mut data = header::<V>().to_vec();
+        bincode::serialize_into(&mut data, &self.map).or(Err(anyhow!("Failed to serialize DB")))?;
+        write_atomic(&self.path, &data)?;
+
+        let journal = OpenOptions::new().create(true).write(true).truncate(true).open(self.journal_path())?;
+        journal.sync_all()?;
+        self.journal = None;
+        self.records = 0;
+
+        Ok(())
+    }
+}
+
+fn header<V: Versioned>() -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    header[MAGIC.len()..].copy_from_slice(&V::VERSION.to_le_bytes());
+    header
+}
+
+/// Version of the layout and content of a file, without its header
+fn split_header<V: Versioned>(content: &[u8]) -> Result<(u32, &[u8])> {
+    if !content.starts_with(MAGIC) {
+        return Ok((0, content));
+    }
+    if content.len() < HEADER_LEN {
+        bail!("Incomplete header");
+    }
+    let version = u32::from_le_bytes(content[MAGIC.len()..HEADER_LEN].try_into()?);
+    if version > V::VERSION {
+        bail!("Layout version {version} is newer than this binary");
+    }
+    Ok((version, &content[HEADER_LEN..]))
+}
+
+/// Same options as `bincode::serialize`
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes()
+}
+
+/// Decode a record whose value may have an older layout
+fn decode_record<K, V>(version: u32, data: &[u8]) -> Result<Record<K, V>>
+    where K: DeserializeOwned, V: Versioned
+{
+    let mut deserializer = bincode::Deserializer::from_slice(data, bincode_options());
+    // Index of the variant, as serialized by bincode
+    match u32::deserialize(&mut deserializer)? {
+        0 => Ok(Record::Put(K::deserialize(&mut deserializer)?, V::decode(version, &mut deserializer)?)),
+        1 => Ok(Record::Delete(K::deserialize(&mut deserializer)?)),
+        tag => bail!("Unknown record {tag}"),
+    }
+}
+
+/// Record framing : length (u32 LE), CRC32 of the data (u32 LE), data
+fn encode_record(data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 8);
+    frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+fn read_record(reader: &mut &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap_or_default()) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap_or_default());
+
+    if reader.len() < len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data)?;
+
+    if crc32fast::hash(&data) != crc {
+        return Err(std::io::Error::new(ErrorKind::InvalidData, "Checksum mismatch"));
+    }
+    Ok(data)
+}
+
+/// Whether a record running past the end of the journal is a record cut while being appended
+/// A damaged length can also run past the end, the valid records that follow it tell it apart
+fn is_torn_tail(tail: &[u8]) -> bool {
+    (1..tail.len()).all(|start| {
+        let mut reader = &tail[start..];
+        !matches!(read_record(&mut reader), Ok(data) if !data.is_empty())
+    })
+}
+
+/// Replace a file without ever leaving it half written :
+/// write a temporary file, sync it, rename it over the target, then sync the directory
+pub(super) fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = with_suffix(path, "tmp");
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp, path)?;
+
+    // The rename itself is only durable once the directory is synced
+    sync_parent(path)
+}
+
+/// Remove a file, durably once this returns
+pub(super) fn remove_durable(path: &Path) -> Result<()> {
+    fs::remove_file(path)?;
+    sync_parent(path)
+}
+
+fn sync_parent(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn path() -> PathBuf {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("table.bincode")
+    }
+
+    fn open(path: &Path, startup: Startup) -> Result<Table<String, u64>> {
+        let mut table = Table::new(path);
+        table.load(startup)?;
+        Ok(table)
+    }
+
+    #[rstest]
+    pub fn journal_replay_test() {
+        let path = path();
+        let mut table = open(&path, Startup::Strict).unwrap();
+        table.insert("a".into(), 1).unwrap();
+        table.insert("b".into(), 2).unwrap();
+        table.remove("a").unwrap();
+        table.insert("b".into(), 3).unwrap();
+
+        // Only the journal has been written
+        assert!(!path.exists());
+
+        let table = open(&path, Startup::Strict).unwrap();
+        assert!(!table.contains_key("a"));
+        assert_eq!(table.get("b"), Some(&3));
+    }
+
+    #[rstest]
+    pub fn table_name_test() {
+        let table: Table<String, u64> = Table::new(Path::new("data").join("users.bincode"));
+        assert_eq!(table.table_name(), "users");
+    }
+
+    #[rstest]
+    pub fn journal_compaction_test() {
+        let path = path();
+        let mut table = open(&path, Startup::Strict).unwrap();
+        table.insert("a".into(), 1).unwrap();
+        table.compact().unwrap();
+        table.insert("b".into(), 2).unwrap();
+
+        let table = open(&path, Startup::Strict).unwrap();
+        assert_eq!(table.get("a"), Some(&1));
+        assert_eq!(table.get("b"), Some(&2));
+    }
+
+    #[rstest]
+    pub fn journal_torn_tail_test() {
+        let path = path();
+        let mut table = open(&path, Startup::Strict).unwrap();
+        table.insert("a".into(), 1).unwrap();
+        table.insert("b".into(), 2).unwrap();
+
+        // The process stopped while appending the last record
+        let journal = with_suffix(&path, "journal");
+        let len = fs::metadata(&journal).unwrap().len();
+        OpenOptions::new().write(true).open(&journal).unwrap().set_len(len - 3).unwrap();
+
+        // The strict mode doesn't touch the file
+        assert!(open(&path, Startup::Strict).is_err());
+        assert_eq!(fs::metadata(&journal).unwrap().len(), len - 3);
+
+        let mut table = open(&path, Startup::Repair).unwrap();
+        assert_eq!(table.get("a"), Some(&1));
+        assert!(!table.contains_key("b"));
+
+        // New records are appended after the last valid one
+        table.insert("c".into(), 3).unwrap();
+        let table = open(&path, Startup::Strict).unwrap();
+        assert_eq!(table.get("c"), Some(&3));
+    }
+
+    #[rstest]
+    pub fn journal_failed_append_test() {
+        let path = path();
+        let mut table = open(&path, Startup::Strict).unwrap();
+        table.insert("a".into(), 1).unwrap();
+
+        // Disk full in the middle of a record
+        table.fail_after = Some(5);
+        assert!(table.insert("b".into(), 2).is_err());
+        assert!(!table.contains_key("b"));
+        table.fail_after = Some(0);
+        assert!(table.remove("a").is_err());
+        assert_eq!(table.get("a"), Some(&1));
+
+        // The next records follow the last complete one
+        table.insert("c".into(), 3).unwrap();
+        let table = open(&path, Startup::Strict).unwrap();
+        assert_eq!(table.get("a"), Some(&1));
+        assert!(!table.contains_key("b"));
+        assert_eq!(table.get("c"), Some(&3));
+    }
+
+    #[rstest]
+    pub fn journal_strict_upgrade_test() {
+        let path = path();
+        let journal = with_suffix(&path, "journal");
+
+        // Snapshot written before the versioning, journal with the current layout
+        let old: HashMap<String, u64> = HashMap::from([("a".into(), 1)]);
+        fs::write(&path, bincode::serialize(&old).unwrap()).unwrap();
+        let mut content = header::<u64>().to_vec();
+        content.extend_from_slice(&encode_record(&bincode::serialize(&Record::Put("b".to_string(), 2u64)).unwrap()));
+        fs::write(&journal, content).unwrap();
+
+        // Not damaged, the strict mode upgrades it too
+        let table = open(&path, Startup::Strict).unwrap();
+        assert_eq!((table.get("a"), table.get("b")), (Some(&1), Some(&2)));
+        assert!(fs::read(&path).unwrap().starts_with(MAGIC));
+        assert_eq!(fs::metadata(&journal).unwrap().len(), 0);
+
+        let table = open(&path, Startup::Strict).unwrap();
+        assert_eq!((table.get("a"), table.get("b")), (Some(&1), Some(&2)));
+    }
+
+    #[rstest]
+    pub fn journal_corrupted_length_test() {
+        let path = path();
+        let mut table = open(&path, Startup::Strict).unwrap();
+        table.insert("a".into(), 1).unwrap();
+        table.insert("b".into(), 2).unwrap();
+        table.insert("c".into(), 3).unwrap();
+
+        // The length of the middle record now runs past the end of the journal
+        let journal = with_suffix(&path, "journal");
+        let mut content = fs::read(&journal).unwrap();
+        let frame = (content.len() - HEADER_LEN) / 3;
+        content[HEADER_LEN + frame + 2] ^= 0x01;
+        fs::write(&journal, &content).unwrap();
+
+        // Not an incomplete last record, the next records must not be dropped
+        assert!(open(&path, Startup::Strict).is_err());
+        assert!(open(&path, Startup::Repair).is_err());
+        assert_eq!(fs::read(&journal).unwrap(), content);
+    }
+
+    #[rstest]
+    pub fn journal_corruption_test() {
+        let path = path();
+        let mut table = open(&path, Startup::Strict).unwrap();
+        table.insert("a".into(), 1).unwrap();
+        table.insert("b".into(), 2).unwrap();
+
+        // Flip a byte of the first record
+        let journal = with_suffix(&path, "journal");
+        let mut content = fs::read(&journal).unwrap();
+        content[HEADER_LEN + 10] ^= 0xff;
+        fs::write(&journal, content).unwrap();
+
+        assert!(open(&path, Startup::Strict).is_err());
+
+        // Lenient mode starts empty and keeps the corrupted file aside
+        let table = open(&path, Startup::Lenient).unwrap();
+        assert_eq!(table.iter().count(), 0);
+        assert!(!journal.exists());
+    }
+}
@@ -0,0 +1,782 @@
+use std::sync::Mutex;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use uuid::Uuid;
+use crate::database::authorization::Code;
+use crate::database::client::Client;
+use crate::database::email::{Email, Status};
+use crate::database::family::Family;
+use crate::database::session::Session;
+use crate::database::throttle::email_key;
+use crate::database::store::{Startup, Store};
+use crate::database::token::{Purpose, Token};
+use crate::database::user::{Passkeys, Totp, User, UserRole};
+use crate::metrics::DB_SAVE_DURATION;
+use crate::utils::rate_limit::Attempts;
+
+/// Schema migrations, applied in order
+/// The index of the last applied migration is saved in the `user_version` of the database
+/// Never modify an existing migration, add a new one instead
+const MIGRATIONS: &[&str] = &[
+    // 1 : initial schema
+    "CREATE TABLE users (
+        email TEXT PRIMARY KEY,
+        hash TEXT NOT NULL,
+        verified INTEGER NOT NULL,
+        totp TEXT -- JSON, NULL if the user has no second factor
+    );
+    CREATE TABLE tokens (
+        token TEXT PRIMARY KEY,
+        email TEXT NOT NULL,
+        expiration INTEGER NOT NULL,
+        purpose TEXT NOT NULL
+    );
+    CREATE TABLE emails (
+        pk INTEGER PRIMARY KEY AUTOINCREMENT,
+        recipient TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        body TEXT NOT NULL
+    );
+    CREATE INDEX emails_recipient ON emails (recipient);",
+    // 2 : outbox delivery status
+    "ALTER TABLE emails ADD COLUMN status TEXT NOT NULL DEFAULT 'pending';
+    ALTER TABLE emails ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE emails ADD COLUMN next_attempt INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE emails ADD COLUMN error TEXT;
+    CREATE INDEX emails_due ON emails (status, next_attempt);",
+    // 3 : HTML part of the emails
+    "ALTER TABLE emails ADD COLUMN html TEXT;",
+    // 4 : passkeys
+    "ALTER TABLE users ADD COLUMN passkeys TEXT; -- JSON, NULL if the user never registered a passkey",
+    // 5 : external identities
+    "CREATE TABLE identities (
+        issuer TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        email TEXT NOT NULL,
+        PRIMARY KEY (issuer, subject)
+    );
+    CREATE INDEX identities_email ON identities (email);",
+    // 6 : roles
+    "ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user';",
+    // 7 : accounts disabled by an admin
+    "ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;",
+    // 8 : email change
+    "ALTER TABLE tokens ADD COLUMN new_email TEXT;",
+    // 9 : browser sessions
+    "CREATE TABLE sessions (
+        id TEXT PRIMARY KEY,
+        data TEXT NOT NULL, -- JSON
+        created INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL
+    );",
+    // 10 : refresh token families, revoked JWTs, failed logins and OAuth
+    "CREATE TABLE families (
+        id TEXT PRIMARY KEY,
+        email TEXT NOT NULL,
+        generation INTEGER NOT NULL,
+        revoked INTEGER NOT NULL,
+        expiration INTEGER NOT NULL,
+        device TEXT NOT NULL -- JSON
+    );
+    CREATE INDEX families_email ON families (email);
+    CREATE TABLE denylist (
+        jti TEXT PRIMARY KEY,
+        expiration INTEGER NOT NULL
+    );
+    CREATE TABLE revoked_before (
+        email TEXT PRIMARY KEY,
+        timestamp INTEGER NOT NULL
+    );
+    CREATE TABLE throttle (
+        key TEXT PRIMARY KEY,
+        attempts TEXT NOT NULL, -- JSON
+        expiration INTEGER NOT NULL -- Nothing left to enforce from then
+    );
+    CREATE TABLE clients (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        secret_hash TEXT,
+        redirect_uris TEXT NOT NULL, -- JSON
+        created INTEGER NOT NULL
+    );
+    CREATE TABLE authorization_codes (
+        code TEXT PRIMARY KEY,
+        email TEXT NOT NULL,
+        client_id TEXT NOT NULL,
+        redirect_uri TEXT NOT NULL,
+        scopes TEXT NOT NULL, -- JSON
+        nonce TEXT,
+        code_challenge TEXT NOT NULL,
+        expiration INTEGER NOT NULL
+    );
+    CREATE TABLE consents (
+        email TEXT NOT NULL,
+        client_id TEXT NOT NULL,
+        scopes TEXT NOT NULL, -- JSON
+        PRIMARY KEY (email, client_id)
+    );",
+    // 11 : stable identifier of the users
+    "ALTER TABLE users ADD COLUMN id TEXT NOT NULL DEFAULT '';
+    UPDATE users SET id = lower(hex(randomblob(16)));
+    CREATE UNIQUE INDEX users_id ON users (id);",
+];
+
+/// Store backed by an embedded SQLite database
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open the database and apply the missing migrations
+    /// Unless lenient, the integrity of the database is checked first
+    pub fn open(path: &str, startup: Startup) -> Result<Self> {
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+
+        if startup != Startup::Lenient {
+            let check: String = conn.pragma_query_value(None, "quick_check", |row| row.get(0))?;
+            if check != "ok" {
+                return Err(anyhow!("Database {path} is corrupted : {check}"));
+            }
+        } else {
+            warn!("Database integrity isn't checked");
+        }
+        migrate(&mut conn)?;
+
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> Result<std::sync::MutexGuard<'_, Connection>> {
+        self.conn.lock().or(Err(anyhow!("DB poisoned")))
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(anyhow!("Database schema version {version} is newer than this binary"));
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Apply database migration {}", i + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+fn purpose_to_sql(purpose: Purpose) -> &'static str {
+    match purpose {
+        Purpose::Verify => "verify",
+        Purpose::Reset => "reset",
+        Purpose::ChangeEmail => "change_email",
+    }
+}
+
+fn purpose_from_sql(purpose: &str) -> Result<Purpose> {
+    match purpose {
+        "verify" => Ok(Purpose::Verify),
+        "reset" => Ok(Purpose::Reset),
+        "change_email" => Ok(Purpose::ChangeEmail),
+        _ => Err(anyhow!("Unknown token purpose {purpose}")),
+    }
+}
+
+fn role_to_sql(role: UserRole) -> &'static str {
+    match role {
+        UserRole::User => "user",
+        UserRole::Admin => "admin",
+    }
+}
+
+fn role_from_sql(role: &str) -> Result<UserRole> {
+    match role {
+        "user" => Ok(UserRole::User),
+        "admin" => Ok(UserRole::Admin),
+        _ => Err(anyhow!("Unknown user role {role}")),
+    }
+}
+
+fn status_to_sql(status: Status) -> &'static str {
+    match status {
+        Status::Pending => "pending",
+        Status::Sent => "sent",
+        Status::Failed => "failed",
+    }
+}
+
+fn status_from_sql(status: &str) -> Result<Status> {
+    match status {
+        "pending" => Ok(Status::Pending),
+        "sent" => Ok(Status::Sent),
+        "failed" => Ok(Status::Failed),
+        _ => Err(anyhow!("Unknown email status {status}")),
+    }
+}
+
+const USER_COLUMNS: &str = "hash, verified, totp, passkeys, role, disabled, id";
+
+type UserColumns = (String, bool, Option<String>, Option<String>, String, bool, String);
+
+fn read_user(row: &Row) -> rusqlite::Result<UserColumns> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?))
+}
+
+fn user_from_columns((hash, verified, totp, passkeys, role, disabled, id): UserColumns) -> Result<User> {
+    let totp: Option<Totp> = totp.map(|t| serde_json::from_str(&t)).transpose()?;
+    let passkeys: Option<Passkeys> = passkeys.map(|p| serde_json::from_str(&p)).transpose()?;
+    Ok(User { hash, verified, totp, passkeys, role: role_from_sql(&role)?, disabled, id: Uuid::parse_str(&id)? })
+}
+
+/// Escape the wildcards of a LIKE pattern, `\` being the escape character
+fn escape_like(search: &str) -> String {
+    search.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+fn passkeys_to_sql(user: &User) -> Result<Option<String>> {
+    Ok(user.passkeys.as_ref().map(serde_json::to_string).transpose()?)
+}
+
+const EMAIL_COLUMNS: &str = "pk, recipient, subject, body, status, attempts, next_attempt, error, html";
+
+fn read_email(row: &Row) -> rusqlite::Result<(Email, String)> {
+    let email = Email {
+        pk: row.get(0)?,
+        to: row.get(1)?,
+        subject: row.get(2)?,
+        body: row.get(3)?,
+        html: row.get(8)?,
+        status: Status::Pending, // Parsed by `email_from_columns`
+        attempts: row.get(5)?,
+        next_attempt: row.get(6)?,
+        error: row.get(7)?,
+    };
+    Ok((email, row.get(4)?))
+}
+
+fn email_from_columns((email, status): (Email, String)) -> Result<Email> {
+    Ok(Email { status: status_from_sql(&status)?, ..email })
+}
+
+fn totp_to_sql(user: &User) -> Result<Option<String>> {
+    Ok(user.totp.as_ref().map(serde_json::to_string).transpose()?)
+}
+
+const FAMILY_COLUMNS: &str = "email, generation, revoked, expiration, device";
+
+fn read_family(row: &Row) -> rusqlite::Result<(String, u64, bool, u64, String)> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+}
+
+fn family_from_columns((email, generation, revoked, expiration, device): (String, u64, bool, u64, String)) -> Result<Family> {
+    Ok(Family { email, generation, revoked, expiration, device: serde_json::from_str(&device)? })
+}
+
+const CODE_COLUMNS: &str = "email, client_id, redirect_uri, scopes, nonce, code_challenge, expiration";
+
+type CodeColumns = (String, String, String, String, Option<String>, String, u64);
+
+fn read_code(row: &Row) -> rusqlite::Result<CodeColumns> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?))
+}
+
+fn code_from_columns((email, client_id, redirect_uri, scopes, nonce, code_challenge, expiration): CodeColumns) -> Result<Code> {
+    Ok(Code { email, client_id, redirect_uri, scopes: serde_json::from_str(&scopes)?, nonce, code_challenge, expiration })
+}
+
+impl Store for SqliteStore {
+    fn get_user(&self, email: &str) -> Result<Option<User>> {
+        let conn = self.conn()?;
+        conn.query_row(&format!("SELECT {USER_COLUMNS} FROM users WHERE email = ?1"), params![email], read_user)
+            .optional()?
+            .map(user_from_columns)
+            .transpose()
+    }
+
+    fn insert_user(&self, email: &str, user: &User) -> Result<bool> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["users"]).start_timer();
+        let conn = self.conn()?;
+        let inserted = conn.execute(
+            &format!("INSERT OR IGNORE INTO users (email, {USER_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"),
+            params![email, user.hash, user.verified, totp_to_sql(user)?, passkeys_to_sql(user)?, role_to_sql(user.role), user.disabled, user.id.to_string()],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    fn update_user(&self, email: &str, update: &mut dyn FnMut(&mut User) -> bool) -> Result<bool> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        let user = tx.query_row(&format!("SELECT {USER_COLUMNS} FROM users WHERE email = ?1"), params![email], read_user)
+            .optional()?
+            .map(user_from_columns)
+            .transpose()?;
+        let mut user = match user {
+            Some(u) => u,
+            None => return Ok(false),
+        };
+
+        if !update(&mut user) {
+            return Ok(false);
+        }
+
+        let _timer = DB_SAVE_DURATION.with_label_values(&["users"]).start_timer();
+        tx.execute(
+            "UPDATE users SET hash = ?2, verified = ?3, totp = ?4, passkeys = ?5, role = ?6, disabled = ?7 WHERE email = ?1",
+            params![email, user.hash, user.verified, totp_to_sql(&user)?, passkeys_to_sql(&user)?, role_to_sql(user.role), user.disabled],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn list_users(&self, search: &str, offset: usize, limit: usize) -> Result<(usize, Vec<(String, User)>)> {
+        let conn = self.conn()?;
+        let pattern = format!("%{}%", escape_like(search));
+
+        let total: usize = conn.query_row(
+            r"SELECT COUNT(*) FROM users WHERE email LIKE ?1 ESCAPE '\'",
+            params![pattern],
+            |row| row.get(0),
+        )?;
+
+        let mut statement = conn.prepare(&format!(
+            r"SELECT email, {USER_COLUMNS} FROM users WHERE email LIKE ?1 ESCAPE '\' ORDER BY email LIMIT ?2 OFFSET ?3"
+        ))?;
+        let users = statement
+            .query_map(params![pattern, limit, offset], |row| Ok((row.get::<_, String>(0)?, (
+                row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?,
+            ))))?
+            .map(|r| {
+                let (email, columns) = r?;
+                Ok((email, user_from_columns(columns)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok((total, users))
+    }
+
+    fn delete_user(&self, email: &str) -> Result<bool> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["users"]).start_timer();
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        let deleted = tx.execute("DELETE FROM users WHERE email = ?1", params![email])?;
+        if deleted == 0 {
+            return Ok(false);
+        }
+        tx.execute("DELETE FROM identities WHERE email = ?1", params![email])?;
+        tx.execute("DELETE FROM tokens WHERE email = ?1", params![email])?;
+        tx.execute("DELETE FROM consents WHERE email = ?1", params![email])?;
+        tx.execute("DELETE FROM families WHERE email = ?1", params![email])?;
+        tx.execute("DELETE FROM throttle WHERE key = ?1", params![email_key(email)])?;
+
+        tx.commit()?;
+        Ok(deleted == 1)
+    }
+
+    fn rename_user(&self, email: &str, new_email: &str) -> Result<bool> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["users"]).start_timer();
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        let taken: bool = tx.query_row("SELECT EXISTS (SELECT 1 FROM users WHERE email = ?1)", params![new_email], |row| row.get(0))?;
+        if taken {
+            return Ok(false);
+        }
+        if tx.execute("UPDATE users SET email = ?2 WHERE email = ?1", params![email, new_email])? == 0 {
+            return Ok(false);
+        }
+        tx.execute("UPDATE identities SET email = ?2 WHERE email = ?1", params![email, new_email])?;
+        tx.execute("UPDATE tokens SET email = ?2 WHERE email = ?1", params![email, new_email])?;
+        tx.execute(
+            "UPDATE emails SET recipient = ?2 WHERE recipient = ?1 AND status = ?3",
+            params![email, new_email, status_to_sql(Status::Pending)],
+        )?;
+        tx.execute("UPDATE consents SET email = ?2 WHERE email = ?1", params![email, new_email])?;
+        tx.execute("UPDATE families SET email = ?2 WHERE email = ?1", params![email, new_email])?;
+
+        // The failed logins follow the account, replacing whatever was counted against the unused email
+        let (key, new_key) = (email_key(email), email_key(new_email));
+        tx.execute(
+            "INSERT OR REPLACE INTO throttle (key, attempts, expiration) SELECT ?2, attempts, expiration FROM throttle WHERE key = ?1",
+            params![key, new_key],
+        )?;
+        tx.execute("DELETE FROM throttle WHERE key = ?1", params![key])?;
+
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn get_identity(&self, issuer: &str, subject: &str) -> Result<Option<String>> {
+        let conn = self.conn()?;
+        Ok(conn.query_row(
+            "SELECT email FROM identities WHERE issuer = ?1 AND subject = ?2",
+            params![issuer, subject],
+            |row| row.get(0),
+        ).optional()?)
+    }
+
+    fn link_identity(&self, issuer: &str, subject: &str, email: &str) -> Result<bool> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["identities"]).start_timer();
+        let conn = self.conn()?;
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO identities (issuer, subject, email) VALUES (?1, ?2, ?3)",
+            params![issuer, subject, email],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    fn add_token(&self, token: &str, entry: &Token) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["tokens"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO tokens (token, email, expiration, purpose, new_email) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![token, entry.email, entry.expiration, purpose_to_sql(entry.purpose), entry.new_email],
+        )?;
+        Ok(())
+    }
+
+    fn take_token(&self, token: &str, purpose: Purpose) -> Result<Option<Token>> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["tokens"]).start_timer();
+        let conn = self.conn()?;
+        conn.query_row(
+            "DELETE FROM tokens WHERE token = ?1 AND purpose = ?2 RETURNING email, expiration, purpose, new_email",
+            params![token, purpose_to_sql(purpose)],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?, row.get::<_, String>(2)?, row.get::<_, Option<String>>(3)?)),
+        )
+            .optional()?
+            .map(|(email, expiration, purpose, new_email)| Ok(Token { email, expiration, purpose: purpose_from_sql(&purpose)?, new_email }))
+            .transpose()
+    }
+
+    fn delete_tokens(&self, email: &str, purpose: Purpose) -> Result<usize> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["tokens"]).start_timer();
+        let conn = self.conn()?;
+        Ok(conn.execute("DELETE FROM tokens WHERE email = ?1 AND purpose = ?2", params![email, purpose_to_sql(purpose)])?)
+    }
+
+    fn add_email(&self, to: &str, subject: &str, body: &str, html: Option<&str>) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["emails"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO emails (recipient, subject, body, html) VALUES (?1, ?2, ?3, ?4)",
+            params![to, subject, body, html],
+        )?;
+        Ok(())
+    }
+
+    fn get_emails(&self, to: &str) -> Result<Vec<Email>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!("SELECT {EMAIL_COLUMNS} FROM emails WHERE recipient = ?1 ORDER BY pk"))?;
+        let emails = stmt
+            .query_map(params![to], read_email)?
+            .map(|row| email_from_columns(row?))
+            .collect::<Result<Vec<Email>>>()?;
+        Ok(emails)
+    }
+
+    fn due_emails(&self, now: u64) -> Result<Vec<Email>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {EMAIL_COLUMNS} FROM emails WHERE status = ?1 AND next_attempt <= ?2 ORDER BY pk"
+        ))?;
+        let emails = stmt
+            .query_map(params![status_to_sql(Status::Pending), now], read_email)?
+            .map(|row| email_from_columns(row?))
+            .collect::<Result<Vec<Email>>>()?;
+        Ok(emails)
+    }
+
+    fn update_email(&self, pk: u64, update: &mut dyn FnMut(&mut Email) -> bool) -> Result<bool> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        let email = tx.query_row(&format!("SELECT {EMAIL_COLUMNS} FROM emails WHERE pk = ?1"), params![pk], read_email)
+            .optional()?
+            .map(email_from_columns)
+            .transpose()?;
+        let mut email = match email {
+            Some(e) => e,
+            None => return Ok(false),
+        };
+
+        if !update(&mut email) {
+            return Ok(false);
+        }
+
+        let _timer = DB_SAVE_DURATION.with_label_values(&["emails"]).start_timer();
+        tx.execute(
+            "UPDATE emails SET status = ?2, attempts = ?3, next_attempt = ?4, error = ?5 WHERE pk = ?1",
+            params![pk, status_to_sql(email.status), email.attempts, email.next_attempt, email.error],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn get_session(&self, id: &str) -> Result<Option<Session>> {
+        let conn = self.conn()?;
+        Ok(conn.query_row(
+            "SELECT data, created, last_seen FROM sessions WHERE id = ?1",
+            params![id],
+            |row| Ok(Session { data: row.get(0)?, created: row.get(1)?, last_seen: row.get(2)? }),
+        ).optional()?)
+    }
+
+    fn save_session(&self, id: &str, session: &Session) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["sessions"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO sessions (id, data, created, last_seen) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (id) DO UPDATE SET data = excluded.data, last_seen = excluded.last_seen",
+            params![id, session.data, session.created, session.last_seen],
+        )?;
+        Ok(())
+    }
+
+    fn delete_session(&self, id: &str) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["sessions"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute("DELETE FROM sessions WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    fn delete_expired_sessions(&self, created: u64, last_seen: u64) -> Result<usize> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["sessions"]).start_timer();
+        let conn = self.conn()?;
+        Ok(conn.execute("DELETE FROM sessions WHERE created <= ?1 OR last_seen <= ?2", params![created, last_seen])?)
+    }
+
+    fn add_family(&self, id: &str, family: &Family) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["families"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute(
+            &format!("INSERT OR REPLACE INTO families (id, {FAMILY_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6)"),
+            params![id, family.email, family.generation, family.revoked, family.expiration, serde_json::to_string(&family.device)?],
+        )?;
+        Ok(())
+    }
+
+    fn get_family(&self, id: &str) -> Result<Option<Family>> {
+        let conn = self.conn()?;
+        conn.query_row(&format!("SELECT {FAMILY_COLUMNS} FROM families WHERE id = ?1"), params![id], read_family)
+            .optional()?
+            .map(family_from_columns)
+            .transpose()
+    }
+
+    fn update_family(&self, id: &str, update: &mut dyn FnMut(&mut Family) -> bool) -> Result<bool> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        let family = tx.query_row(&format!("SELECT {FAMILY_COLUMNS} FROM families WHERE id = ?1"), params![id], read_family)
+            .optional()?
+            .map(family_from_columns)
+            .transpose()?;
+        let mut family = match family {
+            Some(f) => f,
+            None => return Ok(false),
+        };
+
+        if !update(&mut family) {
+            return Ok(false);
+        }
+
+        let _timer = DB_SAVE_DURATION.with_label_values(&["families"]).start_timer();
+        tx.execute(
+            "UPDATE families SET email = ?2, generation = ?3, revoked = ?4, expiration = ?5, device = ?6 WHERE id = ?1",
+            params![id, family.email, family.generation, family.revoked, family.expiration, serde_json::to_string(&family.device)?],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn list_families(&self, email: &str) -> Result<Vec<(String, Family)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!("SELECT id, {FAMILY_COLUMNS} FROM families WHERE email = ?1"))?;
+        let families = stmt
+            .query_map(params![email], |row| Ok((row.get::<_, String>(0)?, (
+                row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?,
+            ))))?
+            .map(|r| {
+                let (id, columns) = r?;
+                Ok((id, family_from_columns(columns)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(families)
+    }
+
+    fn revoke_families(&self, email: &str) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["families"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute("UPDATE families SET revoked = 1 WHERE email = ?1", params![email])?;
+        Ok(())
+    }
+
+    fn delete_expired_families(&self, now: u64) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["families"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute("DELETE FROM families WHERE expiration <= ?1", params![now])?;
+        Ok(())
+    }
+
+    fn deny_jwt(&self, jti: &str, expiration: u64) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["denylist"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute("INSERT OR REPLACE INTO denylist (jti, expiration) VALUES (?1, ?2)", params![jti, expiration])?;
+        Ok(())
+    }
+
+    fn is_jwt_denied(&self, jti: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        Ok(conn.query_row("SELECT EXISTS (SELECT 1 FROM denylist WHERE jti = ?1)", params![jti], |row| row.get(0))?)
+    }
+
+    fn delete_expired_denied(&self, now: u64) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["denylist"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute("DELETE FROM denylist WHERE expiration <= ?1", params![now])?;
+        Ok(())
+    }
+
+    fn get_revoked_before(&self, email: &str) -> Result<Option<u64>> {
+        let conn = self.conn()?;
+        Ok(conn.query_row("SELECT timestamp FROM revoked_before WHERE email = ?1", params![email], |row| row.get(0))
+            .optional()?)
+    }
+
+    fn set_revoked_before(&self, email: &str, timestamp: u64) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["revoked_before"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO revoked_before (email, timestamp) VALUES (?1, ?2)",
+            params![email, timestamp],
+        )?;
+        Ok(())
+    }
+
+    fn get_attempts(&self, key: &str) -> Result<Option<Attempts>> {
+        let conn = self.conn()?;
+        conn.query_row("SELECT attempts FROM throttle WHERE key = ?1", params![key], |row| row.get::<_, String>(0))
+            .optional()?
+            .map(|a| Ok(serde_json::from_str(&a)?))
+            .transpose()
+    }
+
+    fn update_attempts(&self, key: &str, update: &mut dyn FnMut(&mut Attempts)) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        let attempts = tx.query_row("SELECT attempts FROM throttle WHERE key = ?1", params![key], |row| row.get::<_, String>(0))
+            .optional()?;
+        let mut attempts: Attempts = match attempts {
+            Some(a) => serde_json::from_str(&a)?,
+            None => Attempts::default(),
+        };
+        update(&mut attempts);
+
+        let _timer = DB_SAVE_DURATION.with_label_values(&["throttle"]).start_timer();
+        tx.execute(
+            "INSERT OR REPLACE INTO throttle (key, attempts, expiration) VALUES (?1, ?2, ?3)",
+            params![key, serde_json::to_string(&attempts)?, attempts.expiration()],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete_attempts(&self, key: &str) -> Result<bool> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["throttle"]).start_timer();
+        let conn = self.conn()?;
+        Ok(conn.execute("DELETE FROM throttle WHERE key = ?1", params![key])? == 1)
+    }
+
+    fn delete_expired_attempts(&self, now: u64) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["throttle"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute("DELETE FROM throttle WHERE expiration <= ?1", params![now])?;
+        Ok(())
+    }
+
+    fn add_client(&self, id: &str, client: &Client) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["clients"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO clients (id, name, secret_hash, redirect_uris, created) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![id, client.name, client.secret_hash, serde_json::to_string(&client.redirect_uris)?, client.created],
+        )?;
+        Ok(())
+    }
+
+    fn get_client(&self, id: &str) -> Result<Option<Client>> {
+        let conn = self.conn()?;
+        conn.query_row(
+            "SELECT name, secret_hash, redirect_uris, created FROM clients WHERE id = ?1",
+            params![id],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?, row.get::<_, String>(2)?, row.get::<_, u64>(3)?)),
+        )
+            .optional()?
+            .map(|(name, secret_hash, redirect_uris, created)| Ok(Client {
+                name, secret_hash, redirect_uris: serde_json::from_str(&redirect_uris)?, created,
+            }))
+            .transpose()
+    }
+
+    fn add_code(&self, code: &str, grant: &Code) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["authorization_codes"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute(
+            &format!("INSERT OR REPLACE INTO authorization_codes (code, {CODE_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"),
+            params![
+                code, grant.email, grant.client_id, grant.redirect_uri, serde_json::to_string(&grant.scopes)?,
+                grant.nonce, grant.code_challenge, grant.expiration,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn take_code(&self, code: &str) -> Result<Option<Code>> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["authorization_codes"]).start_timer();
+        let conn = self.conn()?;
+        conn.query_row(
+            &format!("DELETE FROM authorization_codes WHERE code = ?1 RETURNING {CODE_COLUMNS}"),
+            params![code],
+            read_code,
+        )
+            .optional()?
+            .map(code_from_columns)
+            .transpose()
+    }
+
+    fn delete_expired_codes(&self, now: u64) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["authorization_codes"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute("DELETE FROM authorization_codes WHERE expiration <= ?1", params![now])?;
+        Ok(())
+    }
+
+    fn get_consent(&self, email: &str, client_id: &str) -> Result<Option<Vec<String>>> {
+        let conn = self.conn()?;
+        conn.query_row(
+            "SELECT scopes FROM consents WHERE email = ?1 AND client_id = ?2",
+            params![email, client_id],
+            |row| row.get::<_, String>(0),
+        )
+            .optional()?
+            .map(|scopes| Ok(serde_json::from_str(&scopes)?))
+            .transpose()
+    }
+
+    fn save_consent(&self, email: &str, client_id: &str, scopes: &[String]) -> Result<()> {
+        let _timer = DB_SAVE_DURATION.with_label_values(&["consents"]).start_timer();
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO consents (email, client_id, scopes) VALUES (?1, ?2, ?3)",
+            params![email, client_id, serde_json::to_string(scopes)?],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,479 @@
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use once_cell::sync::OnceCell;
+use crate::database::authorization::Code;
+use crate::database::client::Client;
+use crate::database::email::Email;
+use crate::database::family::Family;
+use crate::database::file_store::FileStore;
+use crate::database::sqlite_store::SqliteStore;
+use crate::database::session::Session;
+use crate::database::token::{Purpose, Token};
+use crate::database::user::User;
+use crate::utils::rate_limit::Attempts;
+
+/// Storage of everything the server keeps : the users and their external identities, the email tokens, the outgoing
+/// emails, the browser sessions, the refresh token families, the revoked JWTs, the failed logins and the OAuth data
+/// The business rules stay in the modules of `database`, a store only persists the data
+pub trait Store: Send + Sync {
+    /// Returns the user linked to the email, if any
+    fn get_user(&self, email: &str) -> Result<Option<User>>;
+
+    /// Returns false if a user already exists with this email
+    fn insert_user(&self, email: &str, user: &User) -> Result<bool>;
+
+    /// Atomically read, modify and save a user
+    /// `update` returns whether the user has been modified and must be saved
+    /// Returns false if the user doesn't exist or hasn't been modified
+    fn update_user(&self, email: &str, update: &mut dyn FnMut(&mut User) -> bool) -> Result<bool>;
+
+    /// Returns the users whose email contains `search` (case insensitive for ASCII), ordered by email, and the total number of matches
+    fn list_users(&self, search: &str, offset: usize, limit: usize) -> Result<(usize, Vec<(String, User)>)>;
+
+    /// Delete a user with its external identities, its tokens, its OAuth consents, its refresh token families
+    /// and its failed logins, nothing is left to a new account registered with the same email
+    /// Returns false if it doesn't exist
+    fn delete_user(&self, email: &str) -> Result<bool>;
+
+    /// Atomically move a user to a new email, with its external identities, its tokens, its pending emails,
+    /// its OAuth consents, its refresh token families and its failed logins
+    /// Returns false if the user doesn't exist or the new email is already taken
+    fn rename_user(&self, email: &str, new_email: &str) -> Result<bool>;
+
+    /// Returns the email of the user an external identity is linked to
+    fn get_identity(&self, issuer: &str, subject: &str) -> Result<Option<String>>;
+
+    /// Link an external identity to a user, returns false if it is already linked
+    fn link_identity(&self, issuer: &str, subject: &str, email: &str) -> Result<bool>;
+
+    fn add_token(&self, token: &str, entry: &Token) -> Result<()>;
+
+    /// Remove and return a token, only if it has been created for the given purpose
+    fn take_token(&self, token: &str, purpose: Purpose) -> Result<Option<Token>>;
+
+    /// Remove every token of a user created for the given purpose, returns how many were removed
+    fn delete_tokens(&self, email: &str, purpose: Purpose) -> Result<usize>;
+
+    fn add_email(&self, to: &str, subject: &str, body: &str, html: Option<&str>) -> Result<()>;
+
+    fn get_emails(&self, to: &str) -> Result<Vec<Email>>;
+
+    /// Returns the pending emails whose next attempt is before `now`, ordered by pk
+    fn due_emails(&self, now: u64) -> Result<Vec<Email>>;
+
+    /// Atomically read, modify and save an email, same as `update_user`
+    fn update_email(&self, pk: u64, update: &mut dyn FnMut(&mut Email) -> bool) -> Result<bool>;
+
+    fn get_session(&self, id: &str) -> Result<Option<Session>>;
+
+    /// Create or update a session, the creation date of an existing one is kept
+    fn save_session(&self, id: &str, session: &Session) -> Result<()>;
+
+    fn delete_session(&self, id: &str) -> Result<()>;
+
+    /// Delete the sessions created or last seen at or before the given timestamps, returns how many were deleted
+    fn delete_expired_sessions(&self, created: u64, last_seen: u64) -> Result<usize>;
+
+    fn add_family(&self, id: &str, family: &Family) -> Result<()>;
+
+    fn get_family(&self, id: &str) -> Result<Option<Family>>;
+
+    /// Atomically read, modify and save a family, same as `update_user`
+    fn update_family(&self, id: &str, update: &mut dyn FnMut(&mut Family) -> bool) -> Result<bool>;
+
+    /// Returns every family of a user, revoked and expired ones included
+    fn list_families(&self, email: &str) -> Result<Vec<(String, Family)>>;
+
+    /// Revoke every family of a user
+    fn revoke_families(&self, email: &str) -> Result<()>;
+
+    /// Delete the families whose latest token expired at or before `now`
+    fn delete_expired_families(&self, now: u64) -> Result<()>;
+
+    /// Add a JWT id to the denylist, until the JWT expiration
+    fn deny_jwt(&self, jti: &str, expiration: u64) -> Result<()>;
+
+    fn is_jwt_denied(&self, jti: &str) -> Result<bool>;
+
+    /// Remove the JWT ids whose JWT expired at or before `now`
+    fn delete_expired_denied(&self, now: u64) -> Result<()>;
+
+    /// Returns the timestamp before which the JWTs issued to a user are revoked
+    fn get_revoked_before(&self, email: &str) -> Result<Option<u64>>;
+
+    fn set_revoked_before(&self, email: &str, timestamp: u64) -> Result<()>;
+
+    /// Returns the failed logins of a key (an IP or an email)
+    fn get_attempts(&self, key: &str) -> Result<Option<Attempts>>;
+
+    /// Atomically read, modify and save the failed logins of a key, starting from none
+    fn update_attempts(&self, key: &str, update: &mut dyn FnMut(&mut Attempts)) -> Result<()>;
+
+    /// Returns false if the key had no failed logins
+    fn delete_attempts(&self, key: &str) -> Result<bool>;
+
+    /// Delete the failed logins with nothing left to enforce at `now`
+    fn delete_expired_attempts(&self, now: u64) -> Result<()>;
+
+    fn add_client(&self, id: &str, client: &Client) -> Result<()>;
+
+    fn get_client(&self, id: &str) -> Result<Option<Client>>;
+
+    fn add_code(&self, code: &str, grant: &Code) -> Result<()>;
+
+    /// Remove and return an authorization code
+    fn take_code(&self, code: &str) -> Result<Option<Code>>;
+
+    /// Delete the authorization codes expired at or before `now`
+    fn delete_expired_codes(&self, now: u64) -> Result<()>;
+
+    /// Returns the scopes a user granted to a client
+    fn get_consent(&self, email: &str, client_id: &str) -> Result<Option<Vec<String>>>;
+
+    fn save_consent(&self, email: &str, client_id: &str, scopes: &[String]) -> Result<()>;
+}
+
+/// Storage backends available
+#[derive(Clone, Debug, PartialEq)]
+pub enum Backend {
+    /// One bincode file per module, in the given directory
+    Bincode(String),
+    /// Embedded SQLite database, at the given path
+    Sqlite(String),
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    /// Parse a backend from its configuration : "bincode", "bincode:<dir>", "sqlite" or "sqlite:<path>"
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, path) = s.split_once(':').unwrap_or((s, ""));
+        match kind {
+            "bincode" => Ok(Backend::Bincode(if path.is_empty() { "." } else { path }.to_string())),
+            "sqlite" => Ok(Backend::Sqlite(if path.is_empty() { "king_auth.db" } else { path }.to_string())),
+            _ => bail!("Unknown storage backend {kind}"),
+        }
+    }
+}
+
+/// What to do with a damaged database at startup
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Startup {
+    /// Refuse to start on a damaged file, nothing is repaired. Files with an older layout are still upgraded.
+    Strict,
+    /// Cut the incomplete record left at the end of a journal by a stop while appending it
+    Repair,
+    /// Also move the corrupted files aside and start with empty ones
+    Lenient,
+}
+
+impl FromStr for Startup {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strict" => Ok(Startup::Strict),
+            "repair" => Ok(Startup::Repair),
+            "lenient" => Ok(Startup::Lenient),
+            _ => bail!("Unknown startup mode {s}"),
+        }
+    }
+}
+
+static STORE: OnceCell<Box<dyn Store>> = OnceCell::new();
+
+/// Open the storage backend, must be called once at startup
+/// Unless lenient, a corrupted database is an error instead of being discarded
+pub fn init(backend: &Backend, startup: Startup) -> Result<()> {
+    info!("Open {backend:?} storage");
+
+    let store: Box<dyn Store> = match backend {
+        Backend::Bincode(dir) => Box::new(FileStore::open(dir, startup)?),
+        Backend::Sqlite(path) => Box::new(SqliteStore::open(path, startup)?),
+    };
+
+    STORE.set(store).or(Err(anyhow!("Storage already initialized")))
+}
+
+pub(super) fn store() -> Result<&'static dyn Store> {
+    STORE.get().map(|s| s.as_ref()).ok_or(anyhow!("Storage not initialized"))
+}
+
+/// Storage shared by the tests of the `database` modules, in memory
+#[cfg(test)]
+pub(super) fn init_test_store() {
+    STORE.get_or_init(|| Box::new(SqliteStore::open(":memory:", Startup::Strict).expect("Failed to open test storage")));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use crate::database::email::Status;
+    use crate::database::family::Device;
+    use crate::database::throttle::email_key;
+    use crate::database::user::UserRole;
+    use crate::utils::rate_limit::Policy;
+
+    fn user() -> User {
+        User { hash: "hash".into(), verified: false, totp: None, passkeys: None, role: UserRole::User, disabled: false, id: uuid::Uuid::new_v4() }
+    }
+
+    fn token(purpose: Purpose) -> Token {
+        Token { email: "unit@test.com".into(), expiration: 42, purpose, new_email: None }
+    }
+
+    // Every backend must behave the same
+    fn check_store(store: &dyn Store) {
+        assert!(store.insert_user("unit@test.com", &user()).unwrap());
+        assert!(!store.insert_user("unit@test.com", &user()).unwrap());
+        assert!(store.get_user("nobody@test.com").unwrap().is_none());
+
+        assert!(store.update_user("unit@test.com", &mut |u| { u.verified = true; u.role = UserRole::Admin; true }).unwrap());
+        assert!(!store.update_user("unit@test.com", &mut |_| false).unwrap());
+        assert!(!store.update_user("nobody@test.com", &mut |_| true).unwrap());
+        let updated = store.get_user("unit@test.com").unwrap().unwrap();
+        assert!(updated.verified);
+        assert_eq!(updated.role, UserRole::Admin);
+
+        assert!(store.link_identity("https://idp.test.com", "42", "unit@test.com").unwrap());
+        assert!(!store.link_identity("https://idp.test.com", "42", "other@test.com").unwrap());
+        assert_eq!(store.get_identity("https://idp.test.com", "42").unwrap().as_deref(), Some("unit@test.com"));
+        assert!(store.get_identity("https://other.test.com", "42").unwrap().is_none());
+
+        assert!(store.insert_user("other@test.com", &user()).unwrap());
+        assert!(store.insert_user("third@test.com", &user()).unwrap());
+        let (total, users) = store.list_users("test.com", 1, 1).unwrap();
+        assert_eq!(total, 3);
+        assert_eq!(users[0].0, "third@test.com");
+        assert_eq!(store.list_users("other", 0, 10).unwrap().0, 1);
+        assert_eq!(store.list_users("OTHER@Test", 0, 10).unwrap().0, 1);
+        assert_eq!(store.list_users("%", 0, 10).unwrap().0, 0);
+
+        // Nothing of a deleted user is inherited by the next account with its email
+        let (now, policy) = (jsonwebtoken::get_current_timestamp(), Policy { free_failures: 0, max_failures: 2 });
+        store.link_identity("https://idp.test.com", "43", "third@test.com").unwrap();
+        store.add_token("third", &Token { email: "third@test.com".into(), ..token(Purpose::Reset) }).unwrap();
+        store.save_consent("third@test.com", "client", &["openid".to_string()]).unwrap();
+        store.add_family("third", &family("third@test.com", 100)).unwrap();
+        store.update_attempts(&email_key("third@test.com"), &mut |a| { a.fail(&policy, now); }).unwrap();
+        assert!(store.delete_user("third@test.com").unwrap());
+        assert!(!store.delete_user("third@test.com").unwrap());
+        assert!(store.get_user("third@test.com").unwrap().is_none());
+        assert!(store.get_identity("https://idp.test.com", "43").unwrap().is_none());
+        assert!(store.take_token("third", Purpose::Reset).unwrap().is_none());
+        assert!(store.get_consent("third@test.com", "client").unwrap().is_none());
+        assert!(store.get_family("third").unwrap().is_none());
+        assert!(store.get_attempts(&email_key("third@test.com")).unwrap().is_none());
+
+        store.add_token("token", &token(Purpose::Reset)).unwrap();
+        assert!(store.take_token("token", Purpose::Verify).unwrap().is_none());
+        assert_eq!(store.take_token("token", Purpose::Reset).unwrap().unwrap().expiration, 42);
+        assert!(store.take_token("token", Purpose::Reset).unwrap().is_none());
+        store.add_token("reset", &token(Purpose::Reset)).unwrap();
+        store.add_token("verify", &token(Purpose::Verify)).unwrap();
+        assert_eq!(store.delete_tokens("unit@test.com", Purpose::Reset).unwrap(), 1);
+        assert!(store.take_token("reset", Purpose::Reset).unwrap().is_none());
+        assert!(store.take_token("verify", Purpose::Verify).unwrap().is_some());
+
+        store.add_email("unit@test.com", "Subject", "Body", Some("<p>Body</p>")).unwrap();
+        store.add_email("other@test.com", "Subject", "Body", None).unwrap();
+        let emails = store.get_emails("unit@test.com").unwrap();
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].body, "Body");
+        assert_eq!(emails[0].html.as_deref(), Some("<p>Body</p>"));
+
+        let due = store.due_emails(0).unwrap();
+        assert_eq!(due.len(), 2);
+        let pk = due[0].pk;
+        assert!(store.update_email(pk, &mut |e| { e.next_attempt = 100; true }).unwrap());
+        assert_eq!(store.due_emails(99).unwrap().len(), 1);
+        assert!(store.update_email(pk, &mut |e| { e.status = Status::Sent; true }).unwrap());
+        assert_eq!(store.due_emails(100).unwrap().len(), 1);
+        assert!(!store.update_email(pk + 42, &mut |_| true).unwrap());
+
+        // Everything of the user follows it to its new email, failed logins on the unused email are dropped
+        store.link_identity("https://idp.test.com", "44", "other@test.com").unwrap();
+        store.save_consent("other@test.com", "client", &["openid".to_string()]).unwrap();
+        store.add_token("renamed", &Token { email: "other@test.com".into(), ..token(Purpose::Verify) }).unwrap();
+        store.add_family("moved", &family("other@test.com", 100)).unwrap();
+        for _ in 0..2 {
+            store.update_attempts(&email_key("other@test.com"), &mut |a| { a.fail(&policy, now); }).unwrap();
+        }
+        store.update_attempts(&email_key("renamed@test.com"), &mut |a| { a.fail(&policy, now); }).unwrap();
+        assert!(!store.rename_user("other@test.com", "unit@test.com").unwrap());
+        assert!(!store.rename_user("nobody@test.com", "renamed@test.com").unwrap());
+        let id = store.get_user("other@test.com").unwrap().unwrap().id;
+        assert!(store.rename_user("other@test.com", "renamed@test.com").unwrap());
+        assert!(store.get_user("other@test.com").unwrap().is_none());
+        assert_eq!(store.get_user("renamed@test.com").unwrap().unwrap().id, id);
+        assert_eq!(store.get_identity("https://idp.test.com", "44").unwrap().as_deref(), Some("renamed@test.com"));
+        assert_eq!(store.take_token("renamed", Purpose::Verify).unwrap().unwrap().email, "renamed@test.com");
+        assert!(store.get_emails("other@test.com").unwrap().is_empty());
+        assert_eq!(store.get_emails("renamed@test.com").unwrap().len(), 1);
+        assert!(store.get_consent("other@test.com", "client").unwrap().is_none());
+        assert_eq!(store.get_consent("renamed@test.com", "client").unwrap(), Some(vec!["openid".to_string()]));
+        assert_eq!(store.get_family("moved").unwrap().unwrap().email, "renamed@test.com");
+        assert!(store.get_attempts(&email_key("other@test.com")).unwrap().is_none());
+        assert!(store.get_attempts(&email_key("renamed@test.com")).unwrap().unwrap().retry_after(&policy, now).is_some());
+
+        let session = |data: &str, created, last_seen| Session { data: data.into(), created, last_seen };
+        store.save_session("session", &session("{}", 10, 10)).unwrap();
+        store.save_session("session", &session("{\"a\":1}", 20, 20)).unwrap();
+        let saved = store.get_session("session").unwrap().unwrap();
+        assert_eq!((saved.data.as_str(), saved.created, saved.last_seen), ("{\"a\":1}", 10, 20));
+        store.save_session("old", &session("{}", 5, 30)).unwrap();
+        store.save_session("idle", &session("{}", 15, 15)).unwrap();
+        assert_eq!(store.delete_expired_sessions(5, 15).unwrap(), 2);
+        assert!(store.get_session("old").unwrap().is_none());
+        assert!(store.get_session("idle").unwrap().is_none());
+        assert!(store.get_session("session").unwrap().is_some());
+        store.delete_session("session").unwrap();
+        assert!(store.get_session("session").unwrap().is_none());
+
+        store.add_family("family", &family("unit@test.com", 100)).unwrap();
+        store.add_family("expired", &family("unit@test.com", 50)).unwrap();
+        store.add_family("other", &family("other@test.com", 100)).unwrap();
+        assert!(store.update_family("family", &mut |f| { f.generation += 1; true }).unwrap());
+        assert!(!store.update_family("family", &mut |_| false).unwrap());
+        assert!(!store.update_family("nothing", &mut |_| true).unwrap());
+        assert_eq!(store.get_family("family").unwrap().unwrap().generation, 1);
+        assert_eq!(store.list_families("unit@test.com").unwrap().len(), 2);
+        store.delete_expired_families(50).unwrap();
+        assert!(store.get_family("expired").unwrap().is_none());
+        store.revoke_families("unit@test.com").unwrap();
+        assert!(store.get_family("family").unwrap().unwrap().revoked);
+        assert!(!store.get_family("other").unwrap().unwrap().revoked);
+
+        store.deny_jwt("jti", 100).unwrap();
+        store.deny_jwt("expired", 50).unwrap();
+        store.delete_expired_denied(50).unwrap();
+        assert!(store.is_jwt_denied("jti").unwrap());
+        assert!(!store.is_jwt_denied("expired").unwrap());
+        assert!(store.get_revoked_before("unit@test.com").unwrap().is_none());
+        store.set_revoked_before("unit@test.com", 42).unwrap();
+        assert_eq!(store.get_revoked_before("unit@test.com").unwrap(), Some(42));
+
+        // The second failure locks the key only if the first one has been saved
+        store.update_attempts("key", &mut |a| { a.fail(&policy, now); }).unwrap();
+        store.update_attempts("key", &mut |a| { a.fail(&policy, now); }).unwrap();
+        store.update_attempts("old", &mut |a| { a.fail(&policy, 0); }).unwrap();
+        store.delete_expired_attempts(now).unwrap();
+        assert!(store.get_attempts("old").unwrap().is_none());
+        assert!(store.get_attempts("key").unwrap().unwrap().retry_after(&policy, now).is_some());
+        assert!(store.delete_attempts("key").unwrap());
+        assert!(!store.delete_attempts("key").unwrap());
+
+        let client = Client { name: "App".into(), secret_hash: None, redirect_uris: vec!["https://app".into()], created: 42 };
+        store.add_client("client", &client).unwrap();
+        assert_eq!(store.get_client("client").unwrap().unwrap().redirect_uris, client.redirect_uris);
+        assert!(store.get_client("nothing").unwrap().is_none());
+
+        store.add_code("code", &code(100)).unwrap();
+        store.add_code("expired", &code(50)).unwrap();
+        store.delete_expired_codes(50).unwrap();
+        assert!(store.take_code("expired").unwrap().is_none());
+        assert_eq!(store.take_code("code").unwrap().unwrap().scopes, code(100).scopes);
+        assert!(store.take_code("code").unwrap().is_none());
+    }
+
+    fn family(email: &str, expiration: u64) -> Family {
+        let device = Device { label: "curl".into(), user_agent: "curl/8".into(), ip: "::1".into(), created: 0, last_used: 0 };
+        Family { email: email.into(), generation: 0, revoked: false, expiration, device }
+    }
+
+    fn code(expiration: u64) -> Code {
+        Code {
+            email: "unit@test.com".into(), client_id: "client".into(), redirect_uri: "https://app".into(),
+            scopes: vec!["openid".into(), "email".into()], nonce: None, code_challenge: "challenge".into(), expiration,
+        }
+    }
+
+    #[rstest]
+    pub fn file_store_test() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        check_store(&FileStore::open(&dir, Startup::Strict).unwrap());
+
+        // Data is reloaded from the files
+        let store = FileStore::open(&dir, Startup::Strict).unwrap();
+        assert!(store.get_user("unit@test.com").unwrap().unwrap().verified);
+        assert_eq!(store.get_emails("renamed@test.com").unwrap().len(), 1);
+        assert!(store.get_identity("https://idp.test.com", "42").unwrap().is_some());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[rstest]
+    pub fn file_store_interrupted_rename_test() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let store = FileStore::open(&dir, Startup::Strict).unwrap();
+        store.insert_user("unit@test.com", &user()).unwrap();
+        store.add_family("family", &family("unit@test.com", 100)).unwrap();
+        drop(store);
+
+        // Crash right after the rename has been recorded, nothing has been moved yet
+        let marker = dir.join("rename.pending");
+        std::fs::write(&marker, bincode::serialize(&("unit@test.com", "renamed@test.com")).unwrap()).unwrap();
+
+        let store = FileStore::open(&dir, Startup::Strict).unwrap();
+        assert!(store.get_user("unit@test.com").unwrap().is_none());
+        assert!(store.get_user("renamed@test.com").unwrap().is_some());
+        assert_eq!(store.get_family("family").unwrap().unwrap().email, "renamed@test.com");
+        assert!(!marker.exists());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[rstest]
+    pub fn file_store_upgrade_test() {
+        use std::collections::HashMap;
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Files written before the versioning, the tuples have the same layout as the structs of that time
+        let users: HashMap<String, (String, bool)> = HashMap::from([("unit@test.com".into(), ("hash".into(), true))]);
+        std::fs::write(dir.join("users.bincode"), bincode::serialize(&users).unwrap()).unwrap();
+        let tokens: HashMap<String, (String, u64)> = HashMap::from([("token".into(), ("unit@test.com".into(), 42_000))]);
+        std::fs::write(dir.join("tokens.bincode"), bincode::serialize(&tokens).unwrap()).unwrap();
+        let emails: HashMap<u64, (u64, String, String, String)> =
+            HashMap::from([(0, (0, "unit@test.com".into(), "Subject".into(), "Body".into()))]);
+        std::fs::write(dir.join("emails.bincode"), bincode::serialize(&(1u64, emails)).unwrap()).unwrap();
+
+        let mut id = None;
+        for _ in 0..2 {
+            let store = FileStore::open(&dir, Startup::Strict).unwrap();
+            let user = store.get_user("unit@test.com").unwrap().unwrap();
+            // The identifier given by the upgrade is kept
+            assert_eq!(*id.get_or_insert(user.id), user.id);
+            assert_eq!(user.hash, "hash");
+            assert!(user.can_login());
+            assert_eq!(user.role, UserRole::User);
+            let emails = store.get_emails("unit@test.com").unwrap();
+            assert_eq!(emails[0].body, "Body");
+            assert_eq!(emails[0].status, Status::Sent);
+            assert_eq!(store.take_token("token", Purpose::Verify).unwrap().unwrap().expiration, 42);
+            store.add_token("token", &token(Purpose::Verify)).unwrap();
+        }
+
+        // The files have been rewritten in the current layout
+        assert!(std::fs::read(dir.join("users.bincode")).unwrap().starts_with(b"KATB"));
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[rstest]
+    pub fn sqlite_store_test() {
+        check_store(&SqliteStore::open(":memory:", Startup::Strict).unwrap());
+    }
+
+    #[rstest(
+    input,
+    expected,
+    case("bincode", Some(Backend::Bincode(".".into()))),
+    case("bincode:/var/lib/king_auth", Some(Backend::Bincode("/var/lib/king_auth".into()))),
+    case("sqlite", Some(Backend::Sqlite("king_auth.db".into()))),
+    case("sqlite:/tmp/db.sqlite", Some(Backend::Sqlite("/tmp/db.sqlite".into()))),
+    case("postgres", None),
+    )]
+    pub fn backend_parse_test(input: &str, expected: Option<Backend>) {
+        assert_eq!(input.parse::<Backend>().ok(), expected);
+    }
+}
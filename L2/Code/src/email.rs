@@ -1,17 +1,39 @@
+pub mod outbox;
+pub mod templates;
+
 use anyhow::Result;
 use log::{info, trace};
+use serde_json::Value;
 use crate::database;
-use crate::HTTP_PORT;
+use crate::email::templates::Rendered;
+use crate::config;
 
-pub fn send_mail(to: &str, subject: &str, body: &str) -> Result<()> {
+/// Add an email to the outbox, it is delivered by the outbox worker if SMTP is configured
+pub fn send_mail(to: &str, email: &Rendered) -> Result<()> {
     info!("Sending an email");
 
-    database::email::add(to, subject, body)?;
+    database::email::add(to, &email.subject, &email.text, Some(&email.html))?;
+    outbox::wake();
 
     trace!("Email added");
 
     Ok(())
 }
+
+/// Render an email template in the given locale and send it
+pub fn send_template(to: &str, name: &str, locale: &str, data: &Value) -> Result<()> {
+    send_mail(to, &templates::render(name, locale, data)?)
+}
+
 pub fn get_verification_url(token: &str) -> String {
-    format!("http://127.0.0.1:{HTTP_PORT}/verify/{token}")
+    format!("{}/verify/{token}", config::get().public_url())
+}
+pub fn get_reset_url(token: &str) -> String {
+    format!("{}/reset-password/{token}", config::get().public_url())
+}
+pub fn get_change_email_url(token: &str) -> String {
+    format!("{}/change-email/{token}", config::get().public_url())
+}
+pub fn get_login_url() -> String {
+    format!("{}/login", config::get().public_url())
 }
@@ -0,0 +1,262 @@
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::{info, trace, warn};
+use tokio::sync::Notify;
+use crate::config;
+use crate::consts::{EMAIL_MAX_ATTEMPTS, EMAIL_RETRY_BASE_DELAY, EMAIL_RETRY_MAX_DELAY, OUTBOX_POLL_INTERVAL};
+use crate::database;
+use crate::database::email::Email;
+
+/// How the connection to the SMTP relay is secured
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Security {
+    /// Plain connection upgraded with STARTTLS, port 587 by default
+    StartTls,
+    /// Implicit TLS, port 465 by default
+    Tls,
+    /// No encryption at all, only meant for a local relay
+    None,
+}
+
+/// SMTP relay configuration
+#[derive(Clone, Debug)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: Option<u16>,
+    pub security: Security,
+    pub credentials: Option<(String, String)>,
+    pub from: String,
+}
+
+impl SmtpConfig {
+    /// Relay of the configuration, None if no host is set : the emails then stay in the outbox
+    pub fn from_config(smtp: &config::Smtp) -> Option<Self> {
+        Some(Self {
+            host: smtp.host.clone()?,
+            port: smtp.port,
+            security: match smtp.security {
+                config::SmtpSecurity::StartTls => Security::StartTls,
+                config::SmtpSecurity::Tls => Security::Tls,
+                config::SmtpSecurity::None => Security::None,
+            },
+            credentials: smtp.username.clone().zip(smtp.password.clone()),
+            from: smtp.from.clone(),
+        })
+    }
+}
+
+/// Connection to the SMTP relay
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl Mailer {
+    pub fn new(config: &SmtpConfig) -> Result<Self> {
+        let mut builder = match config.security {
+            Security::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?,
+            Security::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+            Security::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+        };
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let Some((username, password)) = &config.credentials {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: config.from.parse().or(Err(anyhow!("Invalid smtp.from")))?,
+        })
+    }
+
+    /// Send an email, as multipart/alternative if it has an HTML part
+    pub async fn send(&self, email: &Email) -> Result<()> {
+        let builder = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse()?)
+            .subject(&email.subject);
+
+        let message = match &email.html {
+            Some(html) => builder.multipart(MultiPart::alternative_plain_html(email.body.clone(), html.clone()))?,
+            None => builder.body(email.body.clone())?,
+        };
+
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+/// Delay before retrying an email after its n-th failed attempt
+pub fn backoff(attempts: u32) -> u64 {
+    EMAIL_RETRY_BASE_DELAY
+        .saturating_mul(1 << attempts.saturating_sub(1).min(31))
+        .min(EMAIL_RETRY_MAX_DELAY)
+}
+
+static WAKE: Notify = Notify::const_new();
+
+/// Wake the worker up, called when an email is added to the outbox
+pub fn wake() {
+    WAKE.notify_one();
+}
+
+/// Send the due emails of the outbox, retrying the failed ones later
+async fn drain(mailer: &Mailer) -> Result<()> {
+    let now = jsonwebtoken::get_current_timestamp();
+
+    for email in database::email::due(now)? {
+        match mailer.send(&email).await {
+            Ok(()) => {
+                trace!("Email sent");
+                database::email::mark_sent(email.pk)?;
+            },
+            Err(e) => {
+                warn!("Failed to send an email : {e}");
+                let attempts = email.attempts + 1;
+                let retry_at = (attempts < EMAIL_MAX_ATTEMPTS).then(|| now + backoff(attempts));
+                database::email::mark_failed(email.pk, &e.to_string(), retry_at)?;
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Background task delivering the outbox through the SMTP relay
+pub async fn run(config: SmtpConfig) {
+    info!("Start the outbox worker, relay {}", config.host);
+
+    let mailer = match Mailer::new(&config) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!("Invalid SMTP configuration, the outbox won't be delivered : {e}");
+            return;
+        },
+    };
+
+    loop {
+        if let Err(e) = drain(&mailer).await {
+            warn!("Failed to drain the outbox : {e}");
+        }
+
+        // Wait for a new email, or for the retries to be due
+        tokio::select! {
+            _ = WAKE.notified() => {},
+            _ = tokio::time::sleep(Duration::from_secs(OUTBOX_POLL_INTERVAL)) => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use crate::consts::EMAIL_FROM;
+    use crate::database::email::Status;
+
+    /// Minimal SMTP server accepting a single message, returns the received DATA
+    /// If `reject` is set, every recipient is refused
+    async fn fake_smtp(reject: bool) -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            write.write_all(b"220 fake ESMTP\r\n").await.unwrap();
+
+            let mut data = String::new();
+            let mut in_data = false;
+            while let Ok(Some(line)) = lines.next_line().await {
+                if in_data {
+                    if line == "." {
+                        write.write_all(b"250 Queued\r\n").await.unwrap();
+                        break;
+                    }
+                    data.push_str(&line);
+                    data.push('\n');
+                    continue;
+                }
+
+                let command = line.get(..4).unwrap_or_default().to_ascii_uppercase();
+                let reply: &[u8] = match command.as_str() {
+                    "EHLO" | "HELO" => b"250 fake\r\n",
+                    "RCPT" if reject => b"550 No such user\r\n",
+                    "DATA" => {
+                        in_data = true;
+                        b"354 Go ahead\r\n"
+                    },
+                    "QUIT" => b"221 Bye\r\n",
+                    _ => b"250 OK\r\n",
+                };
+                write.write_all(reply).await.unwrap();
+            }
+            data
+        });
+
+        (port, handle)
+    }
+
+    fn mailer(port: u16) -> Mailer {
+        Mailer::new(&SmtpConfig {
+            host: "127.0.0.1".into(),
+            port: Some(port),
+            security: Security::None,
+            credentials: None,
+            from: EMAIL_FROM.into(),
+        }).unwrap()
+    }
+
+    fn email() -> Email {
+        Email {
+            pk: 0,
+            to: "unit@test.com".into(),
+            subject: "Confirm your account".into(),
+            body: "Click on the following link".into(),
+            html: Some("<a href=\"#\">Click here</a>".into()),
+            status: Status::Pending,
+            attempts: 0,
+            next_attempt: 0,
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    pub async fn smtp_send_test() {
+        let (port, server) = fake_smtp(false).await;
+        mailer(port).send(&email()).await.unwrap();
+
+        let data = server.await.unwrap();
+        assert!(data.contains("To: unit@test.com"));
+        assert!(data.contains("Subject: Confirm your account"));
+        assert!(data.contains("multipart/alternative"));
+        assert!(data.contains("Click on the following link"));
+        assert!(data.contains("Click here"));
+    }
+
+    #[tokio::test]
+    pub async fn smtp_reject_test() {
+        let (port, _server) = fake_smtp(true).await;
+        assert!(mailer(port).send(&email()).await.is_err());
+    }
+
+    #[rstest(
+    attempts,
+    expected,
+    case(1, EMAIL_RETRY_BASE_DELAY),
+    case(2, EMAIL_RETRY_BASE_DELAY * 2),
+    case(3, EMAIL_RETRY_BASE_DELAY * 4),
+    case(100, EMAIL_RETRY_MAX_DELAY),
+    )]
+    pub fn backoff_test(attempts: u32, expected: u64) {
+        assert_eq!(backoff(attempts), expected);
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::Result;
+use http::header::ACCEPT_LANGUAGE;
+use http::HeaderMap;
+use log::{debug, trace};
+use serde_json::Value;
+use crate::consts::EMAIL_LOCALES;
+use crate::HBS;
+
+/// Email rendered from its templates
+pub struct Rendered {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// Render the subject, text and HTML parts of an email
+/// Templates are `templates/emails/<name>.<locale>.(subject|txt|html).hbs`,
+/// the default locale is used if the template has no variant for the requested one
+pub fn render(name: &str, locale: &str, data: &Value) -> Result<Rendered> {
+    let locale = if HBS.has_template(&format!("emails/{name}.{locale}.subject")) {
+        locale
+    } else {
+        debug!("No {locale} variant for the {name} email");
+        EMAIL_LOCALES[0]
+    };
+    trace!("Render {name} email in {locale}");
+
+    let part = |part: &str| HBS.render(&format!("emails/{name}.{locale}.{part}"), data);
+
+    Ok(Rendered {
+        // Subjects are single line, even if the template ends with a line break
+        subject: part("subject")?.trim().to_string(),
+        text: part("txt")?,
+        html: part("html")?,
+    })
+}
+
+/// Pick the preferred locale of the client among the ones having templates
+/// The Accept-Language header is ordered by weight (q), the default locale is used if nothing matches
+pub fn negotiate_locale(accept_language: Option<&str>) -> &'static str {
+    let mut languages: Vec<(&str, f32)> = accept_language.unwrap_or_default()
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next()?.trim();
+            let weight = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse().ok())?;
+            Some((tag, weight))
+        })
+        .collect();
+
+    // Stable sort, the order of the header breaks the ties
+    languages.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    languages.iter()
+        .filter(|(_, weight)| *weight > 0.0)
+        .find_map(|(tag, _)| {
+            let primary = tag.split('-').next().unwrap_or_default();
+            EMAIL_LOCALES.iter().find(|l| l.eq_ignore_ascii_case(primary))
+        })
+        .copied()
+        .unwrap_or(EMAIL_LOCALES[0])
+}
+
+/// Locale of the emails sent while handling a request
+pub fn locale(headers: &HeaderMap) -> &'static str {
+    negotiate_locale(headers.get(ACCEPT_LANGUAGE).and_then(|h| h.to_str().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use serde_json::json;
+
+    #[rstest(
+    header,
+    expected,
+    case(None, "en"),
+    case(Some("fr"), "fr"),
+    case(Some("fr-CH, fr;q=0.9, en;q=0.8"), "fr"),
+    case(Some("de-DE, en;q=0.5, fr;q=0.7"), "fr"),
+    case(Some("fr;q=0, en"), "en"),
+    case(Some("de, it"), "en"),
+    case(Some("*"), "en"),
+    )]
+    pub fn negotiate_locale_test(header: Option<&str>, expected: &str) {
+        assert_eq!(negotiate_locale(header), expected);
+    }
+
+    #[rstest(
+    name,
+    case("verify"),
+    case("reset"),
+    case("password_changed"),
+    case("totp_enabled"),
+    case("account_locked"),
+    case("passkey_added"),
+    case("identity_linked"),
+    case("email_change"),
+    case("email_change_requested"),
+    case("email_changed"),
+    )]
+    pub fn render_test(name: &str) {
+        let data = json!({"email": "unit@test.com", "name": "Laptop", "provider": "Google", "minutes": 15, "new_email": "new@test.com", "link": "http://localhost/link?a=1&b=2"});
+        for locale in EMAIL_LOCALES {
+            let email = render(name, locale, &data).unwrap();
+            assert!(!email.subject.is_empty() && !email.subject.contains('\n'));
+            assert!(!email.text.is_empty());
+            assert!(email.html.contains("<html"));
+        }
+    }
+
+    #[rstest]
+    pub fn render_link_test() {
+        let data = json!({"link": "http://localhost/link?a=1&b=2"});
+        let email = render("verify", "en", &data).unwrap();
+
+        // Only escaped in the HTML part
+        assert!(email.text.contains("http://localhost/link?a=1&b=2"));
+        assert!(email.html.contains("&amp;b"));
+    }
+
+    #[rstest]
+    pub fn render_fallback_test() {
+        let data = json!({"link": "http://localhost/link"});
+        let email = render("verify", "de", &data).unwrap();
+        assert_eq!(email.subject, render("verify", "en", &data).unwrap().subject);
+    }
+}
@@ -1,15 +1,21 @@
+mod audit;
 mod backend;
+mod config;
 mod database;
 mod utils;
 mod email;
 mod consts;
+mod metrics;
 
 use std::net::SocketAddr;
+use std::time::Duration;
 use dotenv::dotenv;
 use handlebars::Handlebars;
-use log::info;
+use log::{error, info, warn};
 use once_cell::sync::Lazy;
-use crate::consts::HTTP_PORT;
+use clap::Parser;
+use tower_sessions::session_store::ExpiredDeletion;
+use crate::consts::SESSION_SWEEP_INTERVAL;
 use crate::utils::crypto::default_hash;
 
 static HBS: Lazy<Handlebars> = Lazy::new(|| {
@@ -23,24 +29,92 @@ static HBS: Lazy<Handlebars> = Lazy::new(|| {
 #[tokio::main]
 async fn main() {
 
-    // Init env vars and default hash
+    // Init env vars
     dotenv().ok();
-    default_hash();
 
     env_logger::builder()
         .filter_level(log::LevelFilter::Trace)
         .init();
 
-    // Reload DB from files
-    database::user::load().ok();
-    database::token::load().ok();
-    database::email::load().ok();
+    // Settings from the configuration file, the env vars and the command line, the hashing cost is needed by the default hash
+    let config = config::Config::load(config::Args::parse()).expect("Invalid configuration");
+    config::init(config).expect("Failed to set configuration");
+    default_hash();
+
+    let config = config::get();
+
+    // Load the keys signing the access JWTs
+    let keys_dir = config.tokens.access_keys.to_string_lossy().to_string();
+    utils::jwt::load_keyring(&keys_dir).expect("Failed to load access JWT keys");
+
+    // Admin command to rotate the keys without restarting the server : kill -USR1 <pid>
+    tokio::spawn(rotate_keys_on_signal(keys_dir));
+
+    // Open the storage of everything the server keeps, a damaged database stops the startup unless told otherwise
+    let storage = &config.storage;
+    let (backend, startup) = (storage.backend().expect("Invalid backend"), storage.startup().expect("Invalid startup"));
+    database::store::init(&backend, startup).expect("Failed to open storage");
+    audit::init(&storage.audit_log.to_string_lossy()).expect("Failed to open audit log");
+
+    // Accounts granted the admin role at startup
+    for email in &config.server.admin_emails {
+        if !database::user::exists(email).expect("Failed to read users") {
+            warn!("Admin account {email} not registered yet, restart once it is");
+            continue;
+        }
+        database::user::set_role(email, database::user::UserRole::Admin).expect("Failed to grant the admin role");
+    }
+
+    // Browser sessions, signed with the session secret and swept once expired
+    backend::session::init(config.session.secret.clone()).expect("Failed to set session key");
+    tokio::spawn(backend::session::DbStore.continuously_delete_expired(Duration::from_secs(SESSION_SWEEP_INTERVAL)));
+
+    // Deliver the outbox through the SMTP relay, if any
+    match email::outbox::SmtpConfig::from_config(&config.smtp) {
+        Some(smtp) => { tokio::spawn(email::outbox::run(smtp)); },
+        None => warn!("smtp.host not set, emails are only kept in the outbox"),
+    }
+
+    // External identity providers the users can sign in with
+    utils::oidc::init(utils::oidc::Provider::from_config(&config.oidc)).expect("Failed to set OIDC providers");
+
+    // Bearer token the monitoring stack sends to read /metrics, they are public if unset
+    metrics::init(config.server.metrics_token.clone()).expect("Failed to set metrics token");
 
     // Setup the endpoints
     let app = backend::router::get_router();
 
     // Start web server
-    let addr = SocketAddr::from(([0, 0, 0, 0], HTTP_PORT));
+    let addr = config.listen_address();
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+
+    if let (Some(cert), Some(key)) = (&config.tls.cert, &config.tls.key) {
+        let tls = backend::tls::load(cert, key).await.expect("Failed to load TLS certificate");
+
+        // Admin command to renew the certificate without restarting the server : kill -HUP <pid>
+        tokio::spawn(backend::tls::reload_on_signal(tls.clone(), cert.clone(), key.clone()));
+
+        if let Some(port) = config.tls.redirect_port {
+            let redirect_addr = SocketAddr::new(config.server.bind_address, port);
+            let listener = tokio::net::TcpListener::bind(redirect_addr)
+                .await
+                .expect("Failed to open HTTP redirect listener");
+            info!("Redirect http://{redirect_addr} to HTTPS");
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, backend::tls::redirect_router()).await {
+                    error!("HTTP redirect listener stopped : {e}");
+                }
+            });
+        }
+
+        info!("listening on {} with TLS", addr);
+        axum_server::bind_rustls(addr, tls)
+            .serve(app)
+            .await
+            .expect("Failed to bind Axum to listener");
+        return;
+    }
+
     info!("listening on {}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr)
@@ -52,3 +126,23 @@ async fn main() {
         .await
         .expect("Failed to bind Axum to listener");
 }
+
+/// Rotate the access JWT keys each time the process receives SIGUSR1
+async fn rotate_keys_on_signal(keys_dir: String) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signal = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Can't listen to SIGUSR1, key rotation disabled : {e}");
+            return;
+        }
+    };
+
+    while signal.recv().await.is_some() {
+        match utils::jwt::rotate_keyring(&keys_dir) {
+            Ok(()) => info!("Access JWT keys rotated"),
+            Err(e) => error!("Failed to rotate access JWT keys : {e}"),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::time::Instant;
+use anyhow::Result;
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use http::{header, HeaderMap};
+use log::{info, trace};
+use once_cell::sync::{Lazy, OnceCell};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder};
+use crate::utils::jwt;
+
+static REGISTRY: Lazy<Registry> = Lazy::new(|| Registry::new_custom(Some("king_auth".to_string()), None)
+    .expect("Invalid metrics prefix"));
+
+/// Register a metric the first time it is used, a failure is a duplicated name
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY.register(Box::new(metric.clone())).expect("Failed to register metric");
+    metric
+}
+
+pub static REGISTRATIONS: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("registrations_total", "Accounts created").expect("Invalid metric")
+));
+
+/// Labels : outcome (success, failure)
+pub static VERIFICATIONS: Lazy<IntCounterVec> = Lazy::new(|| register(
+    IntCounterVec::new(Opts::new("verifications_total", "Email verification links followed"), &["outcome"])
+        .expect("Invalid metric")
+));
+
+/// Labels : method (password, passkey, totp, oidc), outcome (success, failure)
+pub static LOGINS: Lazy<IntCounterVec> = Lazy::new(|| register(
+    IntCounterVec::new(Opts::new("logins_total", "Login attempts"), &["method", "outcome"])
+        .expect("Invalid metric")
+));
+
+/// Labels : role (access, refresh)
+pub static TOKENS_ISSUED: Lazy<IntCounterVec> = Lazy::new(|| register(
+    IntCounterVec::new(Opts::new("tokens_issued_total", "JWTs issued"), &["role"])
+        .expect("Invalid metric")
+));
+
+/// Labels : endpoint (the path pattern)
+pub static CSRF_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| register(
+    IntCounterVec::new(Opts::new("csrf_rejections_total", "Requests refused by the anti-CSRF check"), &["endpoint"])
+        .expect("Invalid metric")
+));
+
+/// Labels : method, route (the path pattern, not the actual path), status
+pub static REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| register(
+    HistogramVec::new(
+        HistogramOpts::new("http_request_duration_seconds", "Time to handle a request"),
+        &["method", "route", "status"],
+    ).expect("Invalid metric")
+));
+
+/// Labels : table (SQLite table, or file without its extension)
+/// The buckets are shorter than the default ones, a save is a synced write
+pub static DB_SAVE_DURATION: Lazy<HistogramVec> = Lazy::new(|| register(
+    HistogramVec::new(
+        HistogramOpts::new("db_save_duration_seconds", "Time to persist a change to the database")
+            .buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]),
+        &["table"],
+    ).expect("Invalid metric")
+));
+
+static TOKEN: OnceCell<Option<String>> = OnceCell::new();
+
+/// Set the bearer token required to read the metrics, none means they are public
+/// Must be called once at startup
+pub fn init(token: Option<String>) -> Result<()> {
+    info!("Metrics {}", if token.is_some() { "protected by a token" } else { "public" });
+    TOKEN.set(token).or(Err(anyhow::anyhow!("Metrics already initialized")))
+}
+
+/// Check the bearer token of a scraper, if one is required
+pub fn authorized(headers: &HeaderMap) -> bool {
+    match TOKEN.get().and_then(Option::as_ref) {
+        None => true,
+        Some(token) => headers.get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .is_some_and(|given| ring::constant_time::verify_slices_are_equal(given.as_bytes(), token.as_bytes()).is_ok()),
+    }
+}
+
+/// Every metric in the Prometheus text format
+pub fn render() -> Result<String> {
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+pub fn login(method: &str, success: bool) {
+    LOGINS.with_label_values(&[method, outcome(success)]).inc();
+}
+
+pub fn verification(success: bool) {
+    VERIFICATIONS.with_label_values(&[outcome(success)]).inc();
+}
+
+pub fn token_issued(role: &jwt::Role) {
+    let role = match role {
+        jwt::Role::Access => "access",
+        jwt::Role::Refresh => "refresh",
+    };
+    TOKENS_ISSUED.with_label_values(&[role]).inc();
+}
+
+fn outcome(success: bool) -> &'static str {
+    if success { "success" } else { "failure" }
+}
+
+/// Middleware measuring the time to handle each request
+/// Only matched routes are measured, to keep the number of label values bounded
+pub async fn track(request: Request, next: Next) -> Response {
+    let route = match request.extensions().get::<MatchedPath>() {
+        Some(path) => path.as_str().to_string(),
+        None => return next.run(request).await,
+    };
+    let method = request.method().to_string();
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    trace!("{method} {route} handled in {elapsed}s");
+    REQUEST_DURATION
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .observe(elapsed);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    pub fn render_test() {
+        REGISTRATIONS.inc();
+        login("password", false);
+        token_issued(&jwt::Role::Refresh);
+
+        let text = render().unwrap();
+        assert!(text.contains("king_auth_registrations_total"));
+        assert!(text.contains("king_auth_logins_total{method=\"password\",outcome=\"failure\"}"));
+        assert!(text.contains("king_auth_tokens_issued_total{role=\"refresh\"}"));
+    }
+}
@@ -1,3 +1,10 @@
 pub mod jwt;
 pub mod crypto;
-pub mod input_val;
\ No newline at end of file
+pub mod input_val;
+pub mod totp;
+pub mod rate_limit;
+pub mod webauthn;
+pub mod oauth;
+pub mod oidc;
+pub mod proxy;
+pub mod device;
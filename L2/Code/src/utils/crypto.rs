@@ -2,17 +2,27 @@ use argon2::{password_hash::{
     rand_core::OsRng,
     PasswordHash, PasswordHasher, PasswordVerifier, SaltString
 }, Argon2, Algorithm, Version, Params};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use lazy_static::lazy_static;
+use ring::hmac;
+use crate::config;
 
 pub fn hash_password(password: &str) -> Result<String,  argon2::password_hash::Error> {
     let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(64 * 1024, 3, 1, Some(32)).unwrap());
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params());
     let password_hash = argon2.hash_password(password.as_bytes(), &salt)?;
     Ok(password_hash.to_string())
 }
 
+/// Argon2 cost configured, checked at startup
+fn params() -> Params {
+    config::get().argon2.params().unwrap_or_default()
+}
+
 pub fn verify_password(password: &str, hash: &str) -> bool {
-    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(64 * 1024, 3, 1, Some(32)).unwrap());
+    // The cost is read from the hash itself, older hashes keep working after a change
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params());
     let password_hash = match PasswordHash::new(hash) {
         Ok(h) => h,
         Err(_) => return false,
@@ -28,6 +38,21 @@ pub fn default_hash() -> String {
     DEFAULT_HASH.to_string()
 }
 
+/// Append an HMAC of the cookie name and value to the value : `<value>.<tag>`
+/// The name is signed too, so that a value can't be moved to another cookie
+pub fn sign_cookie(key: &hmac::Key, name: &str, value: &str) -> String {
+    let tag = hmac::sign(key, format!("{name}={value}").as_bytes());
+    format!("{value}.{}", URL_SAFE_NO_PAD.encode(tag.as_ref()))
+}
+
+/// Returns the value of a signed cookie, None if it has been tampered with
+pub fn verify_cookie<'a>(key: &hmac::Key, name: &str, signed: &'a str) -> Option<&'a str> {
+    let (value, tag) = signed.rsplit_once('.')?;
+    let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+    hmac::verify(key, format!("{name}={value}").as_bytes(), &tag).ok()?;
+    Some(value)
+}
+
 #[cfg(test)]
 mod crypto_tests {
     use super::*;
@@ -54,4 +79,16 @@ mod crypto_tests {
         let wrong_password : &str = "ThisIsNotTheGoodPassword";
         assert!(!verify_password(wrong_password, &hashed_password));
     }
+
+    #[rstest]
+    fn test_signed_cookie() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let signed = sign_cookie(&key, "id", "a1b2-c3");
+
+        assert_eq!(verify_cookie(&key, "id", &signed), Some("a1b2-c3"));
+        assert_eq!(verify_cookie(&key, "other", &signed), None);
+        assert_eq!(verify_cookie(&key, "id", &signed.replace("a1b2", "a1b3")), None);
+        assert_eq!(verify_cookie(&key, "id", "a1b2-c3"), None);
+        assert_eq!(verify_cookie(&hmac::Key::new(hmac::HMAC_SHA256, b"other"), "id", &signed), None);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,56 @@
+/// Short name of the device behind a user agent, such as "Firefox on Linux"
+/// Only the common browsers are recognized, other clients are named after their first product token
+pub fn label(user_agent: &str) -> String {
+    let user_agent = user_agent.trim();
+    if user_agent.is_empty() {
+        return "Unknown device".to_string();
+    }
+
+    // Most browsers also claim to be the ones they derive from, the most specific token wins
+    let browser = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("CriOS/", "Chrome"),
+        ("Safari/", "Safari"),
+    ].into_iter().find(|(token, _)| user_agent.contains(token)).map(|(_, name)| name);
+
+    // iOS and Android also claim to be macOS and Linux
+    let os = [
+        ("Windows", "Windows"),
+        ("Android", "Android"),
+        ("iPhone", "iOS"),
+        ("iPad", "iPadOS"),
+        ("CrOS", "ChromeOS"),
+        ("Mac OS X", "macOS"),
+        ("Linux", "Linux"),
+    ].into_iter().find(|(token, _)| user_agent.contains(token)).map(|(_, name)| name);
+
+    match (browser, os) {
+        (Some(browser), Some(os)) => format!("{browser} on {os}"),
+        (Some(browser), None) => browser.to_string(),
+        _ => user_agent.split(['/', ' ']).next().unwrap_or(user_agent).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+    user_agent,
+    expected,
+    case("Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0", "Firefox on Linux"),
+    case("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36 Edg/120.0.0.0", "Edge on Windows"),
+    case("Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Mobile Safari/537.36", "Chrome on Android"),
+    case("Mozilla/5.0 (iPhone; CPU iPhone OS 17_2 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.2 Mobile/15E148 Safari/604.1", "Safari on iOS"),
+    case("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.2 Safari/605.1.15", "Safari on macOS"),
+    case("curl/8.5.0", "curl"),
+    case("  ", "Unknown device"),
+    )]
+    fn label_test(user_agent: &str, expected: &str) {
+        assert_eq!(label(user_agent), expected);
+    }
+}
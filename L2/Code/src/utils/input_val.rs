@@ -1,17 +1,19 @@
 use once_cell::sync::Lazy;
 use regex::Regex;
 use zxcvbn::zxcvbn;
-use crate::consts::{MAIL_REGEX, MAX_PASSWORD_LENGTH, MIN_PASSWORD_LENGTH, ZXCVBN_THRESHOLD};
+use crate::config;
+use crate::consts::MAIL_REGEX;
 
-pub fn is_password_valid(password : &String) -> bool {
-    if password.len() < MIN_PASSWORD_LENGTH || password.len() > MAX_PASSWORD_LENGTH {
+pub fn is_password_valid(password : &str) -> bool {
+    let policy = &config::get().password;
+    if password.len() < policy.min_length || password.len() > policy.max_length {
         return false;
     }
-    let estimate = zxcvbn(&password, &[]).unwrap();
-    return estimate.score() >= ZXCVBN_THRESHOLD
+    let estimate = zxcvbn(password, &[]).unwrap();
+    estimate.score() >= policy.min_strength
 }
 
-pub fn is_email_valid(email : &String) -> bool {
+pub fn is_email_valid(email : &str) -> bool {
     static RE: Lazy<Regex> = Lazy::new(|| Regex::new(MAIL_REGEX).unwrap());
     RE.is_match(email)
 }
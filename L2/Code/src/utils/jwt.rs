@@ -1,8 +1,24 @@
-use anyhow::anyhow;
-use jsonwebtoken::{Algorithm, decode, DecodingKey, encode, EncodingKey, Header, Validation};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use jsonwebtoken::{Algorithm, decode, decode_header, DecodingKey, encode, EncodingKey, Header, Validation};
 use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
 use serde::{Deserialize, Serialize};
-use crate::consts::{ACCESS_TOKEN_DURATION, REFRESH_TOKEN_DURATION};
+use uuid::Uuid;
+use crate::database::user::UserRole;
+use crate::config;
+use crate::consts::{KEYRING_SIZE, OAUTH_TOKEN_DURATION};
+use crate::metrics;
+use crate::utils::oauth;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum Role {
@@ -11,76 +27,376 @@ pub enum Role {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct SubClaims {
-    email: String,
-    role: String,
+pub struct Claims {
+    pub exp: usize,
+    pub iat: usize,
+    nbf: usize,
+    pub jti: String, // Unique id of the JWT, used to revoke it
+    pub iss: String,
+    pub aud: String,
+    pub sub: String,
+    role: Role,
+    pub fam: String, // Family of refresh tokens the JWT belongs to, one family per login
+    pub gen: u64, // Generation of the refresh token in its family
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<UserRole>, // Roles of the user, only in access JWTs (RFC 9068 7.2.1.1)
 }
 
+/// Claims of the ID token issued to an OpenID Connect client
 #[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    exp: usize,
-    iat: usize,
-    nbf: usize,
-    sub: String,
-    role: Role,
+pub struct IdClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String, // Client id
+    pub exp: usize,
+    pub iat: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+}
+
+/// Claims of the access token issued to an OAuth client (RFC 9068), only accepted by /userinfo
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OAuthClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub client_id: String,
+    pub exp: usize,
+    pub iat: usize,
+    pub jti: String,
+    pub scope: String, // Space separated
+}
+
+// Media type of the OAuth access tokens, an ID token or a session JWT can't be used in their place
+const OAUTH_ACCESS_TYPE: &str = "at+jwt";
+
+// Media type of the session access JWTs, signed with the same keys as the ID tokens and the OAuth access tokens
+const SESSION_ACCESS_TYPE: &str = "session+jwt";
+
+/// Audience of the session JWTs : the services trusting the logins of this server
+fn session_audience(issuer: &str) -> String {
+    format!("{issuer}/session")
 }
 
-pub fn create<T: Into<String>>(payload: T, role: Role) -> anyhow::Result<String> {
+/// Ed25519 key pair signing the access JWTs
+/// Other services verify the access JWTs with the public key published in the JWKS
+pub struct SigningKey {
+    kid: String,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    public: Vec<u8>,
+}
+
+impl SigningKey {
+    /// Build the key pair from a PKCS#8 document
+    pub fn from_pkcs8(der: &[u8]) -> anyhow::Result<Self> {
+        let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der).or(Err(anyhow!("Invalid Ed25519 key")))?;
+        let public = pair.public_key().as_ref().to_vec();
+
+        Ok(Self {
+            kid: thumbprint(&public),
+            encoding: EncodingKey::from_ed_der(der),
+            decoding: DecodingKey::from_ed_der(&public),
+            public,
+        })
+    }
+
+    /// Generate a new random key pair, returned with its PKCS#8 document to save it
+    pub fn generate() -> anyhow::Result<(Self, Vec<u8>)> {
+        let der = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).or(Err(anyhow!("Failed to generate key")))?;
+        Ok((Self::from_pkcs8(der.as_ref())?, der.as_ref().to_vec()))
+    }
+
+    /// Public part of the key, in the JWK format
+    pub fn to_jwk(&self) -> Jwk {
+        Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(KeyAlgorithm::EdDSA),
+                key_id: Some(self.kid.clone()),
+                ..Default::default()
+            },
+            algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(&self.public),
+            }),
+        }
+    }
+}
+
+/// Key id : JWK thumbprint of the public key (RFC 7638)
+fn thumbprint(public: &[u8]) -> String {
+    let jwk = format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, URL_SAFE_NO_PAD.encode(public));
+    URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, jwk.as_bytes()))
+}
+
+/// Keys signing the access JWTs, newest first
+/// JWTs are signed with the newest key, the previous ones only verify the JWTs issued before a rotation
+pub struct Keyring {
+    keys: Vec<SigningKey>,
+    size: usize,
+}
+
+impl Keyring {
+    pub fn new(size: usize) -> Self {
+        Self { keys: vec![], size: size.max(1) }
+    }
+
+    /// Key signing the new JWTs
+    pub fn current(&self) -> Option<&SigningKey> {
+        self.keys.first()
+    }
+
+    /// Key verifying a JWT, selected by the kid of its header
+    pub fn find(&self, kid: &str) -> Option<&SigningKey> {
+        self.keys.iter().find(|k| k.kid == kid)
+    }
+
+    /// Add a new current key, the oldest key is dropped if the keyring is full
+    pub fn push(&mut self, key: SigningKey) {
+        self.keys.insert(0, key);
+        self.keys.truncate(self.size);
+    }
+}
+
+static KEYRING: Lazy<RwLock<Keyring>> = Lazy::new(|| RwLock::new(Keyring::new(KEYRING_SIZE)));
+
+/// Load the keys signing the access JWTs from a directory of PEM files (PKCS#8)
+/// Files are named after their creation date, the newest one is the current key
+/// If the directory is empty, a first key is generated
+pub fn load_keyring<P: AsRef<Path>>(dir: P) -> anyhow::Result<()> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+
+    let mut keyring = Keyring::new(KEYRING_SIZE);
+    for path in key_files(dir)?.iter().take(KEYRING_SIZE).rev() {
+        info!("Loading access JWT key {}", path.display());
+        let pem = pem::parse(fs::read(path)?).with_context(|| format!("Invalid PEM file {}", path.display()))?;
+        keyring.push(SigningKey::from_pkcs8(pem.contents())?);
+    }
+
+    if keyring.current().is_none() {
+        warn!("No access JWT key found, generating one in {}", dir.display());
+        keyring.push(generate_key_file(dir)?);
+    }
+
+    *KEYRING.write().or(Err(anyhow!("Keyring poisoned")))? = keyring;
+    Ok(())
+}
+
+/// Rotate the access JWT keys : generate a new current key, and forget the oldest one
+/// JWTs signed with the previous keys stay valid until they expire (or their key is dropped)
+pub fn rotate_keyring<P: AsRef<Path>>(dir: P) -> anyhow::Result<()> {
+    let dir = dir.as_ref();
+    info!("Rotate access JWT keys");
+
+    let key = generate_key_file(dir)?;
+    KEYRING.write().or(Err(anyhow!("Keyring poisoned")))?.push(key);
+
+    // Keys dropped from the keyring are useless, remove their file
+    for path in key_files(dir)?.iter().skip(KEYRING_SIZE) {
+        info!("Remove retired access JWT key {}", path.display());
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+/// PEM files of a key directory, newest first
+fn key_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "pem"))
+        .collect();
+    files.sort_by(|a, b| b.cmp(a));
+    Ok(files)
+}
+
+/// Generate a new key and save it in the key directory
+fn generate_key_file(dir: &Path) -> anyhow::Result<SigningKey> {
+    let (key, der) = SigningKey::generate()?;
+    let now = time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000;
+    write_private_key(&dir.join(format!("{now:015}.pem")), &der)?;
+    Ok(key)
+}
+
+/// Save a private key as PEM, only readable by the owner
+fn write_private_key(path: &Path, der: &[u8]) -> anyhow::Result<()> {
+    let pem = pem::encode(&pem::Pem::new("PRIVATE KEY", der));
+
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+        fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?.write_all(pem.as_bytes())?;
+    }
+    #[cfg(not(unix))]
+    fs::write(path, pem)?;
+
+    Ok(())
+}
+
+/// Public keys verifying the access JWTs, served as JWKS
+pub fn jwks() -> anyhow::Result<JwkSet> {
+    let keyring = KEYRING.read().or(Err(anyhow!("Keyring poisoned")))?;
+    Ok(JwkSet { keys: keyring.keys.iter().map(SigningKey::to_jwk).collect() })
+}
+
+/// Sign claims with the current key of the keyring (EdDSA)
+fn sign<T: Serialize>(claims: &T, typ: Option<&str>) -> anyhow::Result<String> {
+    let keyring = KEYRING.read().or(Err(anyhow!("Keyring poisoned")))?;
+    let key = keyring.current().ok_or(anyhow!("No access key loaded"))?;
+
+    let mut header = Header::new(Algorithm::EdDSA);
+    header.kid = Some(key.kid.clone());
+    if let Some(typ) = typ {
+        header.typ = Some(typ.to_string());
+    }
+    Ok(encode(&header, claims, &key.encoding)?)
+}
+
+/// Create the ID token of an OpenID Connect client
+/// `subject` is the stable identifier of the user, the email can change
+/// `nonce` is the one sent by the client to /authorize, it binds the ID token to the client session
+pub fn create_id_token(subject: &Uuid, email: &str, client_id: &str, nonce: Option<&str>, with_email: bool) -> anyhow::Result<String> {
+    let current_time = jsonwebtoken::get_current_timestamp() as usize;
+
+    sign(&IdClaims {
+        iss: oauth::issuer(),
+        sub: subject.to_string(),
+        aud: client_id.to_string(),
+        exp: current_time + OAUTH_TOKEN_DURATION,
+        iat: current_time,
+        nonce: nonce.map(String::from),
+        email: with_email.then(|| email.to_string()),
+        email_verified: with_email.then_some(true), // Only verified users can log in
+    }, None)
+}
+
+/// Create the access token of an OAuth client, used to call /userinfo
+pub fn create_oauth_access(email: &str, client_id: &str, scope: &str) -> anyhow::Result<String> {
+    let current_time = jsonwebtoken::get_current_timestamp() as usize;
+    let issuer = oauth::issuer();
+
+    sign(&OAuthClaims {
+        aud: format!("{issuer}/userinfo"),
+        iss: issuer,
+        sub: email.to_string(),
+        client_id: client_id.to_string(),
+        exp: current_time + OAUTH_TOKEN_DURATION,
+        iat: current_time,
+        jti: Uuid::new_v4().to_string(),
+        scope: scope.to_string(),
+    }, Some(OAUTH_ACCESS_TYPE))
+}
+
+/// Verify the access token of an OAuth client and return its claims
+pub fn verify_oauth_access(token: &str) -> anyhow::Result<OAuthClaims> {
+    let header = decode_header(token)?;
+    if header.typ.as_deref() != Some(OAUTH_ACCESS_TYPE) {
+        return Err(anyhow!("Not an OAuth access token"));
+    }
+    let kid = header.kid.ok_or(anyhow!("Missing kid"))?;
+
+    let issuer = oauth::issuer();
+    let mut validation = Validation::new(Algorithm::EdDSA);
+    validation.set_issuer(&[&issuer]);
+    validation.set_audience(&[format!("{issuer}/userinfo")]);
+
+    let keyring = KEYRING.read().or(Err(anyhow!("Keyring poisoned")))?;
+    let key = keyring.find(&kid).ok_or(anyhow!("Unknown key"))?;
+    Ok(decode::<OAuthClaims>(token, &key.decoding, &validation)?.claims)
+}
+
+/// Create a JWT for the given role
+/// Access JWTs are signed with the current Ed25519 key of the keyring (EdDSA), refresh JWTs are only read by this server (HS256)
+/// `family` and `generation` identify the refresh token (or the refresh token used to get an access token)
+/// `roles` are only embedded in access JWTs, they are read again from the user at each refresh
+pub fn create<T: Into<String>>(payload: T, role: Role, family: &str, generation: u64, roles: &[UserRole]) -> anyhow::Result<String> {
     // Get the current timestamp in seconds
     let current_time : usize = jsonwebtoken::get_current_timestamp() as usize;
 
     // Calculate the expiration time based on the token role
     let expiration_time : usize = match role {
-        Role::Access => current_time + ACCESS_TOKEN_DURATION,
-        Role::Refresh => current_time + REFRESH_TOKEN_DURATION,
-    };
-
-    // Get the secret key based on the token role
-    let secret : String = match role {
-        Role::Access => std::env::var("JWT_SECRET_ACCESS")?,
-        Role::Refresh => std::env::var("JWT_SECRET_REFRESH")?
+        Role::Access => current_time + config::get().tokens.access_duration as usize,
+        Role::Refresh => current_time + config::get().tokens.refresh_duration as usize,
     };
 
-    // Create the JWT header
-    let header = Header::default();
-
     // Create the claims for the JWT
+    let issuer = oauth::issuer();
     let claims = Claims {
         exp: expiration_time,
         iat: current_time,
         nbf: current_time,
+        jti: Uuid::new_v4().to_string(),
+        aud: session_audience(&issuer),
+        iss: issuer,
         sub: payload.into(),
+        fam: family.to_string(),
+        gen: generation,
+        roles: match role {
+            Role::Access => roles.to_vec(),
+            Role::Refresh => vec![],
+        },
         role,
     };
 
-    // Encode the JWT with the header, claims, and secret key
-    let jwt : String = encode(&header, &claims, &EncodingKey::from_secret(secret.as_ref()))?;
-    return Ok(jwt);
+    // Encode the JWT with the header, claims, and the key of the role
+    let jwt : String = match claims.role {
+        Role::Access => sign(&claims, Some(SESSION_ACCESS_TYPE))?,
+        Role::Refresh => {
+            let secret = std::env::var("JWT_SECRET_REFRESH")?;
+            encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_ref()))?
+        },
+    };
+    metrics::token_issued(&claims.role);
+    Ok(jwt)
 }
 
 /// Verify the validity of a JWT accordingly to its role (access or refresh)
-/// Return the email contained in the JWT if it's valid
+/// Return the claims (email, family) contained in the JWT if it's valid
 /// Return an error if the JWT is invalid
-pub fn verify<T: Into<String>>(jwt: T, role: Role) -> anyhow::Result<String> {
-    // Get the secret key based on the token role
-    let secret = match role {
-        Role::Access => std::env::var("JWT_SECRET_ACCESS")?,
-        Role::Refresh => std::env::var("JWT_SECRET_REFRESH")?,
-    };
+pub fn verify<T: Into<String>>(jwt: T, role: Role) -> anyhow::Result<Claims> {
+    // Convert the token into a string
+    let token = jwt.into();
 
     // Create validation rules for JWT
-    let mut validation = Validation::new(Algorithm::HS256);
+    let mut validation = Validation::new(match role {
+        Role::Access => Algorithm::EdDSA,
+        Role::Refresh => Algorithm::HS256,
+    });
     validation.validate_exp = true;
     validation.validate_nbf = true;
+    let issuer = oauth::issuer();
+    validation.set_issuer(&[&issuer]);
+    validation.set_audience(&[session_audience(&issuer)]);
 
-    // Convert the token into a string
-    let token = jwt.into();
-
-    // Attempt to decode and validate the JWT
-    let token_decoding_result = decode::<Claims>(&token, &DecodingKey::from_secret(secret.as_ref()), &validation);
+    // Attempt to decode and validate the JWT with the key of the role
+    let token_decoding_result = match role {
+        Role::Access => {
+            // ID tokens and OAuth access tokens are signed with the same keys
+            let header = decode_header(&token)?;
+            if header.typ.as_deref() != Some(SESSION_ACCESS_TYPE) {
+                return Err(anyhow!("Not a session access JWT"));
+            }
+            let kid = header.kid.ok_or(anyhow!("Missing kid"))?;
+            let keyring = KEYRING.read().or(Err(anyhow!("Keyring poisoned")))?;
+            let key = keyring.find(&kid).ok_or(anyhow!("Unknown key"))?;
+            decode::<Claims>(&token, &key.decoding, &validation)
+        },
+        Role::Refresh => {
+            let secret = std::env::var("JWT_SECRET_REFRESH")?;
+            decode::<Claims>(&token, &DecodingKey::from_secret(secret.as_ref()), &validation)
+        },
+    };
 
     match token_decoding_result {
-        Ok(claims) if claims.claims.role == role => Ok(claims.claims.sub),
+        Ok(claims) if claims.claims.role == role => Ok(claims.claims),
         Err(err) => {
             match *err.kind() {
                 ErrorKind::InvalidToken => Err(anyhow!("Invalid token")),
@@ -99,6 +415,26 @@ mod tests {
     use super::*;
     use rstest::rstest;
     use std::env;
+    use std::sync::Once;
+
+    // All the tests share the same access key
+    fn init_key() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| KEYRING.write().unwrap().push(SigningKey::generate().unwrap().0));
+    }
+
+    // Sign claims with the given key, using the kid of the loaded access key
+    fn sign(claims: &Claims, key: &EncodingKey) -> String {
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.typ = Some(SESSION_ACCESS_TYPE.to_string());
+        header.kid = KEYRING.read().unwrap().current().map(|k| k.kid.clone());
+        encode(&header, claims, key).unwrap()
+    }
+
+    fn sign_with_access_key(claims: &Claims) -> String {
+        let keyring = KEYRING.read().unwrap();
+        sign(claims, &keyring.current().unwrap().encoding)
+    }
 
     #[rstest(
     input,
@@ -111,52 +447,131 @@ mod tests {
     case("unit@test.com", Role::Refresh, Role::Access, false),
     )]
     pub fn create_jwt_access_test(input: &str, role_create: Role, role_verify: Role, expected: bool) {
-        env::set_var("JWT_SECRET_ACCESS", "dummy_access_var");
+        init_key();
         env::set_var("JWT_SECRET_REFRESH", "dummy_refresh_var");
-        let result = create(input, role_create).map(|token| verify(token, role_verify));
+        let result = create(input, role_create, "family", 0, &[]).map(|token| verify(token, role_verify));
         let output = result.map(|res| res.is_ok()).unwrap_or(false);
         assert_eq!(output, expected);
     }
 
+    #[rstest]
+    pub fn roles_test() {
+        init_key();
+        env::set_var("JWT_SECRET_REFRESH", "dummy_refresh_var");
+        let roles = UserRole::Admin.granted();
+
+        let access = create("user@test.com", Role::Access, "family", 0, &roles).unwrap();
+        assert_eq!(verify(access, Role::Access).unwrap().roles, roles);
+
+        // Refresh JWTs never carry roles, the user may have lost them since the login
+        let refresh = create("user@test.com", Role::Refresh, "family", 0, &roles).unwrap();
+        assert!(verify(refresh, Role::Refresh).unwrap().roles.is_empty());
+    }
+
     #[rstest]
     pub fn token_invalid_test() {
-        env::set_var("JWT_SECRET_ACCESS", "dummy_access_var");
-        let mut token = create("user@test.com", Role::Access).unwrap();
+        init_key();
+        let mut token = create("user@test.com", Role::Access, "family", 0, &[]).unwrap();
         token.push_str("invalid");
         let result = verify(token, Role::Access);
         assert!(matches!(result, Err(anyhow::Error { .. })));
     }
 
     #[rstest]
-    pub fn token_with_wrong_secret_test() {
-        env::set_var("JWT_SECRET_ACCESS", "dummy_access_var");
-        let token = create("user@test.com", Role::Access).unwrap();
-        env::set_var("JWT_SECRET_ACCESS", "wrong_secret");
+    pub fn token_with_wrong_key_test() {
+        init_key();
+        let current_time = jsonwebtoken::get_current_timestamp() as usize;
+
+        let claims = Claims {
+            exp: current_time + 600,
+            iat: current_time,
+            nbf: current_time,
+            jti: "jti".into(),
+            iss: oauth::issuer(),
+            aud: session_audience(&oauth::issuer()),
+            sub: "user@test.com".into(),
+            role: Role::Access,
+            fam: "family".into(),
+            gen: 0,
+            roles: vec![],
+        };
+
+        // Same kid as the access key, signed by another key
+        let (wrong_key, _) = SigningKey::generate().unwrap();
+        let token = sign(&claims, &wrong_key.encoding);
         let result = verify(token, Role::Access);
         assert!(matches!(result, Err(anyhow::Error { .. })));
     }
 
     #[rstest]
-    pub fn token_exp_invalid_test() {
+    pub fn token_with_shared_secret_test() {
+        init_key();
+        let current_time = jsonwebtoken::get_current_timestamp() as usize;
+
+        let claims = Claims {
+            exp: current_time + 600,
+            iat: current_time,
+            nbf: current_time,
+            jti: "jti".into(),
+            iss: oauth::issuer(),
+            aud: session_audience(&oauth::issuer()),
+            sub: "user@test.com".into(),
+            role: Role::Access,
+            fam: "family".into(),
+            gen: 0,
+            roles: vec![],
+        };
 
+        // Access JWTs signed with a symmetric secret must be refused
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret("dummy_access_var".as_ref())).unwrap();
+        let result = verify(token, Role::Access);
+        assert!(matches!(result, Err(anyhow::Error { .. })));
+    }
+
+    #[rstest]
+    pub fn jwks_test() {
+        init_key();
+        let token = create("user@test.com", Role::Access, "family", 0, &[]).unwrap();
+        let kid = decode_header(&token).unwrap().kid.unwrap();
+
+        // A downstream service only needs the JWKS to verify the access JWT
+        let jwks = jwks().unwrap();
+        let jwk = jwks.find(&kid).unwrap();
+        let mut validation = Validation::new(Algorithm::EdDSA);
+        validation.validate_nbf = true;
+        validation.set_issuer(&[oauth::issuer()]);
+        validation.set_audience(&[session_audience(&oauth::issuer())]);
+        let claims = decode::<Claims>(&token, &DecodingKey::from_jwk(jwk).unwrap(), &validation).unwrap();
+        assert_eq!(claims.claims.sub, "user@test.com");
+    }
+
+    #[rstest]
+    pub fn token_exp_invalid_test() {
+        init_key();
         let current_time = jsonwebtoken::get_current_timestamp() as usize;
 
         let claims = Claims {
             exp: current_time - 500,
             iat: current_time,
             nbf: current_time,
+            jti: "jti".into(),
+            iss: oauth::issuer(),
+            aud: session_audience(&oauth::issuer()),
             sub: "user@test.com".into(),
             role: Role::Access,
+            fam: "family".into(),
+            gen: 0,
+            roles: vec![],
         };
 
-        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret("dummy_access_var".as_ref())).unwrap();
+        let token = sign_with_access_key(&claims);
         let result = verify(token, Role::Access);
         assert!(matches!(result, Err(anyhow::Error { .. })));
     }
 
     #[rstest]
     pub fn token_nbf_invalid_test() {
-
+        init_key();
         let current_time = jsonwebtoken::get_current_timestamp() as usize;
         let nbf_time = current_time + 500;
 
@@ -164,14 +579,100 @@ mod tests {
             exp: current_time + 600,
             iat: current_time,
             nbf: nbf_time,
+            jti: "jti".into(),
+            iss: oauth::issuer(),
+            aud: session_audience(&oauth::issuer()),
             sub: "user@test.com".into(),
             role: Role::Access,
+            fam: "family".into(),
+            gen: 0,
+            roles: vec![],
         };
 
-        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret("dummy_access_var".as_ref())).unwrap();
+        let token = sign_with_access_key(&claims);
         let result = verify(token, Role::Access);
         assert!(matches!(result, Err(anyhow::Error { .. })));
     }
-}
 
+    #[rstest]
+    pub fn token_audience_test() {
+        init_key();
+        let current_time = jsonwebtoken::get_current_timestamp() as usize;
+
+        let mut claims = Claims {
+            exp: current_time + 600,
+            iat: current_time,
+            nbf: current_time,
+            jti: "jti".into(),
+            iss: oauth::issuer(),
+            aud: "client".into(),
+            sub: "user@test.com".into(),
+            role: Role::Access,
+            fam: "family".into(),
+            gen: 0,
+            roles: vec![],
+        };
+
+        // A JWT of the same key meant for someone else is refused
+        assert!(verify(sign_with_access_key(&claims), Role::Access).is_err());
+        claims.aud = session_audience(&oauth::issuer());
+        assert!(verify(sign_with_access_key(&claims), Role::Access).is_ok());
+        claims.iss = "https://other.test.com".into();
+        assert!(verify(sign_with_access_key(&claims), Role::Access).is_err());
+    }
+
+    #[rstest]
+    pub fn id_token_test() {
+        init_key();
+        let subject = Uuid::new_v4();
+        let token = create_id_token(&subject, "user@test.com", "client", Some("nonce"), true).unwrap();
+
+        // The client verifies the ID token with the JWKS, for its own audience
+        let jwk = jwks().unwrap().find(&decode_header(&token).unwrap().kid.unwrap()).unwrap().clone();
+        let mut validation = Validation::new(Algorithm::EdDSA);
+        validation.set_audience(&["client"]);
+        validation.set_issuer(&[oauth::issuer()]);
+        let claims = decode::<IdClaims>(&token, &DecodingKey::from_jwk(&jwk).unwrap(), &validation).unwrap().claims;
+        assert_eq!(claims.sub, subject.to_string());
+        assert_eq!(claims.nonce.as_deref(), Some("nonce"));
+        assert_eq!(claims.email.as_deref(), Some("user@test.com"));
+
+        // An ID token isn't an access token
+        assert!(verify_oauth_access(&token).is_err());
+        assert!(verify(&token, Role::Access).is_err());
+    }
+
+    #[rstest]
+    pub fn oauth_access_test() {
+        init_key();
+        let token = create_oauth_access("user@test.com", "client", "openid email").unwrap();
+        let claims = verify_oauth_access(&token).unwrap();
+        assert_eq!(claims.sub, "user@test.com");
+        assert_eq!(claims.client_id, "client");
+
+        // Neither is a session JWT, and the other way around
+        assert!(verify(&token, Role::Access).is_err());
+        let session = create("user@test.com", Role::Access, "family", 0, &[]).unwrap();
+        assert!(verify_oauth_access(&session).is_err());
+    }
 
+    #[rstest]
+    pub fn keyring_rotation_test() {
+        let mut keyring = Keyring::new(2);
+        let (first, _) = SigningKey::generate().unwrap();
+        let first_kid = first.kid.clone();
+        keyring.push(first);
+
+        // After a rotation, the previous key is still known but doesn't sign anymore
+        let (second, _) = SigningKey::generate().unwrap();
+        let second_kid = second.kid.clone();
+        keyring.push(second);
+        assert_eq!(keyring.current().unwrap().kid, second_kid);
+        assert!(keyring.find(&first_kid).is_some());
+
+        // The oldest key is dropped once the keyring is full
+        keyring.push(SigningKey::generate().unwrap().0);
+        assert!(keyring.find(&first_kid).is_none());
+        assert!(keyring.find(&second_kid).is_some());
+    }
+}
@@ -0,0 +1,102 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use rand::distributions::{Alphanumeric, DistString};
+use ring::digest;
+use url::Url;
+use crate::config;
+use crate::consts::OAUTH_SCOPES;
+
+/// Issuer of the ID tokens, the URL the clients discover the provider from
+/// Configured by oidc.issuer, the public URL by default, without trailing slash
+pub fn issuer() -> String {
+    config::get().oidc_issuer()
+}
+
+/// Random secret given to a confidential client, only its hash is saved
+pub fn generate_secret() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 48)
+}
+
+/// Random single-use authorization code
+pub fn generate_code() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 32)
+}
+
+/// PKCE challenge of a code verifier, S256 method (RFC 7636)
+pub fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, verifier.as_bytes()))
+}
+
+/// Check a PKCE code verifier against the challenge sent to /authorize
+/// Only the S256 method is supported, "plain" would send the secret in the front channel
+pub fn verify_pkce(verifier: &str, challenge: &str) -> bool {
+    // 43 to 128 characters from the unreserved set
+    let valid = (43..=128).contains(&verifier.len())
+        && verifier.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._~".contains(&b));
+    valid && pkce_challenge(verifier) == challenge
+}
+
+/// Parse the space separated scopes requested by a client
+/// Unknown scopes are ignored, None if openid isn't requested : only OpenID Connect is supported
+pub fn parse_scopes(scope: &str) -> Option<Vec<String>> {
+    let scopes: Vec<String> = OAUTH_SCOPES.iter()
+        .filter(|s| scope.split(' ').any(|r| r == **s))
+        .map(|s| s.to_string())
+        .collect();
+    scopes.iter().any(|s| s == "openid").then_some(scopes)
+}
+
+/// Redirect URIs must be absolute, without fragment, and over HTTPS unless they point to the local machine
+pub fn is_redirect_uri_valid(uri: &str) -> bool {
+    let Ok(url) = Url::parse(uri) else {
+        return false;
+    };
+    let local = matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"));
+    url.fragment().is_none() && (url.scheme() == "https" || (url.scheme() == "http" && local))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+    verifier,
+    challenge,
+    expected,
+    // RFC 7636 appendix B
+    case("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk", "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM", true),
+    case("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk", "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk", false),
+    case("too-short", "zGWYWpOqGsMF4M0Q4o2NRfVvhBAh5T1aMMo7CHmvp3M", false),
+    )]
+    pub fn pkce_test(verifier: &str, challenge: &str, expected: bool) {
+        assert_eq!(verify_pkce(verifier, challenge), expected);
+    }
+
+    #[rstest(
+    scope,
+    expected,
+    case("openid", Some(vec!["openid"])),
+    case("email openid offline_access", Some(vec!["openid", "email"])),
+    case("openid openid", Some(vec!["openid"])),
+    case("email", None),
+    case("", None),
+    )]
+    pub fn parse_scopes_test(scope: &str, expected: Option<Vec<&str>>) {
+        let expected = expected.map(|s| s.into_iter().map(String::from).collect());
+        assert_eq!(parse_scopes(scope), expected);
+    }
+
+    #[rstest(
+    uri,
+    expected,
+    case("https://app.example.com/callback", true),
+    case("http://localhost:3000/callback", true),
+    case("http://app.example.com/callback", false),
+    case("https://app.example.com/callback#fragment", false),
+    case("/callback", false),
+    )]
+    pub fn redirect_uri_test(uri: &str, expected: bool) {
+        assert_eq!(is_redirect_uri_valid(uri), expected);
+    }
+}
@@ -0,0 +1,354 @@
+use std::time::Duration;
+use anyhow::{anyhow, bail, Result};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::{info, trace};
+use once_cell::sync::{Lazy, OnceCell};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
+use crate::config;
+use crate::consts::{OIDC_HTTP_TIMEOUT, OIDC_LOGIN_DURATION};
+use crate::utils::oauth;
+
+/// External OpenID Connect provider the users can sign in with
+#[derive(Clone, Debug)]
+pub struct Provider {
+    pub id: String, // Used in the URLs
+    pub name: String, // Displayed on the login page
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>, // None for a public client, PKCE is used in any case
+}
+
+impl Provider {
+    /// Providers of the configuration
+    pub fn from_config(oidc: &config::Oidc) -> Vec<Self> {
+        oidc.providers.iter()
+            .map(|p| Self {
+                id: p.id.to_ascii_lowercase(),
+                name: p.name.clone().unwrap_or(p.id.clone()),
+                issuer: p.issuer.as_str().trim_end_matches('/').to_string(),
+                client_id: p.client_id.clone(),
+                client_secret: p.client_secret.clone(),
+            })
+            .collect()
+    }
+
+    /// Callback URL of this server, to register at the provider
+    pub fn redirect_uri(&self) -> String {
+        format!("{}/login/oidc/{}/callback", oauth::issuer(), self.id)
+    }
+}
+
+static PROVIDERS: OnceCell<Vec<Provider>> = OnceCell::new();
+
+/// Set the providers, must be called once at startup
+pub fn init(providers: Vec<Provider>) -> Result<()> {
+    for provider in &providers {
+        info!("Sign in with {} enabled, callback {}", provider.issuer, provider.redirect_uri());
+    }
+    PROVIDERS.set(providers).or(Err(anyhow!("OIDC providers already initialized")))
+}
+
+pub fn providers() -> &'static [Provider] {
+    PROVIDERS.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+pub fn provider(id: &str) -> Option<&'static Provider> {
+    providers().iter().find(|p| p.id == id)
+}
+
+static HTTP: Lazy<reqwest::Result<reqwest::Client>> = Lazy::new(|| {
+    reqwest::Client::builder().timeout(Duration::from_secs(OIDC_HTTP_TIMEOUT)).build()
+});
+
+fn http() -> Result<&'static reqwest::Client> {
+    HTTP.as_ref().map_err(|e| anyhow!("Can't create the HTTP client : {e}"))
+}
+
+/// Endpoints of a provider, from its discovery document
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Metadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// Fetch the discovery document of a provider, it must be issued for the configured issuer
+pub async fn discover(provider: &Provider) -> Result<Metadata> {
+    trace!("Discover OIDC provider {}", provider.id);
+
+    let url = format!("{}/.well-known/openid-configuration", provider.issuer);
+    let metadata: Metadata = http()?.get(url).send().await?.error_for_status()?.json().await?;
+
+    if metadata.issuer.trim_end_matches('/') != provider.issuer {
+        bail!("Discovery document of {} issued for {}", provider.issuer, metadata.issuer);
+    }
+    Ok(metadata)
+}
+
+/// Keys signing the ID tokens of a provider
+pub async fn fetch_jwks(metadata: &Metadata) -> Result<JwkSet> {
+    Ok(http()?.get(&metadata.jwks_uri).send().await?.error_for_status()?.json().await?)
+}
+
+/// Login in progress at a provider, kept in the session until the callback
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PendingLogin {
+    pub provider: String,
+    pub state: String, // Binds the callback to this session (CSRF)
+    pub nonce: String, // Binds the ID token to this login (replay)
+    pub verifier: String, // PKCE, binds the code to this login (code interception)
+    pub next: Option<String>, // Page to go back to after the login
+    pub expiration: i64, // Unix timestamp
+}
+
+impl PendingLogin {
+    pub fn new(provider: &Provider, next: Option<String>) -> Self {
+        let random = |len| Alphanumeric.sample_string(&mut rand::thread_rng(), len);
+        Self {
+            provider: provider.id.clone(),
+            state: random(32),
+            nonce: random(32),
+            verifier: random(64),
+            next,
+            expiration: time::OffsetDateTime::now_utc().unix_timestamp() + OIDC_LOGIN_DURATION,
+        }
+    }
+}
+
+/// URL of the provider where the user signs in
+pub fn authorization_url(provider: &Provider, metadata: &Metadata, login: &PendingLogin) -> Result<String> {
+    let mut url = Url::parse(&metadata.authorization_endpoint)?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &provider.client_id)
+        .append_pair("redirect_uri", &provider.redirect_uri())
+        .append_pair("scope", "openid email")
+        .append_pair("state", &login.state)
+        .append_pair("nonce", &login.nonce)
+        .append_pair("code_challenge", &oauth::pkce_challenge(&login.verifier))
+        .append_pair("code_challenge_method", "S256");
+    Ok(url.into())
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Exchange the authorization code for an ID token, the access token of the provider isn't needed
+pub async fn exchange_code(provider: &Provider, metadata: &Metadata, code: &str, verifier: &str) -> Result<String> {
+    trace!("Exchange authorization code at {}", provider.issuer);
+
+    let redirect_uri = provider.redirect_uri();
+    let form = [
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &redirect_uri),
+        ("code_verifier", verifier),
+        ("client_id", &provider.client_id),
+    ];
+
+    let mut request = http()?.post(&metadata.token_endpoint).form(&form);
+    if let Some(secret) = &provider.client_secret {
+        request = request.basic_auth(&provider.client_id, Some(secret));
+    }
+
+    let response: TokenResponse = request.send().await?.error_for_status()?.json().await?;
+    Ok(response.id_token)
+}
+
+/// Identity asserted by the ID token of a provider
+#[derive(Debug, PartialEq)]
+pub struct Identity {
+    pub subject: String, // Stable id of the user at the provider
+    pub email: Option<String>,
+    pub email_verified: bool,
+}
+
+#[derive(Deserialize)]
+struct IdClaims {
+    sub: String,
+    azp: Option<String>,
+    nonce: Option<String>,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: Value, // Some providers send a string
+}
+
+// Signature algorithms accepted, a symmetric one would turn the client secret into a signing key
+const ALGORITHMS: [Algorithm; 7] = [
+    Algorithm::RS256, Algorithm::RS384, Algorithm::RS512,
+    Algorithm::PS256, Algorithm::ES256, Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// Check the signature, issuer, audience, expiration and nonce of an ID token
+pub fn validate_id_token(provider: &Provider, metadata: &Metadata, jwks: &JwkSet, token: &str, nonce: &str) -> Result<Identity> {
+    let header = decode_header(token)?;
+    if !ALGORITHMS.contains(&header.alg) {
+        bail!("ID token signed with {:?}", header.alg);
+    }
+
+    // The kid can be omitted if the provider has a single key
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }.ok_or(anyhow!("Unknown ID token key"))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&metadata.issuer]);
+    validation.set_audience(&[&provider.client_id]);
+    let claims = decode::<IdClaims>(token, &DecodingKey::from_jwk(jwk)?, &validation)?.claims;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        bail!("Invalid ID token nonce");
+    }
+    if claims.azp.is_some_and(|azp| azp != provider.client_id) {
+        bail!("ID token issued to another client");
+    }
+
+    Ok(Identity {
+        subject: claims.sub,
+        email: claims.email,
+        email_verified: claims.email_verified == Value::Bool(true) || claims.email_verified == "true",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use ring::rand::SystemRandom;
+    use ring::signature::Ed25519KeyPair;
+    use rstest::rstest;
+    use serde_json::json;
+    use crate::utils::jwt::SigningKey;
+
+    /// Key of the mock provider, with its JWKS
+    fn provider_key() -> (EncodingKey, String, JwkSet) {
+        let der = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let jwk = SigningKey::from_pkcs8(der.as_ref()).unwrap().to_jwk();
+        let kid = jwk.common.key_id.clone().unwrap();
+        (EncodingKey::from_ed_der(der.as_ref()), kid, JwkSet { keys: vec![jwk] })
+    }
+
+    fn provider(issuer: &str) -> Provider {
+        Provider {
+            id: "corp".into(),
+            name: "Corp".into(),
+            issuer: issuer.into(),
+            client_id: "king_auth".into(),
+            client_secret: Some("secret".into()),
+        }
+    }
+
+    fn metadata(issuer: &str) -> Metadata {
+        Metadata {
+            issuer: issuer.into(),
+            authorization_endpoint: format!("{issuer}/authorize"),
+            token_endpoint: format!("{issuer}/token"),
+            jwks_uri: format!("{issuer}/jwks"),
+        }
+    }
+
+    fn id_token(key: &EncodingKey, kid: &str, claims: Value) -> String {
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(kid.into());
+        encode(&header, &claims, key).unwrap()
+    }
+
+    fn claims(issuer: &str) -> Value {
+        let now = jsonwebtoken::get_current_timestamp();
+        json!({
+            "iss": issuer, "aud": "king_auth", "sub": "42", "exp": now + 300, "iat": now,
+            "nonce": "nonce", "email": "unit@test.com", "email_verified": true,
+        })
+    }
+
+    #[rstest(
+    field,
+    value,
+    expected,
+    case("sub", json!("42"), true),
+    case("email_verified", json!("true"), true),
+    case("nonce", json!("other"), false),
+    case("aud", json!("other_client"), false),
+    case("iss", json!("https://evil.example.com"), false),
+    case("exp", json!(1), false),
+    case("azp", json!("other_client"), false),
+    )]
+    pub fn validate_id_token_test(field: &str, value: Value, expected: bool) {
+        let issuer = "https://idp.example.com";
+        let (key, kid, jwks) = provider_key();
+        let mut claims = claims(issuer);
+        claims[field] = value;
+
+        let token = id_token(&key, &kid, claims);
+        let result = validate_id_token(&provider(issuer), &metadata(issuer), &jwks, &token, "nonce");
+        assert_eq!(result.is_ok(), expected);
+    }
+
+    #[rstest]
+    pub fn validate_id_token_algorithm_test() {
+        let issuer = "https://idp.example.com";
+        let (_, kid, jwks) = provider_key();
+
+        // Signed with the client secret, or by a key unknown to the provider
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(kid.clone());
+        let hmac = encode(&header, &claims(issuer), &EncodingKey::from_secret(b"secret")).unwrap();
+        let (other, _, _) = provider_key();
+        let forged = id_token(&other, &kid, claims(issuer));
+
+        for token in [hmac, forged] {
+            assert!(validate_id_token(&provider(issuer), &metadata(issuer), &jwks, &token, "nonce").is_err());
+        }
+    }
+
+    /// Mock provider : discovery, JWKS and a token endpoint returning an ID token for any code
+    async fn mock_provider() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let (key, kid, jwks) = provider_key();
+        let token = id_token(&key, &kid, claims(&issuer));
+
+        // Also served under another path : the document of an issuer served as the one of another
+        let discovery = Json(metadata(&issuer));
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get({ let d = discovery.clone(); move || async move { d } }))
+            .route("/other/.well-known/openid-configuration", get(move || async move { discovery }))
+            .route("/jwks", get(move || async move { Json(jwks) }))
+            .route("/token", post(move || async move { Json(json!({"id_token": token, "token_type": "Bearer"})) }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        issuer
+    }
+
+    #[tokio::test]
+    pub async fn mock_provider_test() {
+        let issuer = mock_provider().await;
+        let provider = provider(&issuer);
+
+        let metadata = discover(&provider).await.unwrap();
+        let login = PendingLogin::new(&provider, None);
+        let url = authorization_url(&provider, &metadata, &login).unwrap();
+        assert!(url.starts_with(&format!("{issuer}/authorize?")));
+        assert!(url.contains(&format!("code_challenge={}", oauth::pkce_challenge(&login.verifier))));
+
+        let token = exchange_code(&provider, &metadata, "code", &login.verifier).await.unwrap();
+        let jwks = fetch_jwks(&metadata).await.unwrap();
+        let identity = validate_id_token(&provider, &metadata, &jwks, &token, "nonce").unwrap();
+        assert_eq!(identity, Identity { subject: "42".into(), email: Some("unit@test.com".into()), email_verified: true });
+
+        // The discovery document must be the one of the configured issuer
+        assert!(discover(&Provider { issuer: format!("{issuer}/other"), ..provider }).await.is_err());
+    }
+}
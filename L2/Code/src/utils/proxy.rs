@@ -0,0 +1,95 @@
+use std::net::{IpAddr, SocketAddr};
+use http::HeaderMap;
+use ipnet::IpNet;
+
+/// Scheme the client used to reach the server
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Scheme {
+    #[default]
+    Http,
+    Https,
+}
+
+/// Address and scheme of the client, when the server is behind reverse proxies
+/// `direct` is the scheme of the connection to this server
+///
+/// The X-Forwarded-* headers are only read from a trusted proxy, anyone else could forge them.
+/// Each proxy appends the address it received the request from to X-Forwarded-For, so the list is read
+/// from the right and the first address that isn't a trusted proxy is the client.
+pub fn resolve(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet], direct: Scheme) -> (IpAddr, Scheme) {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return (peer, direct);
+    }
+
+    let forwarded: Vec<&str> = headers.get_all("x-forwarded-for").iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .map(str::trim)
+        .collect();
+
+    let mut client = peer;
+    for entry in forwarded.iter().rev() {
+        // A garbled entry can't be trusted, the last proxy known is kept as the client
+        let Some(ip) = parse_ip(entry) else {
+            break;
+        };
+        client = ip;
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+
+    // The proxy facing the client sets the first value
+    let scheme = match headers.get("x-forwarded-proto")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.split(',').next())
+        .map(str::trim)
+    {
+        Some(proto) if proto.eq_ignore_ascii_case("https") => Scheme::Https,
+        Some(_) => Scheme::Http,
+        None => direct,
+    };
+
+    (client, scheme)
+}
+
+/// Some proxies add the port of the client
+fn parse_ip(entry: &str) -> Option<IpAddr> {
+    entry.parse::<IpAddr>().ok()
+        .or_else(|| entry.parse::<SocketAddr>().ok().map(|a| a.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+    peer,
+    forwarded,
+    proto,
+    expected,
+    case("203.0.113.7", Some("198.51.100.1"), Some("https"), ("203.0.113.7", Scheme::Http)),
+    case("10.0.0.2", None, None, ("10.0.0.2", Scheme::Http)),
+    case("10.0.0.2", Some("198.51.100.1"), Some("https"), ("198.51.100.1", Scheme::Https)),
+    case("10.0.0.2", Some("6.6.6.6, 198.51.100.1, 10.0.0.3"), Some("HTTPS, http"), ("198.51.100.1", Scheme::Https)),
+    case("10.0.0.2", Some("10.0.0.4, 10.0.0.3"), Some("http"), ("10.0.0.4", Scheme::Http)),
+    case("10.0.0.2", Some("198.51.100.1, nonsense"), None, ("10.0.0.2", Scheme::Http)),
+    case("10.0.0.2", Some("198.51.100.1:4711"), None, ("198.51.100.1", Scheme::Http)),
+    case("::1", Some("[2001:db8::1]:443"), None, ("2001:db8::1", Scheme::Http)),
+    )]
+    pub fn resolve_test(peer: &str, forwarded: Option<&str>, proto: Option<&str>, expected: (&str, Scheme)) {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()];
+        let mut headers = HeaderMap::new();
+        if let Some(forwarded) = forwarded {
+            headers.insert("x-forwarded-for", forwarded.parse().unwrap());
+        }
+        if let Some(proto) = proto {
+            headers.insert("x-forwarded-proto", proto.parse().unwrap());
+        }
+
+        let (ip, scheme) = resolve(peer.parse().unwrap(), &headers, &trusted, Scheme::Http);
+        assert_eq!((ip, scheme), (expected.0.parse().unwrap(), expected.1));
+    }
+}
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use crate::consts::{
+    LOCKOUT_DURATION, LOGIN_FREE_FAILURES_EMAIL, LOGIN_FREE_FAILURES_IP, LOGIN_MAX_DELAY,
+    LOGIN_MAX_FAILURES_EMAIL, LOGIN_MAX_FAILURES_IP, LOGIN_WINDOW, MAX_LOCKOUT_DURATION,
+    RESET_FREE_REQUESTS_EMAIL, RESET_FREE_REQUESTS_IP, RESET_MAX_REQUESTS_EMAIL, RESET_MAX_REQUESTS_IP,
+};
+
+/// Limits applied to the failed logins of a key
+pub struct Policy {
+    pub free_failures: u32, // Failures allowed without any delay
+    pub max_failures: u32, // Failures triggering a lockout
+}
+
+pub const EMAIL_POLICY: Policy = Policy {
+    free_failures: LOGIN_FREE_FAILURES_EMAIL,
+    max_failures: LOGIN_MAX_FAILURES_EMAIL,
+};
+
+pub const IP_POLICY: Policy = Policy {
+    free_failures: LOGIN_FREE_FAILURES_IP,
+    max_failures: LOGIN_MAX_FAILURES_IP,
+};
+
+pub const RESET_EMAIL_POLICY: Policy = Policy {
+    free_failures: RESET_FREE_REQUESTS_EMAIL,
+    max_failures: RESET_MAX_REQUESTS_EMAIL,
+};
+
+pub const RESET_IP_POLICY: Policy = Policy {
+    free_failures: RESET_FREE_REQUESTS_IP,
+    max_failures: RESET_MAX_REQUESTS_IP,
+};
+
+/// Failed logins of a key (an IP or an email) in the sliding window
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+pub struct Attempts {
+    failures: Vec<u64>, // Timestamps of the failures still in the window
+    locked_until: u64, // Unix timestamp
+    lockouts: u32, // Number of lockouts, each one lasts twice as long as the previous
+}
+
+fn doubling(base: u64, exponent: u32, max: u64) -> u64 {
+    base.saturating_mul(1 << exponent.min(31)).min(max)
+}
+
+impl Attempts {
+    fn recent(&self, now: u64) -> impl Iterator<Item = &u64> {
+        self.failures.iter().filter(move |t| *t + LOGIN_WINDOW > now)
+    }
+
+    /// Seconds to wait before the next attempt, None if an attempt is allowed now
+    pub fn retry_after(&self, policy: &Policy, now: u64) -> Option<u64> {
+        if self.locked_until > now {
+            return Some(self.locked_until - now);
+        }
+
+        // Past the free failures, each new one doubles the delay
+        let recent = self.recent(now).count() as u32;
+        let last = self.recent(now).max()?;
+        if recent <= policy.free_failures {
+            return None;
+        }
+        let next = last + doubling(1, recent - policy.free_failures - 1, LOGIN_MAX_DELAY);
+        (next > now).then(|| next - now)
+    }
+
+    /// Record a failed login
+    /// Returns the end of the lockout if this failure locks the key
+    pub fn fail(&mut self, policy: &Policy, now: u64) -> Option<u64> {
+        self.failures.retain(|t| t + LOGIN_WINDOW > now);
+        self.failures.push(now);
+
+        if (self.failures.len() as u32) < policy.max_failures {
+            return None;
+        }
+
+        self.locked_until = now + doubling(LOCKOUT_DURATION, self.lockouts, MAX_LOCKOUT_DURATION);
+        self.lockouts += 1;
+        self.failures.clear();
+        Some(self.locked_until)
+    }
+
+    /// Nothing left to enforce, the key can be forgotten
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expiration() <= now
+    }
+
+    /// Time from which nothing is left to enforce
+    /// The lockouts are remembered for a while so that a new lockout lasts longer
+    pub fn expiration(&self) -> u64 {
+        let window = self.failures.iter().max().map_or(0, |t| t + LOGIN_WINDOW);
+        window.max(self.locked_until + MAX_LOCKOUT_DURATION)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn failed(count: u32, policy: &Policy) -> Attempts {
+        let mut attempts = Attempts::default();
+        for _ in 0..count {
+            attempts.fail(policy, NOW);
+        }
+        attempts
+    }
+
+    #[rstest(
+    failures,
+    expected,
+    case(0, None),
+    case(LOGIN_FREE_FAILURES_EMAIL, None),
+    case(LOGIN_FREE_FAILURES_EMAIL + 1, Some(1)),
+    case(LOGIN_FREE_FAILURES_EMAIL + 2, Some(2)),
+    case(LOGIN_FREE_FAILURES_EMAIL + 3, Some(4)),
+    case(LOGIN_MAX_FAILURES_EMAIL, Some(LOCKOUT_DURATION)),
+    )]
+    pub fn progressive_delay_test(failures: u32, expected: Option<u64>) {
+        assert_eq!(failed(failures, &EMAIL_POLICY).retry_after(&EMAIL_POLICY, NOW), expected);
+    }
+
+    #[rstest]
+    pub fn sliding_window_test() {
+        let attempts = failed(LOGIN_FREE_FAILURES_EMAIL + 1, &EMAIL_POLICY);
+        assert!(attempts.retry_after(&EMAIL_POLICY, NOW).is_some());
+        assert!(attempts.retry_after(&EMAIL_POLICY, NOW + LOGIN_WINDOW).is_none());
+        assert!(!attempts.is_expired(NOW + LOGIN_WINDOW - 1));
+    }
+
+    #[rstest]
+    pub fn lockout_test() {
+        let mut attempts = failed(LOGIN_MAX_FAILURES_EMAIL - 1, &EMAIL_POLICY);
+        assert_eq!(attempts.fail(&EMAIL_POLICY, NOW), Some(NOW + LOCKOUT_DURATION));
+
+        // The next lockout lasts twice as long
+        let later = NOW + LOCKOUT_DURATION;
+        assert!(attempts.retry_after(&EMAIL_POLICY, later).is_none());
+        let locked = (0..LOGIN_MAX_FAILURES_EMAIL).find_map(|_| attempts.fail(&EMAIL_POLICY, later));
+        assert_eq!(locked, Some(later + 2 * LOCKOUT_DURATION));
+
+        assert!(!attempts.is_expired(later + 2 * LOCKOUT_DURATION));
+        assert!(attempts.is_expired(later + 2 * LOCKOUT_DURATION + MAX_LOCKOUT_DURATION));
+    }
+
+    #[rstest]
+    pub fn reset_requests_test() {
+        // Stricter than the failed logins, the requests send emails
+        assert!(failed(RESET_FREE_REQUESTS_EMAIL, &RESET_EMAIL_POLICY).retry_after(&RESET_EMAIL_POLICY, NOW).is_none());
+        assert!(failed(RESET_FREE_REQUESTS_EMAIL + 1, &RESET_EMAIL_POLICY).retry_after(&RESET_EMAIL_POLICY, NOW).is_some());
+        assert_eq!(failed(RESET_MAX_REQUESTS_EMAIL, &RESET_EMAIL_POLICY).retry_after(&RESET_EMAIL_POLICY, NOW), Some(LOCKOUT_DURATION));
+        assert!(failed(RESET_FREE_REQUESTS_IP, &RESET_IP_POLICY).retry_after(&RESET_IP_POLICY, NOW).is_none());
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::{anyhow, Result};
+use rand::distributions::{Alphanumeric, DistString};
+use totp_rs::{Algorithm, Secret, TOTP};
+use crate::consts::{RECOVERY_CODES_COUNT, TOTP_ISSUER};
+
+// RFC 6238 defaults, the only parameters supported by most authenticator apps
+const DIGITS: usize = 6;
+const SKEW: u64 = 1;
+const STEP: u64 = 30;
+
+fn build(secret: &str, email: &str) -> Result<TOTP> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .or(Err(anyhow!("Invalid TOTP secret")))?;
+
+    // Skew is handled by `verify` to know which time step matched
+    Ok(TOTP::new(Algorithm::SHA1, DIGITS, 0, STEP, secret, Some(TOTP_ISSUER.to_string()), email.to_string())?)
+}
+
+/// Generate a new random TOTP secret, base32 encoded
+pub fn generate_secret() -> String {
+    Secret::generate_secret().to_encoded().to_string()
+}
+
+/// Build the otpauth:// URI used by authenticator apps (usually shown as a QR code)
+pub fn get_url(secret: &str, email: &str) -> Result<String> {
+    Ok(build(secret, email)?.get_url())
+}
+
+/// Check a TOTP code against the secret at the given timestamp
+/// Codes from the previous and the next time step are accepted to tolerate clock drift
+/// Return the time step the code belongs to, only if it is newer than `last_step`
+/// This prevents a code from being replayed while it is still valid
+pub fn verify(secret: &str, code: &str, last_step: u64, time: u64) -> Option<u64> {
+    let totp = build(secret, "").ok()?;
+    let code = code.trim();
+
+    let current = time / STEP;
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .filter(|step| *step > last_step)
+        .find(|step| totp.check(code, step * STEP))
+}
+
+/// Same as `verify` with the current system time
+pub fn verify_current(secret: &str, code: &str, last_step: u64) -> Option<u64> {
+    verify(secret, code, last_step, jsonwebtoken::get_current_timestamp())
+}
+
+/// Generate single-use recovery codes (format : xxxxx-xxxxx)
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES_COUNT)
+        .map(|_| {
+            let code = Alphanumeric.sample_string(&mut rand::thread_rng(), 10).to_ascii_lowercase();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Normalize a recovery code typed by the user before comparing it to the stored hashes
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.trim().to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const SECRET: &str = "OBWGC2LOFVZXI4TJNZTS243FMNZGK5BNGEZDG";
+
+    fn code_at(time: u64) -> String {
+        build(SECRET, "unit@test.com").unwrap().generate(time)
+    }
+
+    #[rstest(
+    offset,
+    expected,
+    case(0, true),
+    case(30, true),
+    case(-30, true),
+    case(90, false),
+    case(-90, false),
+    )]
+    pub fn totp_window_test(offset: i64, expected: bool) {
+        let now: u64 = 1_700_000_000;
+        let code = code_at((now as i64 + offset) as u64);
+        assert_eq!(verify(SECRET, &code, 0, now).is_some(), expected);
+    }
+
+    #[rstest]
+    pub fn totp_replay_test() {
+        let now: u64 = 1_700_000_000;
+        let code = code_at(now);
+        let step = verify(SECRET, &code, 0, now).unwrap();
+        assert!(verify(SECRET, &code, step, now).is_none());
+    }
+
+    #[rstest]
+    pub fn totp_url_test() {
+        let url = get_url(&generate_secret(), "unit@test.com").unwrap();
+        assert!(url.starts_with("otpauth://totp/"));
+        assert!(url.contains("issuer="));
+    }
+
+    #[rstest]
+    pub fn recovery_codes_test() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES_COUNT);
+        assert!(codes.iter().all(|c| c.len() == 11 && c == &normalize_recovery_code(c)));
+    }
+}
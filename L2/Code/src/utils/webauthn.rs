@@ -0,0 +1,183 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use log::info;
+use once_cell::sync::Lazy;
+use uuid::Uuid;
+use webauthn_rs::prelude::{
+    CreationChallengeResponse, Passkey, PasskeyAuthentication, PasskeyRegistration, PublicKeyCredential,
+    RegisterPublicKeyCredential, RequestChallengeResponse,
+};
+use webauthn_rs::fake::{FakePasskeyDistribution, WebauthnFakeCredentialGenerator};
+use webauthn_rs::{Webauthn, WebauthnBuilder};
+use webauthn_rs_proto::AllowCredentials;
+use crate::config;
+use crate::consts::WEBAUTHN_RP_NAME;
+
+/// Relying party, the browser only allows it for pages served from its origin
+/// Configured by webauthn.rp_id and webauthn.origin, the public URL and its host by default
+static WEBAUTHN: Lazy<Result<Webauthn>> = Lazy::new(|| {
+    let config = config::get();
+    let origin = config.webauthn_origin();
+    let rp_id = match &config.webauthn.rp_id {
+        Some(rp_id) => rp_id.clone(),
+        None => origin.host_str().ok_or(anyhow!("WebAuthn origin without host"))?.to_string(),
+    };
+    info!("Init WebAuthn for {rp_id} ({origin})");
+
+    Ok(WebauthnBuilder::new(&rp_id, &origin)?.rp_name(WEBAUTHN_RP_NAME).build()?)
+});
+
+/// Credential ids given for the accounts without passkey, derived from the email with a key drawn at each start
+static FAKE_CREDENTIALS: Lazy<Result<WebauthnFakeCredentialGenerator<FakePasskeyDistribution>>> = Lazy::new(|| {
+    let key = WebauthnFakeCredentialGenerator::<FakePasskeyDistribution>::new_hmac_key()?;
+    Ok(WebauthnFakeCredentialGenerator::new(&key)?)
+});
+
+fn webauthn() -> Result<&'static Webauthn> {
+    WEBAUTHN.as_ref().map_err(|e| anyhow!("Invalid WebAuthn configuration : {e}"))
+}
+
+/// Base64url id of a passkey, as sent by the browser
+pub fn passkey_id(passkey: &Passkey) -> String {
+    URL_SAFE_NO_PAD.encode(passkey.cred_id())
+}
+
+/// Start the registration of a new passkey
+/// The passkeys already registered are excluded, an authenticator can't register twice
+pub fn start_registration(
+    user_id: Uuid,
+    email: &str,
+    existing: &[Passkey],
+) -> Result<(CreationChallengeResponse, PasskeyRegistration)> {
+    let exclude = existing.iter().map(|p| p.cred_id().clone()).collect();
+    Ok(webauthn()?.start_passkey_registration(user_id, email, email, Some(exclude))?)
+}
+
+/// Check the attestation of the authenticator against the registration state saved in the session
+pub fn finish_registration(credential: &RegisterPublicKeyCredential, state: &PasskeyRegistration) -> Result<Passkey> {
+    Ok(webauthn()?.finish_passkey_registration(credential, state)?)
+}
+
+/// Start a login with one of the passkeys of a user
+pub fn start_authentication(passkeys: &[Passkey]) -> Result<(RequestChallengeResponse, PasskeyAuthentication)> {
+    Ok(webauthn()?.start_passkey_authentication(passkeys)?)
+}
+
+/// Challenge for an account without passkey, which can't be told apart from the one of an account with passkeys
+/// The fake credentials are derived from the email, asking again gives the same ones
+pub fn fake_authentication(email: &str) -> Result<RequestChallengeResponse> {
+    let fake = FAKE_CREDENTIALS.as_ref().map_err(|e| anyhow!("Fake credentials unavailable : {e}"))?;
+
+    let (mut challenge, _) = webauthn()?.start_discoverable_authentication()?;
+    challenge.mediation = None;
+    challenge.public_key.extensions = None;
+    challenge.public_key.allow_credentials = fake.generate(email.as_bytes())?.into_iter()
+        .map(|id| AllowCredentials { type_: "public-key".to_string(), id: id.into(), transports: None })
+        .collect();
+    Ok(challenge)
+}
+
+/// Check the assertion of the authenticator against the login state saved in the session
+/// Returns the passkeys with the signature counter of the one used updated
+pub fn finish_authentication(
+    credential: &PublicKeyCredential,
+    state: &PasskeyAuthentication,
+    mut passkeys: Vec<Passkey>,
+) -> Result<Vec<Passkey>> {
+    let result = webauthn()?.finish_passkey_authentication(credential, state)?;
+    passkeys.iter_mut().for_each(|p| { p.update_credential(&result); });
+    Ok(passkeys)
+}
+
+/// Serde helper storing a passkey as a JSON string
+/// Passkeys can't be encoded directly with non self-describing formats like bincode
+pub mod as_json {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use webauthn_rs::prelude::Passkey;
+
+    pub fn serialize<S: Serializer>(passkey: &Passkey, serializer: S) -> Result<S::Ok, S::Error> {
+        let json = serde_json::to_string(passkey).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&json)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Passkey, D::Error> {
+        let json = String::deserialize(deserializer)?;
+        serde_json::from_str(&json).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use webauthn_authenticator_rs::softpasskey::SoftPasskey;
+    use webauthn_authenticator_rs::WebauthnAuthenticator;
+    use webauthn_rs::prelude::Url;
+
+    fn origin() -> Url {
+        Url::parse(&config::get().public_url()).unwrap()
+    }
+
+    fn register(authenticator: &mut WebauthnAuthenticator<SoftPasskey>, existing: &[Passkey]) -> Result<Passkey> {
+        let (challenge, state) = start_registration(Uuid::new_v4(), "unit@test.com", existing)?;
+        let credential = authenticator.do_registration(origin(), challenge).map_err(|e| anyhow!("{e:?}"))?;
+        finish_registration(&credential, &state)
+    }
+
+    #[rstest]
+    pub fn passkey_ceremonies_test() {
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let passkey = register(&mut authenticator, &[]).unwrap();
+
+        // Passkeys are stored with bincode in the file store
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Stored(#[serde(with = "as_json")] Passkey);
+        let Stored(passkey) = bincode::deserialize(&bincode::serialize(&Stored(passkey)).unwrap()).unwrap();
+
+        let (challenge, state) = start_authentication(std::slice::from_ref(&passkey)).unwrap();
+        let credential = authenticator.do_authentication(origin(), challenge).unwrap();
+        let passkeys = finish_authentication(&credential, &state, vec![passkey.clone()]).unwrap();
+        assert_eq!(passkey_id(&passkeys[0]), passkey_id(&passkey));
+    }
+
+    #[rstest]
+    pub fn passkey_unknown_authenticator_test() {
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let passkey = register(&mut authenticator, &[]).unwrap();
+
+        // Another authenticator can't log in with the passkeys of the user
+        let mut other = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let (challenge, _) = start_authentication(&[passkey]).unwrap();
+        assert!(other.do_authentication(origin(), challenge).is_err());
+    }
+
+    #[rstest]
+    pub fn passkey_fake_challenge_test() {
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let passkey = register(&mut authenticator, &[]).unwrap();
+
+        // Only the random parts differ from a real challenge
+        let strip = |challenge: RequestChallengeResponse| {
+            let mut challenge = serde_json::to_value(challenge).unwrap();
+            challenge["publicKey"]["challenge"].take();
+            let credentials = challenge["publicKey"]["allowCredentials"].take();
+            (challenge, credentials)
+        };
+        let (real, _) = strip(start_authentication(&[passkey]).unwrap().0);
+        let (fake, credentials) = strip(fake_authentication("unit@test.com").unwrap());
+        assert_eq!(real, fake);
+
+        // The same account always gets the same credentials
+        assert_eq!(strip(fake_authentication("unit@test.com").unwrap()).1, credentials);
+    }
+
+    #[rstest]
+    pub fn passkey_wrong_origin_test() {
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let (challenge, state) = start_registration(Uuid::new_v4(), "unit@test.com", &[]).unwrap();
+        let evil = Url::parse("http://localhost:1337").unwrap();
+        let credential = authenticator.do_registration(evil, challenge).unwrap();
+        assert!(finish_registration(&credential, &state).is_err());
+    }
+}